@@ -4,6 +4,7 @@ use crate::miner::Handle as MinerHandle;
 use crate::transaction_generator::Handle as TxGeneratorHandle;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::network::message::Message;
+use crate::network::shaping::Shape;
 use crate::types::hash::{H256, Hashable};
 use crate::types::transaction;
 use log::info;
@@ -165,6 +166,65 @@ impl Server {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
+                        }
+                        "/network/bans" => {
+                            respond_json!(req, network.bans());
+                        }
+                        "/network/shaping" => {
+                            respond_json!(req, network.shaping());
+                        }
+                        "/network/shaping/set" | "/network/shaping/clear" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // no peer shapes the links to the peers that have no shape of their own
+                            let peer = match params.get("peer").map(|v| v.parse::<std::net::SocketAddr>()) {
+                                None => None,
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing peer: {}", e));
+                                    return;
+                                }
+                            };
+                            if url.path() == "/network/shaping/clear" {
+                                network.set_shaping(peer, None);
+                                respond_result!(req, true, "ok");
+                                return;
+                            }
+                            // what is not given is not shaped
+                            let mut shape = Shape::default();
+                            for (name, value) in [
+                                ("delay", &mut shape.delay_ms),
+                                ("jitter", &mut shape.jitter_ms),
+                                ("bandwidth", &mut shape.bandwidth_kbps),
+                            ] {
+                                if let Some(v) = params.get(name) {
+                                    *value = match v.parse::<u64>() {
+                                        Ok(v) => v,
+                                        Err(e) => {
+                                            respond_result!(req, false, format!("error parsing {}: {}", name, e));
+                                            return;
+                                        }
+                                    };
+                                }
+                            }
+                            if let Some(v) = params.get("drop_rate") {
+                                shape.drop_rate = match v.parse::<f64>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing drop_rate: {}", e));
+                                        return;
+                                    }
+                                };
+                            }
+                            if let Err(e) = shape.check() {
+                                respond_result!(req, false, e);
+                                return;
+                            }
+                            network.set_shaping(peer, Some(shape));
+                            respond_result!(req, true, "ok");
+                        }
                         "/blockchain/longest-chain" => {
                             let blockchain = blockchain.lock().unwrap();
                             let v = blockchain.all_blocks_in_longest_chain();
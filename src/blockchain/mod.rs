@@ -1,29 +1,195 @@
+pub mod store;
+
 use crate::types::block::{Block, Content, Header};
 use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
 use crate::types::transaction::SignedTransaction;
+use crate::types::utxo::{OutPoint, UtxoTransaction};
 use crate::types::address::Address;
 use crate::types::key_pair;
 use ring::signature::{KeyPair, Ed25519KeyPair};
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
+use log::{error, info, warn};
+use store::BlockStore;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Number of blocks between two difficulty adjustments
+pub const RETARGET_INTERVAL: usize = 10;
+/// Block interval the difficulty adjustment aims for, in milliseconds
+pub const TARGET_BLOCK_TIME: u128 = 2000;
+/// Bound on how much a single adjustment can raise or lower the difficulty
+pub const MAX_RETARGET_FACTOR: u128 = 4;
+
+/// Number of past blocks whose median timestamp a new block has to be later than
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// How far in the future a block timestamp may be, in milliseconds
+pub const MAX_FUTURE_DRIFT: u128 = 2 * 60 * 60 * 1000;
+/// Largest number of transactions in a block
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+/// Largest serialized size of a block, in bytes
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+/// Headers are only kept while their chain has at most this many blocks' worth of work less than
+/// the best known chain, so that a fork can only be followed headers first this far back
+pub const HEADER_REACH: u64 = 144;
+/// Most headers kept waiting for their block, about 12 MB of them
+pub const MAX_HEADERS: usize = 100_000;
+
+/// Coins minted by the coinbase of the first blocks
+pub const DEFAULT_BLOCK_SUBSIDY: usize = 50;
+/// Number of blocks after which the block subsidy halves
+pub const DEFAULT_HALVING_INTERVAL: usize = 210;
+
+/// How a chain keeps track of who owns which coins, selected at genesis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedgerModel {
+    /// balances and nonces per address, moved by `SignedTransaction`s
+    Account,
+    /// unspent outputs, moved by `UtxoTransaction`s
+    Utxo,
+}
+
+/// Why `Blockchain::verify_block` rejected a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockValidationError {
+    UnknownParent,
+    /// the block hash is above its difficulty target
+    InsufficientProofOfWork,
+    /// the difficulty target is not the one the retarget rules require
+    WrongDifficulty,
+    MerkleRootMismatch,
+    /// not later than the median timestamp of the previous blocks
+    TimestampTooOld,
+    TimestampInFuture,
+    EmptyBlock,
+    TooManyTransactions,
+    BlockTooLarge,
+    DuplicateTransaction(H256),
+    InvalidTransaction(H256),
+    /// the first transaction is not a coinbase
+    MissingCoinbase,
+    /// a coinbase anywhere but in the first position
+    MisplacedCoinbase(H256),
+    /// the coinbase nonce is not the block height
+    InvalidCoinbase,
+    /// the coinbase mints more than the block subsidy plus the fees
+    CoinbaseTooLarge,
+    /// a transaction of the ledger model the chain does not use
+    WrongLedgerModel,
+    /// a header whose chain has too little work to be kept, see `HEADER_REACH`
+    OutOfReach,
+    /// a header arriving while `MAX_HEADERS` headers wait for their block
+    TooManyHeaders,
+}
+
+/// Check that the block hash meets its own difficulty target. This needs no chain context, so it
+/// can also filter blocks whose parent is still unknown.
+pub fn check_proof_of_work(block: &Block) -> Result<(), BlockValidationError> {
+    check_header_proof_of_work(&block.header())
+}
+
+/// Check that a header's hash meets its own difficulty target
+pub fn check_header_proof_of_work(header: &Header) -> Result<(), BlockValidationError> {
+    if header.hash() > header.get_difficulty() {
+        return Err(BlockValidationError::InsufficientProofOfWork);
+    }
+    Ok(())
+}
+
+/// Whether the block at `height` is the first of a new difficulty interval
+pub fn is_retarget_height(height: usize) -> bool {
+    height.is_multiple_of(RETARGET_INTERVAL)
+}
+
+/// Sum of the fees of some transactions, which the coinbase may claim on top of the subsidy
+pub fn total_fees(transactions: &[SignedTransaction]) -> usize {
+    transactions.iter().fold(0, |fees, transaction| fees.saturating_add(transaction.t.fee))
+}
+
+#[derive(Debug)]
 pub struct Blockchain {
-    chain: HashMap<H256, (Block, usize)>,
+    /// every known block with its height and the cumulative work of the chain ending in it
+    chain: HashMap<H256, (Block, usize, H256)>,
+    /// validated headers whose block has not been downloaded yet, with their height and the
+    /// cumulative work of the chain ending in them
+    headers: HashMap<H256, (Header, usize, H256)>,
+    /// the hashes of `headers` by height
+    headers_by_height: BTreeMap<usize, HashSet<H256>>,
+    /// the most cumulative work of a header in `headers`
+    best_header_work: H256,
     head: Block,
-    pub block_state: HashMap<H256, State>
+    genesis: H256,
+    pub block_state: HashMap<H256, State>,
+    store: Option<BlockStore>,
+    block_subsidy: usize,
+    halving_interval: usize,
+    model: LedgerModel,
+}
+
+/// A switch of the longest chain to another fork, returned by `Blockchain::insert`
+#[derive(Debug, Clone)]
+pub struct Reorg {
+    /// Number of blocks that left the longest chain
+    pub depth: usize,
+    pub common_ancestor: H256,
+    /// Blocks that left the longest chain, ordered from the old tip down to the common ancestor
+    pub disconnected: Vec<Block>,
+    /// Blocks that joined the longest chain, ordered from the common ancestor up to the new tip
+    pub connected: Vec<Block>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
-    pub accounts: HashMap<Address, (usize, usize)>
+    pub accounts: HashMap<Address, (usize, usize)>,
+    /// unspent outputs and their value and recipient, only used by the UTXO ledger model
+    pub utxos: HashMap<OutPoint, (u64, Address)>,
 }
 impl State {
     pub fn new() -> Self {
         let accounts : HashMap<Address, (usize,usize)> = HashMap::new();
-        Self{accounts}
+        let utxos : HashMap<OutPoint, (u64, Address)> = HashMap::new();
+        Self{accounts, utxos}
+    }
+    pub fn add_output(&mut self, out_point: OutPoint, value: u64, recipient: Address) {
+        self.utxos.insert(out_point, (value, recipient));
+    }
+    /// Verify a UTXO transaction against this state and apply it if it is valid, returning its fee
+    pub fn apply_utxo(&mut self, transaction: &UtxoTransaction) -> Option<u64> {
+        if transaction.inputs.is_empty() || transaction.outputs.is_empty() {
+            return None;
+        }
+        let mut spent: HashSet<OutPoint> = HashSet::new();
+        let mut input_value: u64 = 0;
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            let out_point = input.out_point();
+            if !spent.insert(out_point) {
+                return None;
+            }
+            let (value, owner) = self.utxos.get(&out_point)?;
+            if !transaction.verify_input(index, owner) {
+                return None;
+            }
+            input_value = input_value.checked_add(*value)?;
+        }
+        let mut output_value: u64 = 0;
+        for output in transaction.outputs.iter() {
+            output_value = output_value.checked_add(output.value)?;
+        }
+        // a transaction cannot create value, what it does not spend goes to the miner
+        if output_value > input_value {
+            return None;
+        }
+        for out_point in spent {
+            self.utxos.remove(&out_point);
+        }
+        let hash = transaction.hash();
+        for (index, output) in transaction.outputs.iter().enumerate() {
+            self.add_output((hash, index as u32), output.value, output.recipient);
+        }
+        Some(input_value - output_value)
     }
     pub fn add_account(&mut self, account_addr: Address, bal:usize) {
         self.accounts.insert(account_addr.clone(), (0, bal));
@@ -34,13 +200,48 @@ impl State {
     pub fn contains(&self, address: &Address) -> bool{
         self.accounts.contains_key(address)
     }
+    /// Credit the coins minted by a coinbase transaction to its receiver
+    pub fn apply_coinbase(&mut self, coinbase: &SignedTransaction) {
+        let receiver = coinbase.t.receiver;
+        if !self.contains(&receiver) {
+            self.add_account(receiver, 0);
+        }
+        let (receiver_nonce, receiver_bal) = self.accounts[&receiver];
+        self.accounts.insert(receiver, (receiver_nonce, receiver_bal + coinbase.t.value));
+    }
+    /// Verify a transaction against this state and apply it if it is valid
+    pub fn apply(&mut self, transaction: &SignedTransaction) -> bool {
+        if !transaction.verify(self) {
+            return false;
+        }
+        let sender = transaction.t.sender;
+        let receiver = transaction.t.receiver;
+        let value = transaction.t.value;
+        if !self.contains(&receiver) {
+            self.add_account(receiver, 0);
+        }
+        let (sender_nonce, sender_bal) = self.accounts[&sender];
+        self.accounts.insert(sender, (sender_nonce + 1, sender_bal - value - transaction.t.fee));
+        let (receiver_nonce, receiver_bal) = self.accounts[&receiver];
+        self.accounts.insert(receiver, (receiver_nonce, receiver_bal + value));
+        true
+    }
 
 }
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the account model, only containing the genesis block
     pub fn new() -> Self {
-        // random nonce(doesn't have to solve the puzzle for the genesis according to Office Hours)
-        let nonce: u32 = 00000000000000000000000000000000;
+        Self::with_model(LedgerModel::Account)
+    }
+    /// Create a new blockchain only containing the genesis block, whose ICO follows `model`
+    pub fn with_model(model: LedgerModel) -> Self {
+        // random nonce(doesn't have to solve the puzzle for the genesis according to Office Hours).
+        // It differs by ledger model, so that nodes of different models have different genesis
+        // blocks and refuse each other at the handshake.
+        let nonce: u32 = match model {
+            LedgerModel::Account => 0,
+            LedgerModel::Utxo => 1,
+        };
         // random parent(okay according to Office Hours)
         let mut parent: H256 = [0; 32].into();
         // empty root as well, genesis stores no data right now
@@ -52,7 +253,7 @@ impl Blockchain {
         // current timestamp
         let timestamp : u128 = 0;
         let height = 0;
-        let mut chain: HashMap<H256, (Block, usize)> = HashMap::new();
+        let mut chain: HashMap<H256, (Block, usize, H256)> = HashMap::new();
         let genesis = Block::new(
             parent,
             nonce,
@@ -72,50 +273,144 @@ impl Blockchain {
         let mut state = State::new();
         let pub_key_1 = key_pair_1.public_key().as_ref();
         let account_addr_1 = Address::from_public_key_bytes(pub_key_1);
-        state.add_account(account_addr_1, 10000);
         let pub_key_2 = key_pair_2.public_key().as_ref();
         let account_addr_2 = Address::from_public_key_bytes(pub_key_2);
-        state.add_account(account_addr_2, 0);
         let pub_key_3 = key_pair_3.public_key().as_ref();
         let account_addr_3 = Address::from_public_key_bytes(pub_key_3);
-        state.add_account(account_addr_3, 0);         
-        
-        println!("Accounts: {:?}", state.get_accounts());
+        match model {
+            LedgerModel::Account => {
+                state.add_account(account_addr_1, 10000);
+                state.add_account(account_addr_2, 0);
+                state.add_account(account_addr_3, 0);
+                println!("Accounts: {:?}", state.get_accounts());
+            }
+            LedgerModel::Utxo => {
+                // the genesis block has no transactions, its hash names the ico output
+                state.add_output((genesis.hash(), 0), 10000, account_addr_1);
+            }
+        }
         let mut block_state: HashMap<H256, State> = HashMap::new();
         block_state.insert(genesis.hash(), state);
-        chain.insert(genesis.hash(), (genesis.clone(), height));
+        chain.insert(genesis.hash(), (genesis.clone(), height, difficulty.work()));
         Self {
             chain: chain,
+            headers: HashMap::new(),
+            headers_by_height: BTreeMap::new(),
+            best_header_work: H256::default(),
             head: genesis.clone(),
-            block_state: block_state.clone()
+            genesis: genesis.hash(),
+            block_state: block_state.clone(),
+            store: None,
+            block_subsidy: DEFAULT_BLOCK_SUBSIDY,
+            halving_interval: DEFAULT_HALVING_INTERVAL,
+            model: model,
         }
     }
+    pub fn ledger_model(&self) -> LedgerModel {
+        self.model
+    }
+    /// Set the subsidy of the first blocks and the number of blocks after which it halves
+    pub fn set_subsidy(&mut self, block_subsidy: usize, halving_interval: usize) {
+        self.block_subsidy = block_subsidy;
+        self.halving_interval = halving_interval;
+    }
+    /// Coins the coinbase of the block at `height` may mint on top of the fees
+    pub fn block_subsidy(&self, height: usize) -> usize {
+        let halvings = height / self.halving_interval;
+        if halvings >= usize::BITS as usize {
+            return 0;
+        }
+        self.block_subsidy >> halvings
+    }
+    /// Open the account model blockchain persisted in `data_dir`
+    pub fn open(data_dir: &Path) -> std::io::Result<Self> {
+        let mut blockchain = Self::new();
+        blockchain.load(data_dir)?;
+        Ok(blockchain)
+    }
+    /// Load the blocks persisted in `data_dir`, replaying every stored block on top of the genesis
+    /// block to rebuild the heights, the states and the tip, and persist new blocks there
+    pub fn load(&mut self, data_dir: &Path) -> std::io::Result<()> {
+        let (store, blocks) = BlockStore::open(data_dir)?;
+        for block in blocks.iter() {
+            if !self.contains(&block.get_parent()) {
+                warn!("Stored block {} has an unknown parent, skipping it", block.hash());
+                continue;
+            }
+            if self.update_state(block) {
+                self.insert(block);
+            }
+        }
+        self.store = Some(store);
+        info!("Loaded {} blocks from {}, tip height {}", blocks.len(), data_dir.display(), self.get_tip_height());
+        Ok(())
+    }
+    /// Compute the state after a block by applying its transactions in order to the state of its
+    /// parent. Returns false, storing nothing, if a transaction does not apply.
     pub fn update_state(&mut self, block: &Block) -> bool {
         let mut prev_state : State = self.block_state.get(&block.get_parent()).unwrap().clone();
-        let mut valid_block = true;
-        for transaction in block.get_content(){
-            let sender = transaction.t.sender;
-            let receiver = transaction.t.receiver;
-            // add receivers not in the state yet that are being sent coins
-            if !prev_state.contains(&receiver){
-                prev_state.add_account(receiver, 0);
+        if self.apply_transactions(&mut prev_state, block).is_err() {
+            return false;
+        }
+        // add block, state to the block_state
+        self.block_state.insert(block.hash(), prev_state);
+        true
+    }
+    /// Apply the coinbase and the transactions of a block to the state of its parent. The coinbase
+    /// is credited last, so it cannot be spent in its own block.
+    fn apply_transactions(&self, state: &mut State, block: &Block) -> Result<(), BlockValidationError> {
+        let content = block.get_content();
+        let utxo_content = block.get_utxo_content();
+        if content.is_empty() {
+            if utxo_content.is_empty() {
+                return Ok(());
             }
-            let accounts = prev_state.get_accounts();
-            let (sender_nonce, sender_bal) = accounts.get(&sender).unwrap();
-            let (receiver_nonce, receiver_bal) = accounts.get(&receiver).unwrap();
-            let value = transaction.t.value;
-            if sender_bal < &value{
-                valid_block = false;
-                break;
+            return Err(BlockValidationError::MissingCoinbase);
+        }
+        let coinbase = &content[0];
+        if !coinbase.is_coinbase() {
+            return Err(BlockValidationError::MissingCoinbase);
+        }
+        let height = self.chain.get(&block.get_parent()).unwrap().1 + 1;
+        if coinbase.t.nonce != height {
+            return Err(BlockValidationError::InvalidCoinbase);
+        }
+        let mut fees: usize = 0;
+        match self.model {
+            LedgerModel::Account => {
+                if !utxo_content.is_empty() {
+                    return Err(BlockValidationError::WrongLedgerModel);
+                }
+                for transaction in content[1..].iter() {
+                    if transaction.is_coinbase() {
+                        return Err(BlockValidationError::MisplacedCoinbase(transaction.hash()));
+                    }
+                    if !state.apply(transaction) {
+                        return Err(BlockValidationError::InvalidTransaction(transaction.hash()));
+                    }
+                }
+                fees = total_fees(&content[1..]);
             }
-            prev_state.accounts.insert(sender, (*sender_nonce + 1, sender_bal - value));
-            prev_state.accounts.insert(receiver, (*receiver_nonce, receiver_bal + value));
+            LedgerModel::Utxo => {
+                if content.len() > 1 {
+                    return Err(BlockValidationError::WrongLedgerModel);
+                }
+                for transaction in utxo_content.iter() {
+                    match state.apply_utxo(transaction) {
+                        Some(fee) => fees = fees.saturating_add(fee as usize),
+                        None => return Err(BlockValidationError::InvalidTransaction(transaction.hash())),
+                    }
+                }
+            }
+        }
+        if coinbase.t.value > self.block_subsidy(height).saturating_add(fees) {
+            return Err(BlockValidationError::CoinbaseTooLarge);
         }
-            // add block, state to the block_state
-        if valid_block {
-            self.block_state.insert(block.hash(), prev_state.clone());
+        match self.model {
+            LedgerModel::Account => state.apply_coinbase(coinbase),
+            LedgerModel::Utxo => state.add_output((coinbase.hash(), 0), coinbase.t.value as u64, coinbase.t.receiver),
         }
-        valid_block
+        Ok(())
     }
     pub fn contains(&self, key: &H256) -> bool{
         self.chain.contains_key(key)
@@ -126,38 +421,195 @@ impl Blockchain {
     pub fn get_tip_state(&mut self) -> State{
         self.get_state(&self.head())
     }
-    pub fn verify_block(&mut self, block: &Block) -> bool{
-        // if a block makes it here, it's parent is known to be in the chain
-        let parent = self.get_block(&block.get_parent()).unwrap();
-        if !(block.get_difficulty() == parent.get_difficulty()) {
-            return false;
+    /// Check a block against every consensus rule, given that its parent is in the chain
+    pub fn verify_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        if !self.contains(&block.get_parent()) {
+            return Err(BlockValidationError::UnknownParent);
         }
-        let mut curr_state = self.get_tip_state();
-        if block.get_content().is_empty() {
-            return false;
+        check_proof_of_work(block)?;
+        if !(block.get_difficulty() == self.next_difficulty(&block.get_parent())) {
+            return Err(BlockValidationError::WrongDifficulty);
+        }
+        let timestamp = block.get_timestamp();
+        if timestamp <= self.median_time_past(&block.get_parent()) {
+            return Err(BlockValidationError::TimestampTooOld);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if timestamp > now + MAX_FUTURE_DRIFT {
+            return Err(BlockValidationError::TimestampInFuture);
+        }
+        let content = block.get_content();
+        if content.is_empty() {
+            return Err(BlockValidationError::EmptyBlock);
+        }
+        let tx_hashes: Vec<H256> = block.transaction_hashes();
+        if tx_hashes.len() > MAX_BLOCK_TRANSACTIONS {
+            return Err(BlockValidationError::TooManyTransactions);
         }
-        for transaction in block.get_content(){
-            // if the receiver account isn't in the chain, add a receiver account
-            let receiver = transaction.t.receiver;
-            if !curr_state.contains(&receiver){
-                curr_state.add_account(receiver, 0);
+        if bincode::serialized_size(block).unwrap() as usize > MAX_BLOCK_SIZE {
+            return Err(BlockValidationError::BlockTooLarge);
+        }
+        let mut seen: HashSet<H256> = HashSet::new();
+        for hash in tx_hashes.iter() {
+            if !seen.insert(*hash) {
+                return Err(BlockValidationError::DuplicateTransaction(*hash));
             }
-            if !transaction.verify(&curr_state){
-                return false;
+        }
+        if MerkleTree::new(&tx_hashes).root() != block.get_merkle_root() {
+            return Err(BlockValidationError::MerkleRootMismatch);
+        }
+        // transactions build on the state of the parent, which may be on a side fork, and on the
+        // transactions before them in the block
+        let mut curr_state = match self.block_state.get(&block.get_parent()) {
+            Some(state) => state.clone(),
+            None => return Err(BlockValidationError::UnknownParent),
+        };
+        self.apply_transactions(&mut curr_state, block)
+    }
+    /// Median timestamp of the last `MEDIAN_TIME_SPAN` blocks ending in `block_hash`
+    pub fn median_time_past(&self, block_hash: &H256) -> u128 {
+        let mut timestamps: Vec<u128> = vec![];
+        let mut curr_header = self.get_header(block_hash);
+        while let Some((header, _)) = curr_header {
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
             }
+            timestamps.push(header.get_timestamp());
+            curr_header = self.get_header(&header.get_parent());
         }
-        return true;
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
     }
-    /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
-        let tip_height: usize = self.get_tip_height();
-        let parent_height: usize = self.chain.get(&block.get_parent()).unwrap().1;
+    /// Difficulty required of a child of `parent_hash`. It only changes every `RETARGET_INTERVAL`
+    /// blocks, scaled by how long the last interval took compared to `TARGET_BLOCK_TIME`.
+    pub fn next_difficulty(&self, parent_hash: &H256) -> H256 {
+        let (parent, parent_height) = self.get_header(parent_hash).unwrap();
+        let height = parent_height + 1;
+        if !is_retarget_height(height) {
+            return parent.get_difficulty();
+        }
+        // the genesis timestamp is not a real time, so the first interval starts at height 1
+        let (mut first, mut first_height) = (parent.clone(), parent_height);
+        while first_height > 1 && first_height > height - RETARGET_INTERVAL {
+            let (header, header_height) = self.get_header(&first.get_parent()).unwrap();
+            first = header;
+            first_height = header_height;
+        }
+        let intervals = (height - 1 - first_height) as u128;
+        if intervals == 0 {
+            return parent.get_difficulty();
+        }
+        let expected = intervals * TARGET_BLOCK_TIME;
+        let actual = parent.get_timestamp().saturating_sub(first.get_timestamp())
+            .max(expected / MAX_RETARGET_FACTOR)
+            .min(expected * MAX_RETARGET_FACTOR);
+        let difficulty = parent.get_difficulty().scale(actual as u64, expected as u64);
+        info!("Retargeting difficulty at height {}: {} -> {}", height, parent.get_difficulty(), difficulty);
+        difficulty
+    }
+    /// Insert a block into blockchain, returning the reorg if the longest chain switched to
+    /// another fork
+    pub fn insert(&mut self, block: &Block) -> Option<Reorg> {
+        let tip_work: H256 = self.get_tip_work();
+        let (_, parent_height, parent_work) = self.chain.get(&block.get_parent()).unwrap();
         let block_height: usize = parent_height + 1;
-        self.chain.insert(block.hash(), (block.clone(), block_height));
-        // rule = only make the fork the new longest chain if the fork tip is strictly longer than the current tip
-        if self.tip() == block.get_parent() || block_height > tip_height {
+        let block_work: H256 = parent_work.saturating_add(&block.get_difficulty().work());
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.append(block) {
+                error!("Error persisting block {}: {}", block.hash(), e);
+            }
+        }
+        self.chain.insert(block.hash(), (block.clone(), block_height, block_work));
+        self.remove_header(&block.hash());
+        // rule = the longest chain is the one with the most cumulative work, and on a tie the tip
+        // with the lower hash, which unlike the arrival order or the timestamp every node agrees on
+        // and no miner can choose
+        let more_work = block_work > tip_work
+            || (block_work == tip_work && block.hash() < self.head.hash());
+        if self.tip() == block.get_parent() {
             self.head = block.clone();
         }
+        else if more_work {
+            let old_head = self.head.clone();
+            self.head = block.clone();
+            let reorg = self.reorg(&old_head, block);
+            info!("Reorg of depth {} to {} at height {}, common ancestor {}", reorg.depth, block.hash(), block_height, reorg.common_ancestor);
+            return Some(reorg);
+        }
+        None
+    }
+    /// Walk both forks back to their common ancestor
+    fn reorg(&self, old_head: &Block, new_head: &Block) -> Reorg {
+        let mut disconnected: Vec<Block> = vec![];
+        let mut connected: Vec<Block> = vec![];
+        let mut old_block = old_head.clone();
+        let mut new_block = new_head.clone();
+        while self.get_height(&new_block) > self.get_height(&old_block) {
+            let parent = self.get_block(&new_block.get_parent()).unwrap();
+            connected.push(new_block);
+            new_block = parent;
+        }
+        while self.get_height(&old_block) > self.get_height(&new_block) {
+            let parent = self.get_block(&old_block.get_parent()).unwrap();
+            disconnected.push(old_block);
+            old_block = parent;
+        }
+        while old_block.hash() != new_block.hash() {
+            let old_parent = self.get_block(&old_block.get_parent()).unwrap();
+            let new_parent = self.get_block(&new_block.get_parent()).unwrap();
+            disconnected.push(old_block);
+            connected.push(new_block);
+            old_block = old_parent;
+            new_block = new_parent;
+        }
+        connected.reverse();
+        Reorg {
+            depth: disconnected.len(),
+            common_ancestor: old_block.hash(),
+            disconnected,
+            connected,
+        }
+    }
+    /// Transactions of the disconnected blocks of a reorg that did not make it into the new longest
+    /// chain and are still valid on top of its tip, in the order they should be re-applied. The
+    /// coinbases of the disconnected blocks are left out, their coins were never there.
+    pub fn reorg_transactions(&self, reorg: &Reorg) -> Vec<SignedTransaction> {
+        let mut confirmed: HashSet<H256> = HashSet::new();
+        for block in reorg.connected.iter() {
+            for transaction in block.get_content() {
+                confirmed.insert(transaction.hash());
+            }
+        }
+        let mut state = self.block_state.get(&self.tip()).unwrap().clone();
+        let mut transactions = vec![];
+        for block in reorg.disconnected.iter().rev() {
+            for transaction in block.get_content().into_iter().filter(|transaction| !transaction.is_coinbase()) {
+                if !confirmed.contains(&transaction.hash()) && state.apply(&transaction) {
+                    transactions.push(transaction);
+                }
+            }
+        }
+        transactions
+    }
+    /// Like `reorg_transactions`, the UTXO transactions of the disconnected blocks of a reorg that
+    /// still apply on top of the new tip
+    pub fn reorg_utxo_transactions(&self, reorg: &Reorg) -> Vec<UtxoTransaction> {
+        let mut confirmed: HashSet<H256> = HashSet::new();
+        for block in reorg.connected.iter() {
+            for transaction in block.get_utxo_content() {
+                confirmed.insert(transaction.hash());
+            }
+        }
+        let mut state = self.block_state.get(&self.tip()).unwrap().clone();
+        let mut transactions = vec![];
+        for block in reorg.disconnected.iter().rev() {
+            for transaction in block.get_utxo_content() {
+                if !confirmed.contains(&transaction.hash()) && state.apply_utxo(&transaction).is_some() {
+                    transactions.push(transaction);
+                }
+            }
+        }
+        transactions
     }
     pub fn get_block(&self, block_hash: &H256) -> Option<Block> {
         let block: Option<Block>;
@@ -170,9 +622,174 @@ impl Blockchain {
     fn get_height(&self, block: &Block) -> usize {
         self.chain.get(&block.hash()).unwrap().1
     }
+    /// Get the height of a block, if it is in the chain
+    pub fn height(&self, block_hash: &H256) -> Option<usize> {
+        self.chain.get(block_hash).map(|entry| entry.1)
+    }
+    /// Get the header and height of a block in the chain or of a header waiting for its block
+    fn get_header(&self, hash: &H256) -> Option<(Header, usize)> {
+        match self.chain.get(hash) {
+            Some((block, height, _)) => Some((block.header(), *height)),
+            None => self.headers.get(hash).map(|(header, height, _)| (header.clone(), *height)),
+        }
+    }
+    /// Get the cumulative work of the chain ending in a block or in a header waiting for its block
+    fn header_work(&self, hash: &H256) -> Option<H256> {
+        match self.chain.get(hash) {
+            Some((_, _, work)) => Some(*work),
+            None => self.headers.get(hash).map(|(_, _, work)| *work),
+        }
+    }
+    /// The most cumulative work of a known chain, with or without its blocks
+    fn best_known_work(&self) -> H256 {
+        self.get_tip_work().max(self.best_header_work)
+    }
+    /// Whether a chain with `work`, whose blocks are at `difficulty`, is within `HEADER_REACH`
+    /// blocks of the best known chain
+    fn within_reach(&self, work: &H256, difficulty: &H256) -> bool {
+        work.saturating_add(&difficulty.work().scale(HEADER_REACH, 1)) >= self.best_known_work()
+    }
+    fn remove_header(&mut self, hash: &H256) -> bool {
+        let height = match self.headers.remove(hash) {
+            Some((_, height, _)) => height,
+            None => return false,
+        };
+        if let Some(hashes) = self.headers_by_height.get_mut(&height) {
+            hashes.remove(hash);
+            if hashes.is_empty() {
+                self.headers_by_height.remove(&height);
+            }
+        }
+        true
+    }
+    /// Forget the headers that fell out of reach of the best known chain
+    fn prune_headers(&mut self) {
+        let out_of_reach: Vec<H256> = self
+            .headers
+            .iter()
+            .filter(|(_, (header, _, work))| !self.within_reach(work, &header.get_difficulty()))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in out_of_reach.iter() {
+            self.remove_header(hash);
+        }
+    }
+    /// Get the height of a known header, with or without its block
+    pub fn header_height(&self, hash: &H256) -> Option<usize> {
+        self.get_header(hash).map(|(_, height)| height)
+    }
+    /// Whether the header of `hash` is known, with or without its block
+    pub fn has_header(&self, hash: &H256) -> bool {
+        self.chain.contains_key(hash) || self.headers.contains_key(hash)
+    }
+    /// Check a header against the proof of work, difficulty and timestamp rules and keep it until
+    /// its block is downloaded. Returns false if the header is already known.
+    ///
+    /// A valid header costs little work at a low difficulty, so only headers whose chain is within
+    /// `HEADER_REACH` blocks of the best known chain are kept, and at most `MAX_HEADERS` of them,
+    /// so that a peer cannot fill memory with side branches.
+    pub fn insert_header(&mut self, header: &Header) -> Result<bool, BlockValidationError> {
+        let hash = header.hash();
+        if self.has_header(&hash) {
+            return Ok(false);
+        }
+        let parent_height = match self.get_header(&header.get_parent()) {
+            Some((_, height)) => height,
+            None => return Err(BlockValidationError::UnknownParent),
+        };
+        check_header_proof_of_work(header)?;
+        if header.get_difficulty() != self.next_difficulty(&header.get_parent()) {
+            return Err(BlockValidationError::WrongDifficulty);
+        }
+        if header.get_timestamp() <= self.median_time_past(&header.get_parent()) {
+            return Err(BlockValidationError::TimestampTooOld);
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        if header.get_timestamp() > now + MAX_FUTURE_DRIFT {
+            return Err(BlockValidationError::TimestampInFuture);
+        }
+        let work = self.header_work(&header.get_parent()).unwrap().saturating_add(&header.get_difficulty().work());
+        if !self.within_reach(&work, &header.get_difficulty()) {
+            return Err(BlockValidationError::OutOfReach);
+        }
+        if self.headers.len() >= MAX_HEADERS {
+            self.prune_headers();
+            if self.headers.len() >= MAX_HEADERS {
+                return Err(BlockValidationError::TooManyHeaders);
+            }
+        }
+        self.headers.insert(hash, (header.clone(), parent_height + 1, work));
+        self.headers_by_height.entry(parent_height + 1).or_default().insert(hash);
+        self.best_header_work = self.best_header_work.max(work);
+        Ok(true)
+    }
+    /// Forget a header whose block turned out to be invalid, along with the headers building on it
+    pub fn discard_header(&mut self, hash: &H256) {
+        let height = match self.headers.get(hash) {
+            Some((_, height, _)) => *height,
+            None => return,
+        };
+        self.remove_header(hash);
+        let mut discarded: HashSet<H256> = HashSet::new();
+        discarded.insert(*hash);
+        let children: Vec<H256> = self.headers_by_height.range(height + 1..).flat_map(|(_, hashes)| hashes.iter().cloned()).collect();
+        for child_hash in children {
+            if discarded.contains(&self.headers[&child_hash].0.get_parent()) {
+                self.remove_header(&child_hash);
+                discarded.insert(child_hash);
+            }
+        }
+        self.best_header_work = self.headers.values().map(|(_, _, work)| *work).max().unwrap_or_default();
+    }
+    /// Height of the highest known header, with or without its block
+    pub fn best_header_height(&self) -> usize {
+        let header_height = self.headers_by_height.keys().next_back().cloned().unwrap_or(0);
+        header_height.max(self.get_tip_height())
+    }
+    /// Up to `max` hashes of headers whose block is still missing with their height, lowest first
+    pub fn missing_blocks(&self, max: usize) -> Vec<(H256, usize)> {
+        self.headers_by_height
+            .iter()
+            .flat_map(|(height, hashes)| hashes.iter().map(move |hash| (*hash, *height)))
+            .take(max)
+            .collect()
+    }
+    /// Hashes of the longest chain for a peer to find where its chain forks off ours, the ten
+    /// latest blocks one by one and then exponentially further apart down to the genesis block
+    pub fn locator(&self) -> Vec<H256> {
+        let chain = self.all_blocks_in_longest_chain();
+        let mut locator = vec![];
+        let mut index = chain.len() - 1;
+        let mut step = 1;
+        while index > 0 {
+            locator.push(chain[index]);
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            index = index.saturating_sub(step);
+        }
+        locator.push(self.genesis);
+        locator
+    }
+    /// Up to `max` headers of the longest chain after the first block of `locator` that is on it,
+    /// or after the genesis block if none is
+    pub fn headers_after(&self, locator: &[H256], max: usize) -> Vec<Header> {
+        let chain = self.all_blocks_in_longest_chain();
+        let positions: HashMap<H256, usize> = chain.iter().enumerate().map(|(index, hash)| (*hash, index)).collect();
+        let fork = locator.iter().find_map(|hash| positions.get(hash)).cloned().unwrap_or(0);
+        chain[fork + 1..]
+            .iter()
+            .take(max)
+            .map(|hash| self.chain[hash].0.header())
+            .collect()
+    }
     pub fn head(&self) -> Block{
         self.head.clone()
     }
+    /// Get the genesis block's hash, which peers on the same network share
+    pub fn genesis(&self) -> H256 {
+        self.genesis
+    }
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         self.head.hash()
@@ -180,24 +797,19 @@ impl Blockchain {
     pub fn get_tip_height(&self) -> usize {
         self.chain.get(&self.tip()).unwrap().1
     }
+    /// Get the cumulative work of the longest chain
+    pub fn get_tip_work(&self) -> H256 {
+        self.chain.get(&self.tip()).unwrap().2
+    }
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
-        let mut curr_block: Block = self.head.clone();
-        let mut parent_hash: H256;
-        let mut count = 0;
-        let longest_chain_len: usize = self.get_tip_height() + 1;
-        let mut list: Vec<H256> = vec![curr_block.hash(); longest_chain_len];
-        list[longest_chain_len - 1] = curr_block.hash();
-        loop {
-            parent_hash = curr_block.get_parent();
-            list[longest_chain_len - 1 - count] = parent_hash;
-
-            if count == longest_chain_len - 1 {
-                break;
-            }
-            curr_block = self.chain.get(&parent_hash).unwrap().0.clone();
-            count += 1;
+        let mut list: Vec<H256> = vec![];
+        let mut curr_block = Some(self.head.clone());
+        while let Some(block) = curr_block {
+            list.push(block.hash());
+            curr_block = self.get_block(&block.get_parent());
         }
+        list.reverse();
         list
     }
     pub fn all_transactions_in_longest_chain(&self) -> Vec<Vec<SignedTransaction>> {
@@ -296,8 +908,13 @@ mod tests {
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
         blockchain.insert(&block);
-        // fork by creating another block with the genesis hash as a parent
-        let block_fork = generate_random_block(&genesis_hash);
+        // fork by creating another block with the genesis hash as a parent, which loses the tie
+        let block_fork = loop {
+            let block_fork = generate_random_block(&genesis_hash);
+            if block_fork.hash() > block.hash() {
+                break block_fork;
+            }
+        };
         let second_block_fork: Block = generate_random_block(&block_fork.hash());
         blockchain.insert(&block_fork);
         assert_eq!(blockchain.tip(), block.hash());
@@ -313,6 +930,412 @@ mod tests {
         }
         assert_eq!(blockchain.tip(), second_block_fork.hash());
     }
+    #[test]
+    fn reorg_returns_abandoned_transactions() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key_pair = key_pair::from_seed([0; 32]);
+        let receiver = Address::from_public_key_bytes(key_pair::from_seed([1; 32]).public_key().as_ref());
+        let transaction = crate::types::transaction::generate_signed_transaction(&key_pair, &receiver, &0, &10000);
+        // same difficulty as the random blocks of the fork
+        let difficulty: H256 = [5; 32].into();
+        let coinbase = SignedTransaction::coinbase(receiver, 0, 1);
+        let block = Block::new(genesis_hash, 0, 0, difficulty, genesis_hash, vec![coinbase, transaction.clone()]);
+        assert!(blockchain.update_state(&block));
+        assert!(blockchain.insert(&block).is_none());
+
+        // a fork of equal work which loses the tie to the block
+        let block_fork = loop {
+            let block_fork = generate_random_block(&genesis_hash);
+            if block_fork.hash() > block.hash() {
+                break block_fork;
+            }
+        };
+        blockchain.update_state(&block_fork);
+        assert!(blockchain.insert(&block_fork).is_none());
+        let second_block_fork = generate_random_block(&block_fork.hash());
+        blockchain.update_state(&second_block_fork);
+        let reorg = blockchain.insert(&second_block_fork).unwrap();
+        assert_eq!(reorg.depth, 1);
+        assert_eq!(reorg.common_ancestor, genesis_hash);
+        assert_eq!(reorg.disconnected[0].hash(), block.hash());
+        let connected: Vec<H256> = reorg.connected.iter().map(|b| b.hash()).collect();
+        assert_eq!(connected, vec![block_fork.hash(), second_block_fork.hash()]);
+        let transactions = blockchain.reorg_transactions(&reorg);
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].hash(), transaction.hash());
+    }
+    #[test]
+    fn retarget_difficulty() {
+        let mut blockchain = Blockchain::new();
+        let difficulty = blockchain.head().get_difficulty();
+        let mut parent = blockchain.tip();
+        // blocks come twice as slow as the target, so the target doubles
+        for height in 1..RETARGET_INTERVAL {
+            assert_eq!(blockchain.next_difficulty(&parent), difficulty);
+            let timestamp = height as u128 * TARGET_BLOCK_TIME * 2;
+            let block = Block::new(parent, 0, timestamp, difficulty, parent, vec![]);
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        assert_eq!(blockchain.next_difficulty(&parent), difficulty.scale(2, 1));
+    }
+    #[test]
+    fn fork_choice_by_work() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let easy: H256 = [0xff; 32].into();
+        let hard: H256 = (&hex!("00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")).into();
+        assert_eq!(easy.work(), (&hex!("0000000000000000000000000000000000000000000000000000000000000001")).into());
+        assert_eq!(hard.work(), (&hex!("0000000000000000000000000000000000000000000000000000000000000100")).into());
+        // three easy blocks form the longer chain
+        let mut parent = genesis_hash;
+        for timestamp in 1..=3 {
+            let block = Block::new(parent, 0, timestamp, easy, parent, vec![]);
+            blockchain.insert(&block);
+            parent = block.hash();
+        }
+        assert_eq!(blockchain.get_tip_height(), 3);
+        // a single hard block carries more work
+        let block_hard = Block::new(genesis_hash, 0, 1, hard, genesis_hash, vec![]);
+        let reorg = blockchain.insert(&block_hard).unwrap();
+        assert_eq!(reorg.depth, 3);
+        assert_eq!(blockchain.tip(), block_hard.hash());
+        // on equal work the lower hash wins, whichever sibling arrives first
+        let siblings: Vec<Block> = (0..2).map(|timestamp| Block::new(genesis_hash, 0, timestamp, hard, genesis_hash, vec![])).collect();
+        let lower = siblings.iter().map(|block| block.hash()).min().unwrap();
+        for order in [[0, 1], [1, 0]] {
+            let mut blockchain = Blockchain::new();
+            for index in order {
+                blockchain.insert(&siblings[index]);
+            }
+            assert_eq!(blockchain.tip(), lower);
+        }
+    }
+    /// Solve the puzzle for a block on top of `parent` holding `content`
+    fn mine(blockchain: &Blockchain, parent: &H256, timestamp: u128, content: Vec<SignedTransaction>, merkle_root: H256) -> Block {
+        let difficulty = blockchain.next_difficulty(parent);
+        let mut nonce: u32 = 0;
+        loop {
+            let block = Block::new(*parent, nonce, timestamp, difficulty, merkle_root, content.clone());
+            if block.hash() <= difficulty {
+                return block;
+            }
+            nonce += 1;
+        }
+    }
+    /// Put the coinbase of a block on top of `parent` in front of `transactions`
+    fn with_coinbase(blockchain: &Blockchain, parent: &H256, transactions: Vec<SignedTransaction>) -> Vec<SignedTransaction> {
+        let height = blockchain.height(parent).unwrap() + 1;
+        let mut content = vec![SignedTransaction::coinbase(Address::random(), blockchain.block_subsidy(height), height)];
+        content.extend(transactions);
+        content
+    }
+    fn merkle_root(content: &[SignedTransaction]) -> H256 {
+        let hashes: Vec<H256> = content.iter().map(|t| t.hash()).collect();
+        MerkleTree::new(&hashes).root()
+    }
+    #[test]
+    fn verify_block_rules() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key_pair = key_pair::from_seed([0; 32]);
+        let receiver = Address::from_public_key_bytes(key_pair::from_seed([1; 32]).public_key().as_ref());
+        let transaction = crate::types::transaction::generate_signed_transaction(&key_pair, &receiver, &0, &10000);
+        let content = with_coinbase(&blockchain, &genesis_hash, vec![transaction.clone()]);
+        let root = merkle_root(&content);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), root);
+        assert_eq!(blockchain.verify_block(&block), Ok(()));
+
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), genesis_hash);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::MerkleRootMismatch));
+
+        let duplicated = with_coinbase(&blockchain, &genesis_hash, vec![transaction.clone(), transaction.clone()]);
+        let block = mine(&blockchain, &genesis_hash, now, duplicated.clone(), merkle_root(&duplicated));
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::DuplicateTransaction(transaction.hash())));
+
+        let block = mine(&blockchain, &genesis_hash, 0, content.clone(), root);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::TimestampTooOld));
+
+        let block = mine(&blockchain, &genesis_hash, now + 2 * MAX_FUTURE_DRIFT, content.clone(), root);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::TimestampInFuture));
+
+        let block = mine(&blockchain, &genesis_hash, now, vec![], MerkleTree::new(&[]).root());
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::EmptyBlock));
+
+        let difficulty = blockchain.next_difficulty(&genesis_hash);
+        let mut nonce: u32 = 0;
+        let block = loop {
+            let block = Block::new(genesis_hash, nonce, now, difficulty, root, content.clone());
+            if block.hash() > difficulty {
+                break block;
+            }
+            nonce += 1;
+        };
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::InsufficientProofOfWork));
+    }
+    #[test]
+    fn verify_coinbase() {
+        let blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key_pair = key_pair::from_seed([0; 32]);
+        let receiver = Address::from_public_key_bytes(key_pair::from_seed([1; 32]).public_key().as_ref());
+        let transaction = crate::types::transaction::generate_signed_transaction(&key_pair, &receiver, &0, &10000);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+        let content = vec![transaction.clone()];
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::MissingCoinbase));
+
+        // the coinbase may claim the fees on top of the subsidy, but no more
+        let claimable = DEFAULT_BLOCK_SUBSIDY + transaction.t.fee;
+        let content = vec![SignedTransaction::coinbase(receiver, claimable + 1, 1), transaction.clone()];
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::CoinbaseTooLarge));
+        let content = vec![SignedTransaction::coinbase(receiver, claimable, 1), transaction.clone()];
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Ok(()));
+
+        let content = vec![SignedTransaction::coinbase(receiver, DEFAULT_BLOCK_SUBSIDY, 2)];
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::InvalidCoinbase));
+
+        let extra = SignedTransaction::coinbase(receiver, 1, 1);
+        let content = with_coinbase(&blockchain, &genesis_hash, vec![extra.clone()]);
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::MisplacedCoinbase(extra.hash())));
+
+        // a block with only the coinbase is valid and pays the miner
+        let mut blockchain = blockchain;
+        let content = vec![SignedTransaction::coinbase(receiver, DEFAULT_BLOCK_SUBSIDY, 1)];
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Ok(()));
+        assert!(blockchain.update_state(&block));
+        assert_eq!(blockchain.block_state[&block.hash()].accounts[&receiver], (0, DEFAULT_BLOCK_SUBSIDY));
+    }
+    #[test]
+    fn block_subsidy_halves() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_subsidy(100, 10);
+        assert_eq!(blockchain.block_subsidy(1), 100);
+        assert_eq!(blockchain.block_subsidy(10), 50);
+        assert_eq!(blockchain.block_subsidy(25), 25);
+        assert_eq!(blockchain.block_subsidy(10 * 200), 0);
+    }
+    #[test]
+    fn refuse_side_branch_flood() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.genesis();
+        // blocks a target block time apart keep the difficulty where it is
+        let start = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() - 1000 * TARGET_BLOCK_TIME;
+        let mut hashes = vec![genesis];
+        for i in 1..=HEADER_REACH as u128 + 10 {
+            let header = mine(&blockchain, hashes.last().unwrap(), start + i * TARGET_BLOCK_TIME, vec![], H256::default()).header();
+            assert_eq!(blockchain.insert_header(&header), Ok(true));
+            hashes.push(header.hash());
+        }
+        // a flood of cheap headers forking off the genesis block is refused without being kept
+        for i in 1..=1000 {
+            let header = mine(&blockchain, &genesis, start + i, vec![], H256::default()).header();
+            assert_eq!(blockchain.insert_header(&header), Err(BlockValidationError::OutOfReach));
+        }
+        assert_eq!(blockchain.headers.len(), HEADER_REACH as usize + 10);
+        assert_eq!(blockchain.best_header_height(), HEADER_REACH as usize + 10);
+        // but a fork within reach of the best header is followed
+        let fork = mine(&blockchain, &hashes[20], start + 21 * TARGET_BLOCK_TIME + 1, vec![], H256::default()).header();
+        assert_eq!(blockchain.insert_header(&fork), Ok(true));
+        assert_eq!(blockchain.missing_blocks(2), vec![(hashes[1], 1), (hashes[2], 2)]);
+        // headers that fell out of reach are pruned once the headers fill up
+        for i in HEADER_REACH as u128 + 11..=HEADER_REACH as u128 + 25 {
+            let header = mine(&blockchain, hashes.last().unwrap(), start + i * TARGET_BLOCK_TIME, vec![], H256::default()).header();
+            assert_eq!(blockchain.insert_header(&header), Ok(true));
+            hashes.push(header.hash());
+        }
+        blockchain.prune_headers();
+        assert_eq!(blockchain.best_header_height(), HEADER_REACH as usize + 25);
+        assert!(!blockchain.has_header(&fork.hash()));
+        assert!(!blockchain.has_header(&hashes[1]));
+    }
+    #[test]
+    fn headers_first() {
+        let mut source = Blockchain::new();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut blocks = vec![];
+        // past the first retarget, which header validation computes from headers alone
+        for i in 0..12 {
+            let parent = source.tip();
+            let content = with_coinbase(&source, &parent, vec![]);
+            let block = mine(&source, &parent, now + i, content.clone(), merkle_root(&content));
+            assert!(source.update_state(&block));
+            source.insert(&block);
+            blocks.push(block);
+        }
+        let locator = source.locator();
+        assert_eq!(locator[0], source.tip());
+        assert_eq!(*locator.last().unwrap(), source.genesis());
+
+        let mut blockchain = Blockchain::new();
+        let headers = source.headers_after(&blockchain.locator(), 100);
+        assert_eq!(headers.len(), 12);
+        let some: Vec<H256> = source.headers_after(&[[9; 32].into(), blocks[4].hash()], 3).iter().map(|h| h.hash()).collect();
+        assert_eq!(some, blocks[5..8].iter().map(|b| b.hash()).collect::<Vec<H256>>());
+        for header in headers.iter() {
+            assert_eq!(blockchain.insert_header(header), Ok(true));
+        }
+        assert_eq!(blockchain.insert_header(&headers[0]), Ok(false));
+        assert_eq!(blockchain.best_header_height(), 12);
+        assert_eq!(blockchain.get_tip_height(), 0);
+        let missing = blockchain.missing_blocks(2);
+        assert_eq!(missing, vec![(blocks[0].hash(), 1), (blocks[1].hash(), 2)]);
+
+        // the bodies connect on top of the genesis block and leave the headers
+        for block in blocks[..6].iter() {
+            assert_eq!(blockchain.verify_block(block), Ok(()));
+            assert!(blockchain.update_state(block));
+            blockchain.insert(block);
+        }
+        assert_eq!(blockchain.missing_blocks(100).len(), 6);
+        blockchain.discard_header(&blocks[8].hash());
+        assert_eq!(blockchain.missing_blocks(100), vec![(blocks[6].hash(), 7), (blocks[7].hash(), 8)]);
+
+        let content = with_coinbase(&blockchain, &blocks[5].hash(), vec![]);
+        let easy = Block::new(blocks[5].hash(), 0, now + 20, [255; 32].into(), merkle_root(&content), content.clone());
+        assert_eq!(blockchain.insert_header(&easy.header()), Err(BlockValidationError::WrongDifficulty));
+        let orphan = Block::new([9; 32].into(), 0, now + 20, [255; 32].into(), merkle_root(&content), content);
+        assert_eq!(blockchain.insert_header(&orphan.header()), Err(BlockValidationError::UnknownParent));
+    }
+    #[test]
+    fn verify_block_against_parent_state() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip();
+        let key_pair = key_pair::from_seed([0; 32]);
+        let receiver = Address::from_public_key_bytes(key_pair::from_seed([1; 32]).public_key().as_ref());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        // two transactions of the same sender with consecutive nonces in one block
+        let first = crate::types::transaction::generate_signed_transaction(&key_pair, &receiver, &0, &10000);
+        let second = crate::types::transaction::generate_signed_transaction(&key_pair, &receiver, &1, &10000);
+        let content = with_coinbase(&blockchain, &genesis_hash, vec![first.clone(), second.clone()]);
+        let block = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Ok(()));
+        assert!(blockchain.update_state(&block));
+        blockchain.insert(&block);
+        // a fork block spending nonce 1 again is valid against the genesis state it builds on
+        let other = Address::from_public_key_bytes(key_pair::from_seed([2; 32]).public_key().as_ref());
+        let fork = crate::types::transaction::generate_signed_transaction(&key_pair, &other, &0, &10000);
+        let content = with_coinbase(&blockchain, &genesis_hash, vec![fork.clone()]);
+        let block_fork = mine(&blockchain, &genesis_hash, now, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block_fork), Ok(()));
+        // but not on top of the block that already used it
+        let tip = blockchain.tip();
+        let content = with_coinbase(&blockchain, &tip, vec![fork.clone()]);
+        let block_replay = mine(&blockchain, &tip, now + 1, content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block_replay), Err(BlockValidationError::InvalidTransaction(fork.hash())));
+    }
+    /// Mine a UTXO block on top of `parent` claiming the subsidy plus `fee`
+    fn mine_utxo(blockchain: &Blockchain, parent: &H256, fee: usize, utxo_content: Vec<UtxoTransaction>) -> Block {
+        let height = blockchain.height(parent).unwrap() + 1;
+        let coinbase = SignedTransaction::coinbase(Address::random(), blockchain.block_subsidy(height) + fee, height);
+        let mut hashes = vec![coinbase.hash()];
+        hashes.extend(utxo_content.iter().map(|t| t.hash()));
+        let merkle_root = MerkleTree::new(&hashes).root();
+        let difficulty = blockchain.next_difficulty(parent);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let mut nonce: u32 = 0;
+        loop {
+            let block = Block::new_with_utxo(*parent, nonce, timestamp, difficulty, merkle_root, vec![coinbase.clone()], utxo_content.clone());
+            if block.hash() <= difficulty {
+                return block;
+            }
+            nonce += 1;
+        }
+    }
+    #[test]
+    fn verify_utxo_transactions() {
+        use crate::types::utxo::{Input, Output};
+        let mut blockchain = Blockchain::with_model(LedgerModel::Utxo);
+        assert_ne!(blockchain.genesis(), Blockchain::new().genesis());
+        let genesis_hash = blockchain.tip();
+        let key_pair = key_pair::from_seed([0; 32]);
+        let owner = Address::from_public_key_bytes(key_pair.public_key().as_ref());
+        let receiver = Address::random();
+        let ico = (genesis_hash, 0);
+        assert_eq!(blockchain.get_tip_state().utxos.get(&ico), Some(&(10000, owner)));
+        let spend = |outputs: Vec<Output>, key: &Ed25519KeyPair| {
+            let mut transaction = UtxoTransaction { inputs: vec![Input::new(genesis_hash, 0)], outputs };
+            transaction.sign_input(0, key);
+            transaction
+        };
+
+        // outputs worth more than the inputs
+        let inflating = spend(vec![Output { value: 10001, recipient: receiver }], &key_pair);
+        let block = mine_utxo(&blockchain, &genesis_hash, 0, vec![inflating.clone()]);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::InvalidTransaction(inflating.hash())));
+        // signed by a key that does not own the output
+        let stolen = spend(vec![Output { value: 10000, recipient: receiver }], &key_pair::from_seed([1; 32]));
+        let block = mine_utxo(&blockchain, &genesis_hash, 0, vec![stolen.clone()]);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::InvalidTransaction(stolen.hash())));
+        // account transactions do not belong on a UTXO chain
+        let account_transaction = crate::types::transaction::generate_signed_transaction(&key_pair, &receiver, &0, &10000);
+        let content = with_coinbase(&blockchain, &genesis_hash, vec![account_transaction]);
+        let block = mine(&blockchain, &genesis_hash, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(), content.clone(), merkle_root(&content));
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::WrongLedgerModel));
+
+        // pay 6000 and take 3990 back, leaving a fee of 10 to the miner
+        let payment = spend(vec![Output { value: 6000, recipient: receiver }, Output { value: 3990, recipient: owner }], &key_pair);
+        let block = mine_utxo(&blockchain, &genesis_hash, 11, vec![payment.clone()]);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::CoinbaseTooLarge));
+        let block = mine_utxo(&blockchain, &genesis_hash, 10, vec![payment.clone()]);
+        assert_eq!(blockchain.verify_block(&block), Ok(()));
+        assert!(blockchain.update_state(&block));
+        blockchain.insert(&block);
+        let state = blockchain.get_tip_state();
+        assert!(!state.utxos.contains_key(&ico));
+        assert_eq!(state.utxos.get(&(payment.hash(), 0)), Some(&(6000, receiver)));
+        assert_eq!(state.utxos.get(&(payment.hash(), 1)), Some(&(3990, owner)));
+        let coinbase = &block.get_content()[0];
+        assert_eq!(state.utxos.get(&(coinbase.hash(), 0)), Some(&(coinbase.t.value as u64, coinbase.t.receiver)));
+
+        // the ico output is spent now
+        let tip = blockchain.tip();
+        let double_spend = spend(vec![Output { value: 10000, recipient: receiver }], &key_pair);
+        let block = mine_utxo(&blockchain, &tip, 0, vec![double_spend.clone()]);
+        assert_eq!(blockchain.verify_block(&block), Err(BlockValidationError::InvalidTransaction(double_spend.hash())));
+
+        // a longer fork without the payment gives it back, but not the coinbase it was mined with
+        let fork = mine_utxo(&blockchain, &genesis_hash, 0, vec![]);
+        assert!(blockchain.update_state(&fork));
+        let tie = blockchain.insert(&fork);
+        let fork_tip = mine_utxo(&blockchain, &fork.hash(), 0, vec![]);
+        assert!(blockchain.update_state(&fork_tip));
+        let reorg = tie.or(blockchain.insert(&fork_tip)).unwrap();
+        let returned: Vec<H256> = blockchain.reorg_utxo_transactions(&reorg).iter().map(|t| t.hash()).collect();
+        assert_eq!(returned, vec![payment.hash()]);
+        assert!(blockchain.reorg_transactions(&reorg).is_empty());
+    }
+    #[test]
+    fn reopen_from_data_dir() {
+        let data_dir = std::env::temp_dir().join(format!("bitcoin-test-{}", thread_rng().gen::<u64>()));
+        let mut blockchain = Blockchain::open(&data_dir).unwrap();
+        let genesis_hash = blockchain.tip();
+        let block = generate_random_block(&genesis_hash);
+        blockchain.update_state(&block);
+        blockchain.insert(&block);
+        let block_fork = generate_random_block(&genesis_hash);
+        blockchain.update_state(&block_fork);
+        blockchain.insert(&block_fork);
+        let second_block_fork = generate_random_block(&block_fork.hash());
+        blockchain.update_state(&second_block_fork);
+        blockchain.insert(&second_block_fork);
+        drop(blockchain);
+
+        let blockchain = Blockchain::open(&data_dir).unwrap();
+        assert_eq!(blockchain.chain.len(), 4);
+        assert_eq!(blockchain.tip(), second_block_fork.hash());
+        assert_eq!(blockchain.get_tip_height(), 2);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
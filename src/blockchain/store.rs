@@ -0,0 +1,193 @@
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+const BLOCK_FILE: &str = "blocks.dat";
+
+/// Append-only block file. Every block is stored as a frame made of a 4-byte big endian length
+/// followed by the bincode encoding of the block. The index
+/// from block hash to file offset is rebuilt by scanning the file when the store is opened.
+#[derive(Debug)]
+pub struct BlockStore {
+    file: File,
+    index: HashMap<H256, u64>,
+    len: u64,
+}
+
+impl BlockStore {
+    /// Open (or create) the block file in `dir`, returning the store and every stored block in the
+    /// order it was written. Since a block is only written after its parent, replaying the blocks
+    /// in this order rebuilds the chain. The last frame, if a crash cut it short or left it
+    /// unreadable, is truncated away; an unreadable frame before it, or a length running over a
+    /// whole frame after it, is an error, since dropping it would drop every block after it too.
+    pub fn open(dir: &Path) -> io::Result<(Self, Vec<Block>)> {
+        fs::create_dir_all(dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(BLOCK_FILE))?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let mut index = HashMap::new();
+        let mut blocks = vec![];
+        let mut offset: usize = 0;
+        while offset + 4 <= bytes.len() {
+            let mut size_buffer: [u8; 4] = [0; 4];
+            size_buffer.copy_from_slice(&bytes[offset..offset + 4]);
+            let size = u32::from_be_bytes(size_buffer) as usize;
+            if offset + 4 + size > bytes.len() {
+                // a torn write leaves the last frame short, a length that jumps over a whole frame
+                // was corrupted
+                if let Some(next) = next_frame(&bytes, offset + 4) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "corrupt length at offset {} of {}, runs over the block at offset {}",
+                            offset, BLOCK_FILE, next
+                        ),
+                    ));
+                }
+                break;
+            }
+            let block: Block = match bincode::deserialize(&bytes[offset + 4..offset + 4 + size]) {
+                Ok(block) => block,
+                Err(_) if offset + 4 + size == bytes.len() => break,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt block at offset {} of {}: {}", offset, BLOCK_FILE, e),
+                    ))
+                }
+            };
+            index.insert(block.hash(), offset as u64);
+            blocks.push(block);
+            offset += 4 + size;
+        }
+        if offset < bytes.len() {
+            warn!("Dropping {} trailing bytes of a partially written block", bytes.len() - offset);
+            file.set_len(offset as u64)?;
+        }
+        let store = Self {
+            file,
+            index,
+            len: offset as u64,
+        };
+        Ok((store, blocks))
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.index.contains_key(hash)
+    }
+
+    /// Append a block to the file and flush it to disk
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return Ok(());
+        }
+        let payload = bincode::serialize(block).unwrap();
+        let size_buffer = (payload.len() as u32).to_be_bytes();
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        frame.extend_from_slice(&size_buffer);
+        frame.extend_from_slice(&payload);
+        self.file.write_all(&frame)?;
+        self.file.sync_data()?;
+        self.index.insert(hash, self.len);
+        self.len += frame.len() as u64;
+        Ok(())
+    }
+}
+
+/// Offset of the first whole frame holding a block that starts at or after `from`, if any
+fn next_frame(bytes: &[u8], from: usize) -> Option<usize> {
+    (from..bytes.len().saturating_sub(4)).find(|&start| {
+        let mut size_buffer: [u8; 4] = [0; 4];
+        size_buffer.copy_from_slice(&bytes[start..start + 4]);
+        let size = u32::from_be_bytes(size_buffer) as usize;
+        let payload = match bytes.get(start + 4..start + 4 + size) {
+            Some(payload) => payload,
+            None => return false,
+        };
+        // a block read from stray bytes would not encode back to the same bytes
+        match bincode::deserialize::<Block>(payload) {
+            Ok(block) => bincode::serialize(&block).is_ok_and(|encoded| encoded == payload),
+            Err(_) => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::block::generate_random_block;
+    use rand::Rng;
+    use std::path::PathBuf;
+
+    /// A store holding three blocks in a new data directory, and the offsets of their frames
+    fn store_of_three() -> (PathBuf, Vec<Block>, Vec<u64>) {
+        let dir = std::env::temp_dir().join(format!("bitcoin-test-{}", rand::thread_rng().gen::<u64>()));
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        let mut blocks = vec![generate_random_block(&H256::default())];
+        for _ in 0..2 {
+            blocks.push(generate_random_block(&blocks.last().unwrap().hash()));
+        }
+        let mut offsets = vec![];
+        for block in blocks.iter() {
+            offsets.push(store.len);
+            store.append(block).unwrap();
+        }
+        (dir, blocks, offsets)
+    }
+
+    /// Corrupt the frame at `offset` by setting its length to `size`
+    fn corrupt(dir: &Path, offset: u64, size: u32) {
+        let path = dir.join(BLOCK_FILE);
+        let mut bytes = fs::read(&path).unwrap();
+        let offset = offset as usize;
+        bytes[offset..offset + 4].copy_from_slice(&size.to_be_bytes());
+        fs::write(&path, bytes).unwrap();
+    }
+
+    #[test]
+    fn truncate_torn_write() {
+        let (dir, blocks, _) = store_of_three();
+        let path = dir.join(BLOCK_FILE);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 10).unwrap();
+        drop(file);
+        let (_, reopened) = BlockStore::open(&dir).unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened[1].hash(), blocks[1].hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_corrupt_middle_frame() {
+        let (dir, _, offsets) = store_of_three();
+        // too short for a block
+        corrupt(&dir, offsets[1], 2);
+        let len = fs::metadata(dir.join(BLOCK_FILE)).unwrap().len();
+        assert_eq!(BlockStore::open(&dir).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // nothing was truncated
+        assert_eq!(fs::metadata(dir.join(BLOCK_FILE)).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuse_length_past_the_end() {
+        let (dir, _, offsets) = store_of_three();
+        // running past the end of the file, over the last block
+        corrupt(&dir, offsets[1], 1 << 20);
+        let len = fs::metadata(dir.join(BLOCK_FILE)).unwrap().len();
+        assert_eq!(BlockStore::open(&dir).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(dir.join(BLOCK_FILE)).unwrap().len(), len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
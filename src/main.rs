@@ -4,22 +4,24 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod mempool;
 pub mod miner;
 pub mod network;
 pub mod types;
 pub mod transaction_generator;
-use std::collections::HashMap;
+#[cfg(any(test, test_utilities))]
+pub mod simulator;
 use crate::types::key_pair;
 use crate::types::address::Address;
-use types::transaction::{SignedTransaction, Transaction};
-use types::hash::{Hashable, H256};
 use api::Server as ApiServer;
-use blockchain::Blockchain;
+use blockchain::{Blockchain, LedgerModel};
+use mempool::Mempool;
 use clap::clap_app;
 use log::{error, info};
 use ring::signature::KeyPair;
 use smol::channel;
 use std::net;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -33,8 +35,27 @@ fn main() {
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start, as ADDR, or ADDR@KEY to require the peer to prove the hex encoded identity key KEY")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg outbound: --outbound [INT] default_value("8") "Sets the number of outbound peers dialed from the addresses heard of")
+     (@arg max_outbound: --("max-outbound") [INT] default_value("16") "Sets the most outbound peers, including the ones connected to on request")
+     (@arg max_inbound: --("max-inbound") [INT] default_value("117") "Sets the most inbound peers")
+     (@arg ping_interval: --("ping-interval") [SECS] default_value("30") "Sets the seconds between pings that measure the round-trip time to peers")
+     (@arg ping_timeout: --("ping-timeout") [SECS] default_value("90") "Sets the seconds a peer has to answer a ping before it is disconnected")
+     (@arg ban_time: --("ban-time") [SECS] default_value("86400") "Sets the seconds a peer that misbehaved too much is banned for")
+     (@arg encryption: --encryption [MODE] default_value("require") "Sets whether links to peers are encrypted, off, prefer or require, where prefer lets a man in the middle turn encryption off")
+     (@arg delay: --delay [MS] default_value("0") "Sets the milliseconds messages to peers are held back, to simulate a wide-area network")
+     (@arg jitter: --jitter [MS] default_value("0") "Sets the most milliseconds the delay of a message to a peer randomly varies by")
+     (@arg bandwidth: --bandwidth [KBPS] default_value("0") "Sets the kilobits per second messages to each peer are sent at, 0 for no limit")
+     (@arg drop_rate: --("drop-rate") [RATE] default_value("0") "Sets the share of messages to peers that are dropped, from 0 to 1")
+     (@arg data_dir: --("data-dir") [DIR] "Sets the directory the blockchain and the node identity key are persisted in")
+     (@arg block_subsidy: --("block-subsidy") [INT] default_value("50") "Sets the coins minted by the coinbase of the first blocks")
+     (@arg halving_interval: --("halving-interval") [INT] default_value("210") "Sets the number of blocks after which the block subsidy halves")
+     (@arg ledger: --ledger [MODEL] default_value("account") "Sets the ledger model of the chain, account or utxo")
+     (@arg mempool_size: --("mempool-size") [INT] default_value("5000") "Sets the maximum number of transactions in the mempool")
+     (@arg mempool_bytes: --("mempool-bytes") [INT] default_value("5000000") "Sets the maximum total size in bytes of the transactions in the mempool")
+     (@arg mempool_expiry: --("mempool-expiry") [SECS] default_value("10800") "Sets the seconds after which a transaction not mined is dropped from the mempool")
+     (@arg fee_bump: --("fee-bump") [PERCENT] default_value("10") "Sets how many percent more fee a transaction has to pay to replace one with the same nonce")
     )
     .get_matches();
     // create random account addresses for the tx_generator to use to make valid transactions
@@ -45,9 +66,109 @@ fn main() {
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    let transactions: HashMap<H256,SignedTransaction> = HashMap::new();
-    let mempool = Arc::new(Mutex::new(transactions));
+    let model = match matches.value_of("ledger").unwrap() {
+        "account" => LedgerModel::Account,
+        "utxo" => LedgerModel::Utxo,
+        other => {
+            error!("Unknown ledger model {}, expected account or utxo", other);
+            process::exit(1);
+        }
+    };
+    let mut blockchain = Blockchain::with_model(model);
+    let block_subsidy = matches
+        .value_of("block_subsidy")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block subsidy: {}", e);
+            process::exit(1);
+        });
+    let halving_interval = matches
+        .value_of("halving_interval")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing halving interval: {}", e);
+            process::exit(1);
+        });
+    if halving_interval == 0 {
+        error!("The halving interval must be positive");
+        process::exit(1);
+    }
+    blockchain.set_subsidy(block_subsidy, halving_interval);
+    // replay the stored blocks once the consensus parameters are set
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        blockchain.load(Path::new(data_dir)).unwrap_or_else(|e| {
+            error!("Error opening data directory {}: {}", data_dir, e);
+            process::exit(1);
+        });
+    }
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool_size = matches
+        .value_of("mempool_size")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool size: {}", e);
+            process::exit(1);
+        });
+    let mempool_bytes = matches
+        .value_of("mempool_bytes")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool bytes: {}", e);
+            process::exit(1);
+        });
+    let mempool_expiry = matches
+        .value_of("mempool_expiry")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing mempool expiry: {}", e);
+            process::exit(1);
+        });
+    let fee_bump = matches
+        .value_of("fee_bump")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing fee bump: {}", e);
+            process::exit(1);
+        });
+    let mut mempool = Mempool::with_limits(mempool_size, mempool_bytes, time::Duration::from_secs(mempool_expiry));
+    mempool.set_fee_bump(fee_bump);
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        let tip_state = blockchain.lock().unwrap().get_tip_state();
+        match mempool.load(Path::new(data_dir), &tip_state) {
+            Ok(admitted) => info!("Restored {} transactions to the mempool", admitted),
+            Err(e) => error!("Error loading the mempool from {}: {}", data_dir, e),
+        }
+    }
+    let mempool = Arc::new(Mutex::new(mempool));
+    // save the mempool periodically and on shutdown
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        let data_dir = PathBuf::from(data_dir);
+        let periodic_mempool = Arc::clone(&mempool);
+        let periodic_dir = data_dir.clone();
+        thread::spawn(move || loop {
+            thread::sleep(mempool::SAVE_INTERVAL);
+            if let Err(e) = periodic_mempool.lock().unwrap().save(&periodic_dir) {
+                error!("Error saving the mempool: {}", e);
+            }
+        });
+        let shutdown_mempool = Arc::clone(&mempool);
+        ctrlc::set_handler(move || {
+            if let Err(e) = shutdown_mempool.lock().unwrap().save(&data_dir) {
+                error!("Error saving the mempool: {}", e);
+            }
+            process::exit(0);
+        })
+        .unwrap_or_else(|e| {
+            error!("Error setting the shutdown handler: {}", e);
+            process::exit(1);
+        });
+    }
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -85,7 +206,143 @@ fn main() {
     let (msg_tx, msg_rx) = channel::bounded(10000);
 
     // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
+    let version_blockchain = Arc::clone(&blockchain);
+    let version_source: network::server::VersionSource = Arc::new(move || {
+        let blockchain = version_blockchain.lock().unwrap();
+        network::message::Version::new(blockchain.genesis(), blockchain.get_tip_height(), p2p_addr)
+    });
+    let (mut server_ctx, server) = network::server::new(p2p_addr, msg_tx, version_source).unwrap();
+    let outbound = matches
+        .value_of("outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing outbound peers: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_target_outbound(outbound);
+    let max_outbound = matches
+        .value_of("max_outbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max outbound peers: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_max_outbound(max_outbound);
+    let max_inbound = matches
+        .value_of("max_inbound")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max inbound peers: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_max_inbound(max_inbound);
+    let ping_interval = matches
+        .value_of("ping_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ping interval: {}", e);
+            process::exit(1);
+        });
+    if ping_interval == 0 {
+        error!("The ping interval must be positive");
+        process::exit(1);
+    }
+    server_ctx.set_ping_interval(time::Duration::from_secs(ping_interval));
+    let ping_timeout = matches
+        .value_of("ping_timeout")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ping timeout: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_ping_timeout(time::Duration::from_secs(ping_timeout));
+    let ban_time = matches
+        .value_of("ban_time")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing ban time: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_ban_duration(time::Duration::from_secs(ban_time));
+    let encryption = matches
+        .value_of("encryption")
+        .unwrap()
+        .parse::<network::secure::Encryption>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing encryption: {}", e);
+            process::exit(1);
+        });
+    server_ctx.set_encryption(encryption);
+    let delay = matches
+        .value_of("delay")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing delay: {}", e);
+            process::exit(1);
+        });
+    let jitter = matches
+        .value_of("jitter")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing jitter: {}", e);
+            process::exit(1);
+        });
+    let bandwidth = matches
+        .value_of("bandwidth")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing bandwidth: {}", e);
+            process::exit(1);
+        });
+    let drop_rate = matches
+        .value_of("drop_rate")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing drop rate: {}", e);
+            process::exit(1);
+        });
+    let shape = network::shaping::Shape {
+        delay_ms: delay,
+        jitter_ms: jitter,
+        bandwidth_kbps: bandwidth,
+        drop_rate,
+    };
+    shape.check().unwrap_or_else(|e| {
+        error!("Error in network shaping: {}", e);
+        process::exit(1);
+    });
+    server_ctx.set_shaping(shape);
+    // keep the identity of the node across restarts if there is somewhere to keep it
+    if let Some(data_dir) = matches.value_of("data_dir") {
+        let identity = network::secure::load_identity(Path::new(data_dir)).unwrap_or_else(|e| {
+            error!("Error loading the node identity key from {}: {}", data_dir, e);
+            process::exit(1);
+        });
+        server_ctx.set_identity(identity);
+    }
+    // the peers to connect to at start, and the identity keys pinned for them
+    let mut known_peers = vec![];
+    for peer in matches.values_of("known_peer").into_iter().flatten() {
+        match network::secure::parse_peer(peer) {
+            Ok((addr, key)) => {
+                if let Some(key) = key {
+                    server_ctx.pin_key(addr, key);
+                }
+                known_peers.push(addr);
+            }
+            Err(e) => error!("Error parsing peer {}: {}", peer, e),
+        }
+    }
     server_ctx.start().unwrap();
 
     // start the worker
@@ -102,7 +359,8 @@ fn main() {
     worker_ctx.start();
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
+    let miner_address = Address::from_public_key_bytes(key_pair.public_key().as_ref());
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, miner_address);
     let miner_worker_ctx = miner::worker::Worker::new(&blockchain,&mempool, &server, finished_block_chan);
     let (tx_generator_ctx, tx_generator) = transaction_generator::new(&blockchain, &mempool, &server, key_pair);
  
@@ -110,19 +368,11 @@ fn main() {
     miner_worker_ctx.start();
     tx_generator_ctx.start();
     // connect to known peers
-    if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
+    if !known_peers.is_empty() {
         let server = server.clone();
         thread::spawn(move || {
-            for peer in known_peers {
+            for addr in known_peers {
                 loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
                     match server.connect(addr) {
                         Ok(_) => {
                             info!("Connected to outgoing peer {}", &addr);
@@ -0,0 +1,813 @@
+use crate::blockchain::State;
+use crate::types::address::Address;
+use crate::types::clock::{self, Clock};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{self, SignedTransaction};
+use crate::types::utxo::{OutPoint, UtxoTransaction};
+use log::debug;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_COUNT: usize = 5000;
+pub const DEFAULT_MAX_BYTES: usize = 5_000_000;
+/// Transactions not mined within three hours are dropped
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(3 * 60 * 60);
+/// A replacement has to pay at least 10% more fee than the transaction it replaces
+pub const DEFAULT_FEE_BUMP: usize = 10;
+/// How far past the next nonce of its sender a pending transaction may be
+pub const MAX_NONCE_GAP: usize = 64;
+
+/// How often a node with a data directory saves its mempool, besides on shutdown
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+const MEMPOOL_FILE: &str = "mempool.dat";
+/// Version of the mempool file, written ahead of the transactions
+const MEMPOOL_FILE_VERSION: u32 = 2;
+
+struct Entry {
+    transaction: SignedTransaction,
+    size: usize,
+    added: Instant,
+}
+
+impl Entry {
+    /// Order by fee rate, then older first, comparing fee / size without dividing
+    fn cmp_fee_rate(&self, other: &Entry) -> Ordering {
+        let lhs = self.transaction.t.fee as u128 * other.size as u128;
+        let rhs = other.transaction.t.fee as u128 * self.size as u128;
+        lhs.cmp(&rhs).then_with(|| self.added.cmp(&other.added))
+    }
+}
+
+struct UtxoEntry {
+    transaction: UtxoTransaction,
+    fee: u64,
+    size: usize,
+    added: Instant,
+}
+
+/// The transactions waiting to be mined. Every sender has a ready queue of transactions with
+/// consecutive nonces that apply in order on top of the tip state, and a pending queue of
+/// transactions whose nonce is further ahead, which get promoted once the gap before them is
+/// filled. A ready or pending transaction can be replaced by one with the same nonce paying enough
+/// more fee. The pool is capped by count and by bytes, and evicts pending transactions before ready
+/// ones, each time the queue tail with the lowest fee rate (the oldest of equal ones).
+///
+/// Transactions of the UTXO ledger model are kept apart. Each of them spends outputs of the tip
+/// state that no other one in the pool spends; the first one seen wins, there is no replacement,
+/// and once the pool is full new ones are turned away rather than evicting others.
+pub struct Mempool {
+    transactions: HashMap<H256, Entry>,
+    by_sender: HashMap<Address, BTreeMap<usize, H256>>,
+    pending: HashMap<Address, BTreeMap<usize, H256>>,
+    utxo_transactions: HashMap<H256, UtxoEntry>,
+    /// the UTXO transaction spending each output
+    spent: HashMap<OutPoint, H256>,
+    bytes: usize,
+    max_count: usize,
+    max_bytes: usize,
+    expiry: Duration,
+    fee_bump: usize,
+    clock: Clock,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_MAX_COUNT, DEFAULT_MAX_BYTES, DEFAULT_EXPIRY)
+    }
+
+    pub fn with_limits(max_count: usize, max_bytes: usize, expiry: Duration) -> Self {
+        Mempool {
+            transactions: HashMap::new(),
+            by_sender: HashMap::new(),
+            pending: HashMap::new(),
+            utxo_transactions: HashMap::new(),
+            spent: HashMap::new(),
+            bytes: 0,
+            max_count,
+            max_bytes,
+            expiry,
+            fee_bump: DEFAULT_FEE_BUMP,
+            clock: clock::system(),
+        }
+    }
+
+    /// Set the percentage by which a replacement has to raise the fee
+    pub fn set_fee_bump(&mut self, fee_bump: usize) {
+        self.fee_bump = fee_bump;
+    }
+
+    /// Set the clock transactions are timed by for their expiry
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len() + self.utxo_transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of transactions waiting for a nonce gap to be filled
+    pub fn pending_len(&self) -> usize {
+        self.pending.values().map(|queue| queue.len()).sum()
+    }
+
+    /// Serialized size of all the transactions in the pool
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.transactions.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        self.transactions.get(hash).map(|entry| &entry.transaction)
+    }
+
+    /// All transactions, ready and pending
+    pub fn iter(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.transactions.values().map(|entry| &entry.transaction)
+    }
+
+    pub fn contains_utxo(&self, hash: &H256) -> bool {
+        self.utxo_transactions.contains_key(hash)
+    }
+
+    pub fn get_utxo(&self, hash: &H256) -> Option<&UtxoTransaction> {
+        self.utxo_transactions.get(hash).map(|entry| &entry.transaction)
+    }
+
+    /// The UTXO transactions with their fee
+    pub fn utxo_iter(&self) -> impl Iterator<Item = (&UtxoTransaction, u64)> {
+        self.utxo_transactions.values().map(|entry| (&entry.transaction, entry.fee))
+    }
+
+    /// Whether a UTXO transaction in the pool spends `out_point`
+    pub fn spends(&self, out_point: &OutPoint) -> bool {
+        self.spent.contains_key(out_point)
+    }
+
+    /// The nonce the next ready transaction of `sender` needs on top of `state`
+    pub fn next_nonce(&self, sender: &Address, state: &State) -> usize {
+        match self.by_sender.get(sender).and_then(|queue| queue.keys().next_back()) {
+            Some(nonce) => nonce + 1,
+            None => state.accounts.get(sender).map(|account| account.0 + 1).unwrap_or(1),
+        }
+    }
+
+    /// `state` after the ready transactions of `sender` with a nonce below `nonce`
+    fn queued_state(&self, sender: &Address, state: &State, nonce: usize) -> State {
+        let mut state = state.clone();
+        if let Some(queue) = self.by_sender.get(sender) {
+            for hash in queue.range(..nonce).map(|(_, hash)| hash) {
+                state.apply(&self.transactions[hash].transaction);
+            }
+        }
+        state
+    }
+
+    /// Whether `new` pays enough more than `old` to replace it
+    fn pays_fee_bump(&self, old: &SignedTransaction, new: &SignedTransaction) -> bool {
+        let required = old.t.fee as u128 * (100 + self.fee_bump as u128);
+        new.t.fee > old.t.fee && new.t.fee as u128 * 100 >= required
+    }
+
+    fn add_entry(&mut self, transaction: SignedTransaction, size: usize) {
+        self.bytes += size;
+        self.transactions.insert(transaction.hash(), Entry { transaction, size, added: (self.clock)() });
+    }
+
+    /// Admit a transaction on top of `state`, the tip state. A transaction extending its sender's
+    /// ready queue has to apply after it, one further ahead waits in the pending queue, and one with
+    /// the nonce of a queued transaction has to pay the fee bump to replace it. Returns false if it
+    /// is not admitted, or evicted right away because the pool is full.
+    pub fn insert(&mut self, transaction: SignedTransaction, state: &State) -> bool {
+        let hash = transaction.hash();
+        if transaction.is_coinbase() || self.contains(&hash) {
+            return false;
+        }
+        let size = bincode::serialized_size(&transaction).unwrap() as usize;
+        if size > self.max_bytes {
+            return false;
+        }
+        let sender = transaction.t.sender;
+        let nonce = transaction.t.nonce;
+        let account_nonce = state.accounts.get(&sender).map(|account| account.0).unwrap_or(0);
+        let next_nonce = self.next_nonce(&sender, state);
+        if nonce <= account_nonce {
+            return false;
+        }
+        if nonce < next_nonce {
+            return self.replace_ready(transaction, size, state);
+        }
+        if nonce > next_nonce {
+            return self.insert_pending(transaction, size, next_nonce);
+        }
+        self.insert_ready(transaction, size, state)
+    }
+
+    /// Put a transaction into the ready queue of its sender if it applies after the transactions
+    /// before it
+    fn insert_ready(&mut self, transaction: SignedTransaction, size: usize, state: &State) -> bool {
+        let hash = transaction.hash();
+        let sender = transaction.t.sender;
+        let nonce = transaction.t.nonce;
+        if !transaction.verify(&self.queued_state(&sender, state, nonce)) {
+            return false;
+        }
+        self.by_sender.entry(sender).or_default().insert(nonce, hash);
+        self.add_entry(transaction, size);
+        self.promote(&sender, state);
+        self.evict();
+        self.contains(&hash)
+    }
+
+    /// Put a transaction into the pending queue of its sender, possibly replacing one
+    fn insert_pending(&mut self, transaction: SignedTransaction, size: usize, next_nonce: usize) -> bool {
+        let hash = transaction.hash();
+        let sender = transaction.t.sender;
+        let nonce = transaction.t.nonce;
+        if nonce - next_nonce > MAX_NONCE_GAP {
+            return false;
+        }
+        // the state it applies to is not known yet, but the signature can be checked already
+        if !transaction::verify(&transaction.t, &transaction.pub_key, &transaction.sig) {
+            return false;
+        }
+        if let Some(old) = self.pending.get(&sender).and_then(|queue| queue.get(&nonce)).cloned() {
+            if !self.pays_fee_bump(&self.transactions[&old].transaction, &transaction) {
+                return false;
+            }
+            debug!("Replacing pending transaction {} by {}", old, hash);
+            self.remove(&old);
+        }
+        self.pending.entry(sender).or_default().insert(nonce, hash);
+        self.add_entry(transaction, size);
+        self.evict();
+        self.contains(&hash)
+    }
+
+    /// Replace a ready transaction by one with the same nonce paying the fee bump. The transactions
+    /// after it go back to the pending queue and get promoted again as far as they still apply.
+    fn replace_ready(&mut self, transaction: SignedTransaction, size: usize, state: &State) -> bool {
+        let hash = transaction.hash();
+        let sender = transaction.t.sender;
+        let nonce = transaction.t.nonce;
+        let old = match self.by_sender.get(&sender).and_then(|queue| queue.get(&nonce)) {
+            Some(old) => *old,
+            // the transaction was taken out of the middle of the queue, as the miner does with the
+            // ones it mines, and this one fills the hole
+            None => return self.insert_ready(transaction, size, state),
+        };
+        if !self.pays_fee_bump(&self.transactions[&old].transaction, &transaction) {
+            return false;
+        }
+        if !transaction.verify(&self.queued_state(&sender, state, nonce)) {
+            return false;
+        }
+        debug!("Replacing transaction {} by {}", old, hash);
+        let later: Vec<(usize, H256)> = self.by_sender[&sender].range(nonce + 1..).map(|(nonce, hash)| (*nonce, *hash)).collect();
+        self.remove(&old);
+        let queue = self.by_sender.entry(sender).or_default();
+        for (later_nonce, _) in later.iter() {
+            queue.remove(later_nonce);
+        }
+        queue.insert(nonce, hash);
+        let pending = self.pending.entry(sender).or_default();
+        for (later_nonce, later_hash) in later {
+            pending.insert(later_nonce, later_hash);
+        }
+        self.add_entry(transaction, size);
+        self.promote(&sender, state);
+        self.evict();
+        self.contains(&hash)
+    }
+
+    /// Move pending transactions of `sender` to the ready queue while the next nonce is there and
+    /// applies; one that does not apply is dropped
+    fn promote(&mut self, sender: &Address, state: &State) {
+        loop {
+            let nonce = self.next_nonce(sender, state);
+            let hash = match self.pending.get_mut(sender).and_then(|queue| queue.remove(&nonce)) {
+                Some(hash) => hash,
+                None => break,
+            };
+            if self.pending.get(sender).map(|queue| queue.is_empty()).unwrap_or(false) {
+                self.pending.remove(sender);
+            }
+            if self.transactions[&hash].transaction.verify(&self.queued_state(sender, state, nonce)) {
+                self.by_sender.entry(*sender).or_default().insert(nonce, hash);
+            } else if let Some(entry) = self.transactions.remove(&hash) {
+                debug!("Dropping pending transaction {} which does not apply", hash);
+                self.bytes -= entry.size;
+                break;
+            }
+        }
+    }
+
+    /// Remove a transaction, e.g. because it has been mined
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let entry = self.transactions.remove(hash)?;
+        self.bytes -= entry.size;
+        let sender = entry.transaction.t.sender;
+        let nonce = entry.transaction.t.nonce;
+        for queues in [&mut self.by_sender, &mut self.pending] {
+            if let Some(queue) = queues.get_mut(&sender) {
+                if queue.get(&nonce) == Some(hash) {
+                    queue.remove(&nonce);
+                }
+                if queue.is_empty() {
+                    queues.remove(&sender);
+                }
+            }
+        }
+        Some(entry.transaction)
+    }
+
+    /// Admit a UTXO transaction that applies on top of `state`, the tip state, and spends no output
+    /// a transaction in the pool spends already. Returns false if it is not admitted.
+    pub fn insert_utxo(&mut self, transaction: UtxoTransaction, state: &State) -> bool {
+        let hash = transaction.hash();
+        if self.contains_utxo(&hash) || transaction.inputs.iter().any(|input| self.spends(&input.out_point())) {
+            return false;
+        }
+        let size = bincode::serialized_size(&transaction).unwrap() as usize;
+        if self.len() >= self.max_count || self.bytes + size > self.max_bytes {
+            return false;
+        }
+        let fee = match state.clone().apply_utxo(&transaction) {
+            Some(fee) => fee,
+            None => return false,
+        };
+        for input in transaction.inputs.iter() {
+            self.spent.insert(input.out_point(), hash);
+        }
+        self.bytes += size;
+        self.utxo_transactions.insert(hash, UtxoEntry { transaction, fee, size, added: (self.clock)() });
+        true
+    }
+
+    /// Remove a UTXO transaction, e.g. because it has been mined
+    pub fn remove_utxo(&mut self, hash: &H256) -> Option<UtxoTransaction> {
+        let entry = self.utxo_transactions.remove(hash)?;
+        self.bytes -= entry.size;
+        for input in entry.transaction.inputs.iter() {
+            self.spent.remove(&input.out_point());
+        }
+        Some(entry.transaction)
+    }
+
+    /// Remove the ready transaction of `sender` with `nonce` and every later one, which cannot apply
+    /// without it
+    fn remove_from(&mut self, sender: &Address, nonce: usize) {
+        let hashes: Vec<H256> = match self.by_sender.get(sender) {
+            Some(queue) => queue.range(nonce..).map(|(_, hash)| *hash).collect(),
+            None => return,
+        };
+        for hash in hashes {
+            self.remove(&hash);
+        }
+    }
+
+    /// Evict queue tails, pending ones first and lowest fee rate first, until the pool is within
+    /// its limits
+    fn evict(&mut self) {
+        while self.len() > self.max_count || self.bytes > self.max_bytes {
+            let queues = if self.pending.is_empty() { &self.by_sender } else { &self.pending };
+            let victim = queues
+                .values()
+                .filter_map(|queue| queue.values().next_back())
+                .map(|hash| &self.transactions[hash])
+                .min_by(|a, b| a.cmp_fee_rate(b))
+                .map(|entry| entry.transaction.hash());
+            match victim {
+                Some(hash) => {
+                    debug!("Mempool full, evicting {}", hash);
+                    self.remove(&hash);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drop the transactions added more than the expiry before `now`, together with the later ready
+    /// transactions of their sender
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<(H256, Address, usize)> = self
+            .transactions
+            .iter()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.added) >= self.expiry)
+            .map(|(hash, entry)| (*hash, entry.transaction.t.sender, entry.transaction.t.nonce))
+            .collect();
+        for (hash, sender, nonce) in expired {
+            let ready = self.by_sender.get(&sender).and_then(|queue| queue.get(&nonce)) == Some(&hash);
+            if ready {
+                self.remove_from(&sender, nonce);
+            } else {
+                self.remove(&hash);
+            }
+        }
+        let expired: Vec<H256> = self
+            .utxo_transactions
+            .iter()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.added) >= self.expiry)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove_utxo(&hash);
+        }
+    }
+
+    /// Write every transaction to the mempool file in `data_dir`, replacing it atomically. The
+    /// account transactions are written in nonce order so that loading them fills the ready queues
+    /// first, followed by the UTXO transactions.
+    pub fn save(&self, data_dir: &Path) -> io::Result<()> {
+        let mut transactions: Vec<&SignedTransaction> = self.iter().collect();
+        transactions.sort_by_key(|transaction| transaction.t.nonce);
+        let utxo_transactions: Vec<&UtxoTransaction> =
+            self.utxo_transactions.values().map(|entry| &entry.transaction).collect();
+        let bytes =
+            bincode::serialize(&(MEMPOOL_FILE_VERSION, transactions, utxo_transactions)).unwrap();
+        fs::create_dir_all(data_dir)?;
+        let tmp = data_dir.join(format!("{}.tmp", MEMPOOL_FILE));
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, data_dir.join(MEMPOOL_FILE))
+    }
+
+    /// Admit the transactions saved in `data_dir` on top of `state`, the restored tip state, as if
+    /// they had just arrived. Returns how many were admitted.
+    pub fn load(&mut self, data_dir: &Path, state: &State) -> io::Result<usize> {
+        let bytes = match fs::read(data_dir.join(MEMPOOL_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        type Saved = (u32, Vec<SignedTransaction>, Vec<UtxoTransaction>);
+        let (version, transactions, utxo_transactions): Saved = bincode::deserialize(&bytes)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if version != MEMPOOL_FILE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown mempool file version {}", version),
+            ));
+        }
+        let mut admitted = 0;
+        for transaction in transactions {
+            if self.insert(transaction, state) {
+                admitted += 1;
+            }
+        }
+        for transaction in utxo_transactions {
+            if self.insert_utxo(transaction, state) {
+                admitted += 1;
+            }
+        }
+        Ok(admitted)
+    }
+
+    /// Bring the pool in line with a new tip state: drop expired transactions, transactions whose
+    /// nonce has been used, and from each sender's ready queue the first transaction that no longer
+    /// applies together with the ones after it, then promote what has become ready. Each queue is
+    /// checked on its own, like on insertion, so that it does not count on credits from the queues
+    /// of other senders, which may never be mined. UTXO transactions whose outputs are no longer
+    /// unspent are dropped.
+    pub fn revalidate(&mut self, state: &State) {
+        self.expire((self.clock)());
+        let spent: Vec<H256> = self
+            .utxo_transactions
+            .iter()
+            .filter(|(_, entry)| entry.transaction.inputs.iter().any(|input| !state.utxos.contains_key(&input.out_point())))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in spent {
+            self.remove_utxo(&hash);
+        }
+        let senders: Vec<Address> = self.by_sender.keys().cloned().collect();
+        for sender in senders {
+            let mut queued_state = state.clone();
+            let queue: Vec<(usize, H256)> = self.by_sender[&sender].iter().map(|(nonce, hash)| (*nonce, *hash)).collect();
+            for (nonce, hash) in queue {
+                let used = queued_state.accounts.get(&sender).map(|account| account.0 >= nonce).unwrap_or(false);
+                if used {
+                    self.remove(&hash);
+                } else if !queued_state.apply(&self.transactions[&hash].transaction) {
+                    self.remove_from(&sender, nonce);
+                    break;
+                }
+            }
+        }
+        let senders: Vec<Address> = self.pending.keys().cloned().collect();
+        for sender in senders {
+            let account_nonce = state.accounts.get(&sender).map(|account| account.0).unwrap_or(0);
+            let used: Vec<H256> = self.pending[&sender].range(..=account_nonce).map(|(_, hash)| *hash).collect();
+            for hash in used {
+                self.remove(&hash);
+            }
+            self.promote(&sender, state);
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed(key: &Ed25519KeyPair, nonce: usize, value: usize, fee: usize) -> SignedTransaction {
+        signed_to(key, nonce, Address::random(), value, fee)
+    }
+
+    fn signed_to(key: &Ed25519KeyPair, nonce: usize, receiver: Address, value: usize, fee: usize) -> SignedTransaction {
+        let t = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            nonce,
+            receiver,
+            value,
+            fee,
+        };
+        let sig = sign(&t, key).as_ref().to_vec();
+        SignedTransaction { t, sig, pub_key: key.public_key().as_ref().to_vec() }
+    }
+
+    fn funded(keys: &[&Ed25519KeyPair]) -> State {
+        let mut state = State::new();
+        for key in keys {
+            state.add_account(Address::from_public_key_bytes(key.public_key().as_ref()), 1000);
+        }
+        state
+    }
+
+    #[test]
+    fn admit_in_nonce_order() {
+        let key = key_pair::from_seed([0; 32]);
+        let state = funded(&[&key]);
+        let mut mempool = Mempool::new();
+        assert!(mempool.insert(signed(&key, 1, 10, 1), &state));
+        assert!(!mempool.insert(signed(&key, 1, 10, 1), &state));
+        assert!(mempool.insert(signed(&key, 2, 10, 1), &state));
+        // the queue has to stay affordable
+        assert!(!mempool.insert(signed(&key, 3, 980, 1), &state));
+        assert_eq!(mempool.next_nonce(&signed(&key, 1, 0, 0).t.sender, &state), 3);
+        assert_eq!(mempool.len(), 2);
+        // a nonce already used on chain
+        let mut used = state.clone();
+        assert!(used.apply(&signed(&key, 1, 10, 1)));
+        assert!(!mempool.insert(signed(&key, 1, 20, 5), &used));
+    }
+
+    #[test]
+    fn promote_pending() {
+        let key = key_pair::from_seed([0; 32]);
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = funded(&[&key]);
+        let mut mempool = Mempool::new();
+        let third = signed(&key, 3, 10, 1);
+        let fourth = signed(&key, 4, 10, 1);
+        assert!(mempool.insert(third.clone(), &state));
+        assert!(mempool.insert(fourth.clone(), &state));
+        assert!(!mempool.insert(signed(&key, 5 + MAX_NONCE_GAP, 10, 1), &state));
+        assert_eq!(mempool.pending_len(), 2);
+        assert_eq!(mempool.next_nonce(&sender, &state), 1);
+        // filling the gap from the mempool promotes both
+        let first = signed(&key, 1, 10, 1);
+        assert!(mempool.insert(first.clone(), &state));
+        assert_eq!(mempool.pending_len(), 2);
+        let second = signed(&key, 2, 10, 1);
+        assert!(mempool.insert(second.clone(), &state));
+        assert_eq!(mempool.pending_len(), 0);
+        assert_eq!(mempool.next_nonce(&sender, &state), 5);
+
+        // or filling it on chain
+        let mut mempool = Mempool::new();
+        assert!(mempool.insert(third.clone(), &state));
+        assert!(state.apply(&first));
+        assert!(state.apply(&second));
+        mempool.revalidate(&state);
+        assert_eq!(mempool.pending_len(), 0);
+        assert_eq!(mempool.next_nonce(&sender, &state), 4);
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let key = key_pair::from_seed([0; 32]);
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let state = funded(&[&key]);
+        let mut mempool = Mempool::new();
+        let first = signed(&key, 1, 10, 10);
+        let second = signed(&key, 2, 10, 10);
+        assert!(mempool.insert(first.clone(), &state));
+        assert!(mempool.insert(second.clone(), &state));
+        // 10% more is required
+        assert!(!mempool.insert(signed(&key, 1, 20, 10), &state));
+        let bump = signed(&key, 1, 10, 11);
+        assert!(mempool.insert(bump.clone(), &state));
+        assert!(!mempool.contains(&first.hash()));
+        assert!(mempool.contains(&second.hash()));
+        assert_eq!(mempool.next_nonce(&sender, &state), 3);
+        // a replacement spending more pushes out what no longer applies after it
+        let expensive = signed(&key, 1, 975, 20);
+        assert!(mempool.insert(expensive.clone(), &state));
+        assert!(!mempool.contains(&second.hash()));
+        assert_eq!(mempool.next_nonce(&sender, &state), 2);
+        assert_eq!(mempool.len(), 1);
+
+        // pending transactions can be replaced too, with a configurable bump
+        mempool.set_fee_bump(100);
+        let pending = signed(&key, 5, 1, 10);
+        assert!(mempool.insert(pending.clone(), &state));
+        assert!(!mempool.insert(signed(&key, 5, 1, 19), &state));
+        assert!(mempool.insert(signed(&key, 5, 1, 20), &state));
+        assert!(!mempool.contains(&pending.hash()));
+        assert_eq!(mempool.pending_len(), 1);
+    }
+
+    #[test]
+    fn refill_hole_left_by_miner() {
+        let key = key_pair::from_seed([0; 32]);
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let state = funded(&[&key]);
+        let mut mempool = Mempool::new();
+        let second = signed(&key, 2, 10, 1);
+        for transaction in [signed(&key, 1, 10, 1), second.clone(), signed(&key, 3, 10, 1)] {
+            assert!(mempool.insert(transaction, &state));
+        }
+        // the miner takes out what it mined before the block reaches the chain
+        assert!(mempool.remove(&second.hash()).is_some());
+        // and a peer sends it again
+        assert!(mempool.insert(second.clone(), &state));
+        assert!(mempool.contains(&second.hash()));
+        assert_eq!(mempool.next_nonce(&sender, &state), 4);
+        // a replacement has to pay the fee bump again
+        assert!(!mempool.insert(signed(&key, 2, 20, 1), &state));
+    }
+
+    #[test]
+    fn revalidate_senders_apart() {
+        let key = key_pair::from_seed([0; 32]);
+        let other_key = key_pair::from_seed([1; 32]);
+        let other = Address::from_public_key_bytes(other_key.public_key().as_ref());
+        let state = funded(&[&key, &other_key]);
+        let mut mempool = Mempool::new();
+        let credit = signed_to(&key, 1, other, 500, 1);
+        let spend = signed(&other_key, 1, 900, 1);
+        assert!(mempool.insert(credit.clone(), &state));
+        assert!(mempool.insert(spend.clone(), &state));
+        // a reorg takes away the funds of the other sender, who now only has the unconfirmed credit
+        let reorged = funded(&[&key]);
+        mempool.revalidate(&reorged);
+        assert!(mempool.contains(&credit.hash()));
+        assert!(!mempool.contains(&spend.hash()));
+    }
+
+    #[test]
+    fn utxo_transactions() {
+        use crate::types::utxo::{Input, Output};
+        let key = key_pair::from_seed([0; 32]);
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let funding: H256 = [1; 32].into();
+        let mut state = State::new();
+        state.add_output((funding, 0), 100, owner);
+        let spend = |value: u64| {
+            let mut transaction = UtxoTransaction {
+                inputs: vec![Input::new(funding, 0)],
+                outputs: vec![Output { value, recipient: Address::random() }],
+            };
+            transaction.sign_input(0, &key);
+            transaction
+        };
+        let mut mempool = Mempool::new();
+        let first = spend(90);
+        assert!(mempool.insert_utxo(first.clone(), &state));
+        assert!(mempool.spends(&(funding, 0)));
+        assert_eq!(mempool.utxo_iter().next().map(|(_, fee)| fee), Some(10));
+        // the output is taken, and a transaction cannot spend more than it has
+        assert!(!mempool.insert_utxo(spend(80), &state));
+        assert!(!mempool.insert_utxo(first.clone(), &state));
+        let mut unfunded = State::new();
+        unfunded.add_output(([2; 32].into(), 0), 100, owner);
+        assert!(!Mempool::new().insert_utxo(spend(101), &state));
+        assert!(!Mempool::new().insert_utxo(spend(90), &unfunded));
+        // once the output is spent on chain the transaction goes
+        state.utxos.remove(&(funding, 0));
+        mempool.revalidate(&state);
+        assert!(mempool.is_empty());
+        assert!(!mempool.spends(&(funding, 0)));
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn evict_lowest_fee_rate() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|i| key_pair::from_seed([i; 32])).collect();
+        let state = funded(&keys.iter().collect::<Vec<_>>());
+        let mut mempool = Mempool::with_limits(2, DEFAULT_MAX_BYTES, DEFAULT_EXPIRY);
+        let cheap = signed(&keys[0], 1, 10, 1);
+        let middle = signed(&keys[1], 1, 10, 5);
+        assert!(mempool.insert(cheap.clone(), &state));
+        assert!(mempool.insert(middle.clone(), &state));
+        assert!(mempool.insert(signed(&keys[2], 1, 10, 9), &state));
+        assert!(!mempool.contains(&cheap.hash()));
+        assert_eq!(mempool.len(), 2);
+        // a transaction paying less than everything in a full pool is turned away
+        assert!(!mempool.insert(signed(&keys[0], 1, 10, 0), &state));
+        assert!(mempool.contains(&middle.hash()));
+    }
+
+    #[test]
+    fn byte_limit() {
+        let keys: Vec<Ed25519KeyPair> = (0..2).map(|i| key_pair::from_seed([i; 32])).collect();
+        let state = funded(&keys.iter().collect::<Vec<_>>());
+        let first = signed(&keys[0], 1, 10, 1);
+        let size = bincode::serialized_size(&first).unwrap() as usize;
+        let mut mempool = Mempool::with_limits(DEFAULT_MAX_COUNT, size, DEFAULT_EXPIRY);
+        assert!(mempool.insert(first.clone(), &state));
+        assert!(mempool.insert(signed(&keys[1], 1, 10, 2), &state));
+        assert!(!mempool.contains(&first.hash()));
+        assert_eq!(mempool.bytes(), size);
+    }
+
+    #[test]
+    fn revalidate_and_expire() {
+        let key = key_pair::from_seed([0; 32]);
+        let other_key = key_pair::from_seed([1; 32]);
+        let mut state = funded(&[&key, &other_key]);
+        let mut mempool = Mempool::new();
+        let first = signed(&key, 1, 10, 1);
+        let second = signed(&key, 2, 10, 1);
+        let third = signed(&key, 3, 10, 1);
+        let other = signed(&other_key, 1, 10, 1);
+        for transaction in [first.clone(), second.clone(), third.clone(), other.clone()] {
+            assert!(mempool.insert(transaction, &state));
+        }
+        // the first one got mined
+        assert!(state.apply(&first));
+        // and the other sender used its nonce for a different transaction
+        assert!(state.apply(&signed(&other_key, 1, 980, 1)));
+        mempool.revalidate(&state);
+        assert!(!mempool.contains(&first.hash()));
+        assert!(mempool.contains(&second.hash()));
+        assert!(!mempool.contains(&other.hash()));
+        // expiring the second one takes the third one with it
+        mempool.expire(Instant::now() + DEFAULT_EXPIRY);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+    }
+
+    #[test]
+    fn save_and_load() {
+        use crate::types::utxo::{Input, Output};
+        let key = key_pair::from_seed([0; 32]);
+        let mut state = funded(&[&key]);
+        let mut mempool = Mempool::new();
+        let first = signed(&key, 1, 10, 1);
+        let second = signed(&key, 2, 10, 1);
+        let pending = signed(&key, 4, 10, 1);
+        for transaction in [first.clone(), second.clone(), pending.clone()] {
+            assert!(mempool.insert(transaction, &state));
+        }
+        let funding: H256 = [1; 32].into();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        state.add_output((funding, 0), 100, owner);
+        let mut spend = UtxoTransaction {
+            inputs: vec![Input::new(funding, 0)],
+            outputs: vec![Output { value: 90, recipient: Address::random() }],
+        };
+        spend.sign_input(0, &key);
+        assert!(mempool.insert_utxo(spend.clone(), &state));
+        let data_dir = std::env::temp_dir().join(format!("bitcoin-test-{}", rand::random::<u64>()));
+        mempool.save(&data_dir).unwrap();
+
+        // the first one got mined while the node was down
+        assert!(state.apply(&first));
+        let mut restored = Mempool::new();
+        assert_eq!(restored.load(&data_dir, &state).unwrap(), 3);
+        assert!(!restored.contains(&first.hash()));
+        assert!(restored.contains(&second.hash()));
+        assert_eq!(restored.pending_len(), 1);
+        assert!(restored.spends(&(funding, 0)));
+        let restored_spend = restored.utxo_iter().next().map(|(transaction, _)| transaction.hash());
+        assert_eq!(restored_spend, Some(spend.hash()));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+        // nothing saved yet
+        assert_eq!(Mempool::new().load(&data_dir, &state).unwrap(), 0);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -2,19 +2,26 @@ pub mod worker;
 
 use log::info;
 
-use crate::blockchain::Blockchain;
+use crate::blockchain::{is_retarget_height, total_fees, Blockchain, LedgerModel, State};
+use crate::mempool::Mempool;
+use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
 use crate::types::merkle::MerkleTree;
 use crate::types::transaction::SignedTransaction;
+use crate::types::utxo::UtxoTransaction;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use rand::{thread_rng, Rng};
 use std::sync::{Arc, Mutex};
-use std::collections::{HashSet, HashMap};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
 use std::thread;
 use std::time;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Number of transactions a mined block carries besides its coinbase
+pub const BLOCK_SIZE_LIMIT: usize = 3;
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
     Update,     // update the block in mining, it may due to new blockchain tip or new transaction
@@ -30,10 +37,12 @@ enum OperatingState {
 pub struct Context {
     /// Channel for receiving control signal
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
+    /// Address the coinbase of mined blocks pays to
+    address: Address,
 }
 
 #[derive(Clone)]
@@ -42,7 +51,7 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, address: Address) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
     let blockchain = Arc::clone(blockchain);
@@ -53,6 +62,7 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
+        address: address,
     };
 
     let handle = Handle {
@@ -65,7 +75,148 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256
 #[cfg(any(test, test_utilities))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let blockchain = Blockchain::new();
-    new(&Arc::new(Mutex::new(blockchain)))
+    new(&Arc::new(Mutex::new(blockchain)), &Arc::new(Mutex::new(Mempool::new())), Address::random())
+}
+
+/// A transaction competing for a place in the block template, ordered by fee rate
+struct Candidate {
+    transaction: SignedTransaction,
+    hash: H256,
+    size: u64,
+}
+
+impl Candidate {
+    fn new(transaction: &SignedTransaction) -> Self {
+        Candidate {
+            transaction: transaction.clone(),
+            hash: transaction.hash(),
+            size: bincode::serialized_size(transaction).unwrap(),
+        }
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // compare fee / size without dividing, the smaller hash wins a tie
+        let lhs = self.transaction.t.fee as u128 * other.size as u128;
+        let rhs = other.transaction.t.fee as u128 * self.size as u128;
+        lhs.cmp(&rhs).then_with(|| other.hash.cmp(&self.hash))
+    }
+}
+
+/// Pick up to `limit` transactions from the mempool that apply in order on top of `state`, highest
+/// fee rate first, taking each sender's transactions in nonce order
+pub fn block_template(state: &State, mempool: &Mempool, limit: usize) -> Vec<SignedTransaction> {
+    let mut by_sender: HashMap<Address, BTreeMap<usize, &SignedTransaction>> = HashMap::new();
+    for transaction in mempool.iter() {
+        let queue = by_sender.entry(transaction.t.sender).or_default();
+        // of two transactions with the same nonce, keep the one paying more
+        match queue.get(&transaction.t.nonce) {
+            Some(other) if other.t.fee >= transaction.t.fee => {}
+            _ => {
+                queue.insert(transaction.t.nonce, transaction);
+            }
+        }
+    }
+    let mut state = state.clone();
+    let mut heap = BinaryHeap::new();
+    for (sender, queue) in by_sender.iter() {
+        let next_nonce = state.accounts.get(sender).map(|account| account.0 + 1).unwrap_or(1);
+        if let Some(transaction) = queue.get(&next_nonce) {
+            heap.push(Candidate::new(transaction));
+        }
+    }
+    let mut template = vec![];
+    while let Some(candidate) = heap.pop() {
+        if template.len() == limit {
+            break;
+        }
+        if !state.apply(&candidate.transaction) {
+            continue;
+        }
+        let sender = candidate.transaction.t.sender;
+        let next_nonce = candidate.transaction.t.nonce + 1;
+        if let Some(transaction) = by_sender[&sender].get(&next_nonce) {
+            heap.push(Candidate::new(transaction));
+        }
+        template.push(candidate.transaction);
+    }
+    template
+}
+
+/// Pick up to `limit` UTXO transactions from the mempool that apply on top of `state`, highest fee
+/// rate first, returning them with the sum of their fees
+pub fn utxo_template(state: &State, mempool: &Mempool, limit: usize) -> (Vec<UtxoTransaction>, u64) {
+    let mut candidates: Vec<(&UtxoTransaction, u64, u64, H256)> = mempool
+        .utxo_iter()
+        .map(|(transaction, fee)| (transaction, fee, bincode::serialized_size(transaction).unwrap(), transaction.hash()))
+        .collect();
+    // compare fee / size without dividing, the smaller hash wins a tie
+    candidates.sort_by(|(_, fee, size, hash), (_, other_fee, other_size, other_hash)| {
+        let lhs = *fee as u128 * *other_size as u128;
+        let rhs = *other_fee as u128 * *size as u128;
+        rhs.cmp(&lhs).then_with(|| hash.cmp(other_hash))
+    });
+    let mut state = state.clone();
+    let mut template = vec![];
+    let mut fees: u64 = 0;
+    for (transaction, _, _, _) in candidates {
+        if template.len() == limit {
+            break;
+        }
+        if let Some(fee) = state.apply_utxo(transaction) {
+            fees = fees.saturating_add(fee);
+            template.push(transaction.clone());
+        }
+    }
+    (template, fees)
+}
+
+/// The transactions of a block at `height` on top of `parent` that pays `address`: the coinbase
+/// first, then what fits from the mempool, and the UTXO transactions that fit on a chain of the
+/// UTXO ledger model
+pub fn block_content(
+    blockchain: &Blockchain,
+    mempool: &Mempool,
+    parent: &H256,
+    height: usize,
+    address: Address,
+) -> (Vec<SignedTransaction>, Vec<UtxoTransaction>) {
+    // transactions have to apply on top of the parent, which is unknown while our own last block
+    // waits to be inserted, so that block only gets a coinbase
+    let (transactions, utxo_transactions, fees) = match blockchain.block_state.get(parent) {
+        Some(state) => match blockchain.ledger_model() {
+            LedgerModel::Account => {
+                let transactions = block_template(state, mempool, BLOCK_SIZE_LIMIT);
+                let fees = total_fees(&transactions);
+                (transactions, vec![], fees)
+            }
+            LedgerModel::Utxo => {
+                let (utxo_transactions, fees) = utxo_template(state, mempool, BLOCK_SIZE_LIMIT);
+                (vec![], utxo_transactions, fees as usize)
+            }
+        },
+        None => (vec![], vec![], 0),
+    };
+    // the coinbase comes first and pays the subsidy and the fees to our address
+    let value = blockchain.block_subsidy(height).saturating_add(fees);
+    let mut content = vec![SignedTransaction::coinbase(address, value, height)];
+    content.extend(transactions);
+    (content, utxo_transactions)
 }
 
 impl Handle {
@@ -102,6 +253,7 @@ impl Context {
         let mut data: Vec<H256>;
         let mut content: Vec<SignedTransaction>;
         let mut parent: H256 = self.blockchain.lock().unwrap().tip();
+        let mut last_tip: H256 = parent;
         let mut height: usize = self.blockchain.lock().unwrap().get_tip_height();
         let mut merkle_root: H256;
         let mut difficulty: H256 = self
@@ -164,25 +316,27 @@ impl Context {
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
                 .as_millis();
-            let mut content: Vec<SignedTransaction> = vec![];
-            let mut data = vec![];
-            let mut tx_tracker = HashSet::new();
             let mut b = self.blockchain.lock().unwrap();
             let mut m = self.mempool.lock().unwrap();
-            for (hash, transaction) in m.iter(){
-                if content.len() == 3 {
-                    break;
-                }
-                let mut pub_key = &transaction.pub_key;
-                let mut state = b.get_tip_state();
-                if transaction.verify(&state) {
-                    if !tx_tracker.contains(&pub_key.clone()) {
-                        tx_tracker.insert(pub_key.clone());
-                        content.push(transaction.clone());
-                        data.push(hash.clone());
-                    }
-                }
+            // switch to the tip when a block from elsewhere has become the tip
+            if b.tip() != last_tip {
+                last_tip = b.tip();
+                parent = last_tip;
+                height = b.get_tip_height();
             }
+            // the difficulty follows the retarget rules of the parent. Our own last block may still
+            // be on its way into the blockchain, which only matters when the difficulty changes.
+            if b.contains(&parent) {
+                difficulty = b.next_difficulty(&parent);
+            } else if is_retarget_height(height + 1) {
+                drop(b);
+                drop(m);
+                thread::sleep(time::Duration::from_millis(1));
+                continue;
+            }
+            let (content, utxo_content) = block_content(&b, &m, &parent, height + 1, self.address);
+            let mut data: Vec<H256> = content.iter().map(|transaction| transaction.hash()).collect();
+            data.extend(utxo_content.iter().map(|transaction| transaction.hash()));
             drop(b);
             drop(m);
             merkle_root = MerkleTree::new(&data).root();
@@ -190,14 +344,18 @@ impl Context {
             let nonce: u32 = rng.gen();
 
             let block: Block =
-                Block::new(parent, nonce, timestamp, difficulty, merkle_root, content.clone());
-            if block.hash() <= difficulty && !data.is_empty(){
+                Block::new_with_utxo(parent, nonce, timestamp, difficulty, merkle_root, content.clone(), utxo_content.clone());
+            if block.hash() <= difficulty {
                 self.finished_block_chan.send(block.clone()).unwrap(); // this will handle placing it into the blockchain
                 for tx_hash in data{
-                    self.mempool.lock().unwrap().remove(&tx_hash);
+                    let mut m = self.mempool.lock().unwrap();
+                    if m.remove(&tx_hash).is_none() {
+                        m.remove_utxo(&tx_hash);
+                    }
                     // println!("Size of mempool after removal: {:?}", m.keys().len());
                 }
                 parent = block.hash();
+                height += 1;
             }
 
             // println!("Size of mempool: {:?}", m.keys().len());
@@ -218,6 +376,69 @@ impl Context {
 mod test {
     use crate::types::hash::Hashable;
     use ntest::timeout;
+    use crate::blockchain::State;
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use crate::mempool::Mempool;
+
+    fn signed(key: &Ed25519KeyPair, nonce: usize, fee: usize) -> SignedTransaction {
+        let t = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            nonce: nonce,
+            receiver: Address::random(),
+            value: 10,
+            fee: fee,
+        };
+        let sig = sign(&t, key).as_ref().to_vec();
+        SignedTransaction { t: t, sig: sig, pub_key: key.public_key().as_ref().to_vec() }
+    }
+
+    #[test]
+    fn template_by_fee_rate_in_nonce_order() {
+        let key_a = key_pair::from_seed([0; 32]);
+        let key_b = key_pair::from_seed([1; 32]);
+        let mut state = State::new();
+        state.add_account(Address::from_public_key_bytes(key_a.public_key().as_ref()), 1000);
+        state.add_account(Address::from_public_key_bytes(key_b.public_key().as_ref()), 1000);
+        let a_1 = signed(&key_a, 1, 1);
+        let a_2 = signed(&key_a, 2, 100);
+        let b_1 = signed(&key_b, 1, 50);
+        // a nonce gap is never filled
+        let b_3 = signed(&key_b, 3, 1000);
+        let mut mempool = Mempool::new();
+        for transaction in vec![a_1.clone(), a_2.clone(), b_1.clone(), b_3.clone()] {
+            mempool.insert(transaction, &state);
+        }
+        let template: Vec<_> = super::block_template(&state, &mempool, 10).iter().map(|t| t.hash()).collect();
+        assert_eq!(template, vec![b_1.hash(), a_1.hash(), a_2.hash()]);
+        let template: Vec<_> = super::block_template(&state, &mempool, 1).iter().map(|t| t.hash()).collect();
+        assert_eq!(template, vec![b_1.hash()]);
+    }
+
+    #[test]
+    fn utxo_block_content() {
+        use crate::blockchain::{Blockchain, LedgerModel};
+        use crate::types::utxo::{Input, Output, UtxoTransaction};
+        let blockchain = Blockchain::with_model(LedgerModel::Utxo);
+        let genesis = blockchain.tip();
+        let key = key_pair::from_seed([0; 32]);
+        // the ico output pays 10000, the fee is what the outputs leave
+        let mut transaction = UtxoTransaction {
+            inputs: vec![Input::new(genesis, 0)],
+            outputs: vec![Output { value: 9990, recipient: Address::random() }],
+        };
+        transaction.sign_input(0, &key);
+        let mut mempool = Mempool::new();
+        assert!(mempool.insert_utxo(transaction.clone(), &blockchain.block_state[&genesis]));
+        let miner = Address::random();
+        let (content, utxo_content) = super::block_content(&blockchain, &mempool, &genesis, 1, miner);
+        assert_eq!(content.len(), 1);
+        assert_eq!(content[0].t.value, blockchain.block_subsidy(1) + 10);
+        assert_eq!(utxo_content.len(), 1);
+        assert_eq!(utxo_content[0].hash(), transaction.hash());
+    }
 
     #[test]
     #[timeout(60000)]
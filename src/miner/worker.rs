@@ -1,27 +1,31 @@
-use crate::blockchain::{State, Blockchain};
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::network::compact::CompactBlock;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::block::{Block, Content, Header};
-use crate::types::hash::{Hashable, H256};
-use crate::types::transaction::SignedTransaction;
-use std::collections::HashMap;
+use crate::types::hash::Hashable;
 use super::super::network::message::Message;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use log::{debug, info, warn};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 #[derive(Clone)]
 pub struct Worker {
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     server: ServerHandle,
     finished_block_chan: Receiver<Block>,
+    /// draws the salts of the compact blocks relayed
+    rng: Arc<Mutex<StdRng>>,
 }
 
 impl Worker {
     pub fn new(
         blockchain: &Arc<Mutex<Blockchain>>,
-        mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+        mempool: &Arc<Mutex<Mempool>>,
         server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
     ) -> Self {
@@ -31,27 +35,15 @@ impl Worker {
             blockchain: blockchain,
             mempool: mempool,
             server: server.clone(),
-            finished_block_chan
+            finished_block_chan,
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
         }
     }
-    pub fn validate_mempool(&self, mempool: &HashMap<H256, SignedTransaction>, curr_state: State) -> Vec<H256>{
-        let accounts = curr_state.get_accounts();
-        let mut tx_delete = vec![];
-        for (hash, transaction) in mempool.iter() {
-            let tx_sender = transaction.t.sender;
-            let tx_sender_nonce = transaction.t.nonce;
-            let tx_value = transaction.t.value;
-            if accounts.contains_key(&tx_sender){
-                let (state_account_nonce, state_account_bal) = accounts.get(&tx_sender).unwrap();
-                if state_account_nonce >= &tx_sender_nonce || state_account_bal < &tx_value {
-                    tx_delete.push(hash.clone());
-                }
-            }
-        }
-        tx_delete        
-    }
-
 
+    /// Draw the salts of relayed compact blocks from `seed` rather than from entropy
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+    }
     pub fn start(self) {
         thread::Builder::new()
             .name("miner-worker".to_string())
@@ -68,27 +60,42 @@ impl Worker {
                 .finished_block_chan
                 .recv()
                 .expect("Receive finished block error");
+            self.process_block(_block);
+        }
+    }
 
-            // update the state and the chain here
-            let mut b = self.blockchain.lock().unwrap();
-            let mut m = self.mempool.lock().unwrap();
-            if b.contains(&_block.get_parent()){
-                if b.verify_block(&_block) && b.block_state.contains_key(&_block.get_parent()){
-                    b.update_state(&_block);
-                    b.insert(&_block);
-                    println!("Tip State: {:?}", b.get_tip_state());
-                    let curr_state = b.get_tip_state();
-                    // need to validate the mempool and update the state
-                    let tx_delete = self.validate_mempool(&m, curr_state);
-                    for tx_hash in tx_delete {
-                        m.remove(&tx_hash);
+    /// Insert a block the miner found and relay it
+    pub fn process_block(&self, _block: Block) {
+        // update the state and the chain here
+        let mut b = self.blockchain.lock().unwrap();
+        let mut m = self.mempool.lock().unwrap();
+        if b.contains(&_block.get_parent()){
+            let verified = b.verify_block(&_block);
+            if let Err(e) = &verified {
+                warn!("Mined block {} is invalid: {:?}", _block.hash(), e);
+            }
+            if verified.is_ok() && b.block_state.contains_key(&_block.get_parent()){
+                b.update_state(&_block);
+                let reorg = b.insert(&_block);
+                println!("Tip State: {:?}", b.get_tip_state());
+                let curr_state = b.get_tip_state();
+                m.revalidate(&curr_state);
+                // return transactions of the abandoned fork to the mempool
+                if let Some(reorg) = reorg {
+                    for transaction in b.reorg_transactions(&reorg) {
+                        m.insert(transaction, &curr_state);
+                    }
+                    for transaction in b.reorg_utxo_transactions(&reorg) {
+                        m.insert_utxo(transaction, &curr_state);
                     }
-               
-                    self.server.broadcast(Message::NewBlockHashes(vec![_block.hash()]));
                 }
+           
+                // pushed whole but for the transactions peers have in their mempool already
+                let nonce = self.rng.lock().unwrap().gen();
+                self.server.broadcast(Message::CompactBlock(CompactBlock::new(&_block, nonce)));
             }
-            drop(b);
-            drop(m);
         }
+        drop(b);
+        drop(m);
     }
 }
@@ -0,0 +1,123 @@
+use super::message::PeerAddr;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Most addresses kept, the ones seen longest ago are forgotten first
+pub const MAX_ADDRESSES: usize = 1000;
+/// Most addresses sent in or accepted from one `Addr` message
+pub const MAX_ADDR_PER_MESSAGE: usize = 1000;
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// The listening addresses of nodes we have heard of, with when they were last seen
+pub struct AddressBook {
+    entries: HashMap<SocketAddr, u64>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddressBook {
+    pub fn new() -> Self {
+        AddressBook {
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.entries.contains_key(addr)
+    }
+
+    /// Record that `addr` was seen at `last_seen`, keeping the most recent time. Addresses that
+    /// cannot be dialed are ignored, and times in the future are taken as now.
+    pub fn add(&mut self, addr: SocketAddr, last_seen: u64) {
+        if addr.ip().is_unspecified() || addr.port() == 0 {
+            return;
+        }
+        let last_seen = last_seen.min(now());
+        let entry = self.entries.entry(addr).or_insert(last_seen);
+        *entry = (*entry).max(last_seen);
+        if self.entries.len() > MAX_ADDRESSES {
+            if let Some(oldest) = self.entries.iter().min_by_key(|(_, last_seen)| **last_seen).map(|(addr, _)| *addr) {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.entries.remove(addr);
+    }
+
+    /// Up to `count` addresses to send to a peer, most recently seen first
+    pub fn sample(&self, count: usize) -> Vec<PeerAddr> {
+        let mut addrs: Vec<PeerAddr> = self
+            .entries
+            .iter()
+            .map(|(addr, last_seen)| PeerAddr { addr: *addr, last_seen: *last_seen })
+            .collect();
+        addrs.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        addrs.truncate(count);
+        addrs
+    }
+
+    /// Up to `count` addresses to dial, in random order, leaving out the ones in `exclude`
+    pub fn candidates(&self, count: usize, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut addrs: Vec<SocketAddr> = self.entries.keys().filter(|addr| !exclude.contains(addr)).cloned().collect();
+        addrs.shuffle(&mut rand::thread_rng());
+        addrs.truncate(count);
+        addrs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn keep_most_recent() {
+        let mut book = AddressBook::new();
+        book.add(addr(6000), 10);
+        book.add(addr(6000), 5);
+        book.add(addr(6001), 20);
+        book.add("0.0.0.0:6002".parse().unwrap(), 30);
+        book.add(addr(6003), u64::MAX);
+        assert_eq!(book.len(), 3);
+        let sample = book.sample(2);
+        assert_eq!(sample[0].addr, addr(6003));
+        assert!(sample[0].last_seen <= now());
+        assert_eq!((sample[1].addr, sample[1].last_seen), (addr(6001), 20));
+        let exclude: HashSet<SocketAddr> = [addr(6001), addr(6003)].iter().cloned().collect();
+        assert_eq!(book.candidates(10, &exclude), vec![addr(6000)]);
+    }
+
+    #[test]
+    fn forget_oldest() {
+        let mut book = AddressBook::new();
+        for i in 0..=MAX_ADDRESSES {
+            book.add(addr(10000 + i as u16), 100 + i as u64);
+        }
+        assert_eq!(book.len(), MAX_ADDRESSES);
+        assert!(!book.contains(&addr(10000)));
+        assert!(book.contains(&addr(10001)));
+    }
+}
@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Misbehavior score at which a peer is disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// How long a ban lasts unless configured otherwise
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Something a peer did wrong, scored by how unlikely it is to happen to an honest node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// a message that does not decode
+    MalformedMessage,
+    /// more entries in a message than the protocol allows
+    OversizedMessage,
+    /// a block or header whose hash does not meet its own difficulty target, which costs nothing
+    /// to make
+    InvalidProofOfWork,
+    /// a block or header that breaks a consensus rule
+    InvalidBlock,
+    /// a transaction whose signature does not verify
+    InvalidTransaction,
+}
+
+impl Misbehavior {
+    /// How much the misbehavior adds to the score of the peer
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::MalformedMessage => 20,
+            Misbehavior::OversizedMessage => 20,
+            Misbehavior::InvalidProofOfWork => 100,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::InvalidTransaction => 10,
+        }
+    }
+}
+
+/// What a ban is keyed on: the identity key a peer proved it holds on an encrypted link, or else
+/// the IP address it was seen connecting from. Never what the peer says about itself, such as the
+/// port it claims to listen at, which it can change at will.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Banned {
+    Ip(IpAddr),
+    Identity([u8; 32]),
+}
+
+impl Banned {
+    /// What to ban of a peer seen connecting from `ip`, with identity key `public_key` if the link
+    /// is encrypted. Nodes on one machine share an IP address, so only the key tells them apart.
+    pub fn of(ip: IpAddr, public_key: Option<[u8; 32]>) -> Self {
+        match public_key {
+            Some(key) => Banned::Identity(key),
+            None => Banned::Ip(ip),
+        }
+    }
+}
+
+/// A banned IP address or hex encoded identity key and when the ban ends, in seconds since the
+/// unix epoch
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip: Option<IpAddr>,
+    pub public_key: Option<String>,
+    pub until: u64,
+}
+
+/// Peers not to accept connections from or dial until their ban ends
+pub struct BanList {
+    bans: HashMap<Banned, u64>,
+    duration: Duration,
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList {
+            bans: HashMap::new(),
+            duration: DEFAULT_BAN_DURATION,
+        }
+    }
+
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    /// Ban `banned` from `now`, returning when the ban ends
+    pub fn ban(&mut self, banned: Banned, now: u64) -> u64 {
+        let until = now.saturating_add(self.duration.as_secs());
+        let entry = self.bans.entry(banned).or_insert(until);
+        *entry = (*entry).max(until);
+        *entry
+    }
+
+    pub fn unban(&mut self, banned: &Banned) -> bool {
+        self.bans.remove(banned).is_some()
+    }
+
+    /// Whether `banned` is banned at `now`
+    pub fn is_banned(&self, banned: &Banned, now: u64) -> bool {
+        self.bans.get(banned).is_some_and(|until| *until > now)
+    }
+
+    /// Whether a peer seen connecting from `ip`, with identity key `public_key` if the link is
+    /// encrypted, is banned at `now` by either
+    pub fn is_peer_banned(&self, ip: IpAddr, public_key: Option<[u8; 32]>, now: u64) -> bool {
+        self.is_banned(&Banned::Ip(ip), now) || public_key.is_some_and(|key| self.is_banned(&Banned::Identity(key), now))
+    }
+
+    /// The bans that have not ended at `now`, forgetting the ones that have
+    pub fn list(&mut self, now: u64) -> Vec<Ban> {
+        self.bans.retain(|_, until| *until > now);
+        let mut bans: Vec<Ban> = self
+            .bans
+            .iter()
+            .map(|(banned, until)| match banned {
+                Banned::Ip(ip) => Ban { ip: Some(*ip), public_key: None, until: *until },
+                Banned::Identity(key) => Ban { ip: None, public_key: Some(hex::encode(key)), until: *until },
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ban_and_expire() {
+        let mut bans = BanList::new();
+        bans.set_duration(Duration::from_secs(60));
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let remote: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(bans.ban(Banned::of(local, Some([1; 32])), 100), 160);
+        bans.ban(Banned::of(remote, None), 120);
+        assert!(bans.is_peer_banned(local, Some([1; 32]), 159));
+        assert!(!bans.is_peer_banned(local, Some([1; 32]), 160));
+        // other nodes on this machine are not affected, whatever port they connect from
+        assert!(!bans.is_peer_banned(local, Some([2; 32]), 100));
+        assert!(!bans.is_peer_banned(local, None, 100));
+        // a node banned by IP address stays banned with any key
+        assert!(bans.is_peer_banned(remote, Some([2; 32]), 150));
+        assert_eq!(
+            bans.list(100),
+            vec![
+                Ban { ip: None, public_key: Some(hex::encode([1; 32])), until: 160 },
+                Ban { ip: Some(remote), public_key: None, until: 180 },
+            ]
+        );
+        assert_eq!(bans.list(170).len(), 1);
+        assert!(bans.unban(&Banned::Ip(remote)));
+        assert!(bans.list(0).is_empty());
+    }
+}
@@ -0,0 +1,330 @@
+use crate::blockchain::MAX_BLOCK_TRANSACTIONS;
+use crate::mempool::Mempool;
+use crate::types::block::{Block, Header};
+use crate::types::clock::{self, Clock};
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::transaction::SignedTransaction;
+use crate::types::utxo::UtxoTransaction;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Bytes of a short transaction id
+pub const SHORT_ID_LEN: usize = 6;
+/// Most compact blocks kept waiting for their missing transactions
+pub const MAX_PARTIAL_BLOCKS: usize = 16;
+/// How long a compact block waits for its missing transactions before it is dropped
+pub const PARTIAL_BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type ShortId = [u8; SHORT_ID_LEN];
+
+/// A block as relayed to peers that likely have its transactions in their mempool already: the
+/// header, and the transactions as short ids except the ones the peer cannot have, which are sent
+/// in full
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    /// salt of the short ids, so that transactions whose ids collide in one block do not in the next
+    pub nonce: u64,
+    pub short_ids: Vec<ShortId>,
+    /// transactions sent in full, the coinbase among them, with their index in the block
+    pub prefilled: Vec<(u32, SignedTransaction)>,
+    /// transactions of the UTXO ledger model, which the mempool does not keep
+    pub utxo_content: Vec<UtxoTransaction>,
+}
+
+/// The first bytes of the hash of a transaction salted with the block and the nonce
+fn short_id(block: &H256, nonce: u64, transaction: &H256) -> ShortId {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(block.as_ref());
+    ctx.update(&nonce.to_le_bytes());
+    ctx.update(transaction.as_ref());
+    ctx.finish().as_ref()[..SHORT_ID_LEN].try_into().unwrap()
+}
+
+impl CompactBlock {
+    /// The compact form of `block`, whose short ids are salted with `nonce`
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let hash = block.hash();
+        let mut short_ids = vec![];
+        let mut prefilled = vec![];
+        for (index, transaction) in block.get_content().into_iter().enumerate() {
+            if transaction.is_coinbase() {
+                prefilled.push((index as u32, transaction));
+            } else {
+                short_ids.push(short_id(&hash, nonce, &transaction.hash()));
+            }
+        }
+        CompactBlock {
+            header: block.header(),
+            nonce,
+            short_ids,
+            prefilled,
+            utxo_content: block.get_utxo_content(),
+        }
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+}
+
+/// A compact block whose transactions are being filled in
+pub struct PartialBlock {
+    header: Header,
+    transactions: Vec<Option<SignedTransaction>>,
+    utxo_content: Vec<UtxoTransaction>,
+}
+
+impl PartialBlock {
+    /// Fill the transactions of `compact` in from the prefilled ones and the mempool. Returns
+    /// `None` if the compact block is malformed: too many transactions, or prefilled indexes out
+    /// of range or repeated.
+    pub fn new(compact: CompactBlock, mempool: &Mempool) -> Option<Self> {
+        let hash = compact.hash();
+        let len = compact.short_ids.len() + compact.prefilled.len();
+        if len > MAX_BLOCK_TRANSACTIONS {
+            return None;
+        }
+        let mut transactions: Vec<Option<SignedTransaction>> = vec![None; len];
+        let mut prefilled: HashSet<usize> = HashSet::new();
+        for (index, transaction) in compact.prefilled {
+            let index = index as usize;
+            if index >= len || !prefilled.insert(index) {
+                return None;
+            }
+            transactions[index] = Some(transaction);
+        }
+        // a short id matching several transactions of the mempool is treated as missing
+        let mut by_short_id: HashMap<ShortId, Option<&SignedTransaction>> = HashMap::new();
+        for transaction in mempool.iter() {
+            by_short_id
+                .entry(short_id(&hash, compact.nonce, &transaction.hash()))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(transaction));
+        }
+        let slots = (0..len).filter(|index| !prefilled.contains(index));
+        for (index, id) in slots.zip(compact.short_ids.iter()) {
+            transactions[index] = by_short_id.get(id).cloned().flatten().cloned();
+        }
+        Some(PartialBlock {
+            header: compact.header,
+            transactions,
+            utxo_content: compact.utxo_content,
+        })
+    }
+
+    pub fn hash(&self) -> H256 {
+        self.header.hash()
+    }
+
+    /// Indexes of the transactions that are still missing
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.transactions.len())
+            .filter(|index| self.transactions[*index].is_none())
+            .map(|index| index as u32)
+            .collect()
+    }
+
+    /// Fill in the missing transactions, given in the order of `missing`. Returns false if they
+    /// are not as many as the missing ones.
+    pub fn fill(&mut self, transactions: Vec<SignedTransaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != transactions.len() {
+            return false;
+        }
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            self.transactions[index as usize] = Some(transaction);
+        }
+        true
+    }
+
+    /// The block, once no transaction is missing and they match the merkle root of the header. A
+    /// mismatch means a short id matched the wrong transaction of the mempool, or the peer sent
+    /// wrong ones.
+    pub fn into_block(self) -> Option<Block> {
+        let content: Vec<SignedTransaction> = self.transactions.into_iter().collect::<Option<_>>()?;
+        let mut hashes: Vec<H256> = content.iter().map(|transaction| transaction.hash()).collect();
+        hashes.extend(self.utxo_content.iter().map(|transaction| transaction.hash()));
+        if MerkleTree::new(&hashes).root() != self.header.get_merkle_root() {
+            return None;
+        }
+        Some(Block::from_parts(self.header, content, self.utxo_content))
+    }
+}
+
+/// Compact blocks waiting for the transactions asked from the peer that sent them
+pub struct PartialBlocks {
+    /// each block with the peer it came from, when it came and its insertion order, to tell apart
+    /// blocks that came at the same instant
+    blocks: HashMap<H256, (PartialBlock, SocketAddr, Instant, u64)>,
+    next_sequence: u64,
+    clock: Clock,
+}
+
+impl Default for PartialBlocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialBlocks {
+    pub fn new() -> Self {
+        PartialBlocks {
+            blocks: HashMap::new(),
+            next_sequence: 0,
+            clock: clock::system(),
+        }
+    }
+
+    /// Set the clock the blocks are timed by
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Keep a block until `peer` sends its missing transactions, dropping the ones that timed out
+    /// and, if there are too many, the oldest
+    pub fn insert(&mut self, block: PartialBlock, peer: SocketAddr) {
+        let now = (self.clock)();
+        self.blocks.retain(|_, (_, _, received, _)| now.saturating_duration_since(*received) <= PARTIAL_BLOCK_TIMEOUT);
+        if self.blocks.len() >= MAX_PARTIAL_BLOCKS {
+            let oldest = self.blocks.iter().min_by_key(|(_, (_, _, _, sequence))| *sequence).map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.blocks.remove(&oldest);
+            }
+        }
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.blocks.insert(block.hash(), (block, peer, now, sequence));
+    }
+
+    /// Take the block `hash` if it waits for transactions from `peer`
+    pub fn remove(&mut self, hash: &H256, peer: &SocketAddr) -> Option<PartialBlock> {
+        match self.blocks.get(hash) {
+            Some((_, from, _, _)) if from == peer => self.blocks.remove(hash).map(|(block, _, _, _)| block),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::blockchain::{Blockchain, State};
+    use crate::types::address::Address;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::sync::{Arc, Mutex};
+
+    fn transaction(key: &Ed25519KeyPair, nonce: usize) -> SignedTransaction {
+        let t = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            nonce,
+            receiver: Address::random(),
+            value: 1,
+            fee: 1,
+        };
+        let sig = sign(&t, key).as_ref().to_vec();
+        SignedTransaction { t, sig, pub_key: key.public_key().as_ref().to_vec() }
+    }
+
+    fn funded(key: &Ed25519KeyPair) -> State {
+        let mut state = State::new();
+        state.add_account(Address::from_public_key_bytes(key.public_key().as_ref()), 1000);
+        state
+    }
+
+    fn block(content: Vec<SignedTransaction>) -> Block {
+        let hashes: Vec<H256> = content.iter().map(|transaction| transaction.hash()).collect();
+        let root = MerkleTree::new(&hashes).root();
+        Block::new(Blockchain::new().tip(), 0, 0, [255; 32].into(), root, content)
+    }
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let key = key_pair::from_seed([0; 32]);
+        let coinbase = SignedTransaction::coinbase(Address::random(), 50, 1);
+        let transactions: Vec<SignedTransaction> = (1..=3).map(|nonce| transaction(&key, nonce)).collect();
+        let original = block(vec![coinbase, transactions[0].clone(), transactions[1].clone(), transactions[2].clone()]);
+        let compact = CompactBlock::new(&original, rand::random());
+        assert_eq!(compact.short_ids.len(), 3);
+        assert_eq!(compact.prefilled.len(), 1);
+
+        // the mempool has the first two transactions
+        let mut mempool = Mempool::new();
+        let state = funded(&key);
+        assert!(mempool.insert(transactions[0].clone(), &state));
+        assert!(mempool.insert(transactions[1].clone(), &state));
+        let mut partial = PartialBlock::new(compact.clone(), &mempool).unwrap();
+        assert_eq!(partial.missing(), vec![3]);
+        assert!(!partial.fill(vec![]));
+        assert!(partial.fill(vec![transactions[2].clone()]));
+        let block = partial.into_block().unwrap();
+        assert_eq!(block.hash(), original.hash());
+        assert_eq!(block.transaction_hashes(), original.transaction_hashes());
+
+        // wrong transactions do not match the merkle root
+        let mut partial = PartialBlock::new(compact.clone(), &Mempool::new()).unwrap();
+        assert_eq!(partial.missing(), vec![1, 2, 3]);
+        partial.fill(vec![transactions[0].clone(), transactions[2].clone(), transactions[1].clone()]);
+        assert!(partial.into_block().is_none());
+
+        let mut malformed = compact;
+        malformed.prefilled[0].0 = 4;
+        assert!(PartialBlock::new(malformed, &mempool).is_none());
+    }
+
+    #[test]
+    fn wait_for_the_sender() {
+        let mut partials = PartialBlocks::new();
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut hashes = vec![];
+        for i in 0..=MAX_PARTIAL_BLOCKS {
+            let coinbase = SignedTransaction::coinbase(Address::random(), 50, i);
+            let partial = PartialBlock::new(CompactBlock::new(&block(vec![coinbase]), rand::random()), &Mempool::new()).unwrap();
+            hashes.push(partial.hash());
+            partials.insert(partial, peer);
+        }
+        assert_eq!(partials.len(), MAX_PARTIAL_BLOCKS);
+        // the oldest made room
+        assert!(partials.remove(&hashes[0], &peer).is_none());
+        assert!(partials.remove(&hashes[1], &other).is_none());
+        assert!(partials.remove(&hashes[1], &peer).is_some());
+    }
+
+    #[test]
+    fn time_out_on_the_clock() {
+        let start = Instant::now();
+        let now = Arc::new(Mutex::new(start));
+        let clock_now = Arc::clone(&now);
+        let mut partials = PartialBlocks::new();
+        partials.set_clock(Arc::new(move || *clock_now.lock().unwrap()));
+        let peer: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let partial = |i| {
+            let coinbase = SignedTransaction::coinbase(Address::random(), 50, i);
+            PartialBlock::new(CompactBlock::new(&block(vec![coinbase]), rand::random()), &Mempool::new()).unwrap()
+        };
+        let (first, second) = (partial(0), partial(1));
+        let (first_hash, second_hash) = (first.hash(), second.hash());
+        partials.insert(first, peer);
+        *now.lock().unwrap() = start + PARTIAL_BLOCK_TIMEOUT + Duration::from_secs(1);
+        partials.insert(second, peer);
+        assert_eq!(partials.len(), 1);
+        assert!(partials.remove(&first_hash, &peer).is_none());
+        assert!(partials.remove(&second_hash, &peer).is_some());
+    }
+}
@@ -0,0 +1,210 @@
+use super::address_book::MAX_ADDR_PER_MESSAGE;
+use crate::blockchain::{MAX_BLOCK_SIZE, MAX_BLOCK_TRANSACTIONS};
+use super::message::{MAX_HEADERS_PER_MESSAGE, MAX_INV_PER_MESSAGE, MAX_LOCATOR_SIZE};
+use std::convert::TryInto;
+use std::fmt;
+
+/// First bytes of every frame, telling our network's frames apart from anything else
+pub const MAGIC: [u8; 4] = [0xb1, 0x7c, 0x01, 0x75];
+/// Magic, payload length and checksum
+pub const HEADER_SIZE: usize = 12;
+/// Largest payload of any frame, so that a peer cannot make us allocate more
+pub const MAX_FRAME_SIZE: usize = 32 * 1024 * 1024;
+
+/// Why a frame was refused, which ends the connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    BadMagic([u8; 4]),
+    /// the payload is longer than `MAX_FRAME_SIZE`
+    TooLarge(usize),
+    /// the frame is not as long as its header says
+    BadLength,
+    BadChecksum,
+    /// the payload does not start with the tag of a known message type
+    UnknownMessage,
+    /// the payload is longer than messages of its type can be
+    MessageTooLarge { tag: u32, size: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::BadMagic(magic) => write!(f, "bad magic {:02x?}", magic),
+            FrameError::TooLarge(size) => write!(f, "frame of {} bytes is too large", size),
+            FrameError::BadLength => write!(f, "frame length does not match its header"),
+            FrameError::BadChecksum => write!(f, "checksum mismatch"),
+            FrameError::UnknownMessage => write!(f, "unknown message type"),
+            FrameError::MessageTooLarge { tag, size } => write!(f, "message of type {} and {} bytes is too large", tag, size),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = ring::digest::digest(&ring::digest::SHA256, payload);
+    digest.as_ref()[..4].try_into().unwrap()
+}
+
+/// Largest payload of each message type. bincode starts a `Message` with the index of its variant,
+/// so a payload that is too large for its type is refused before it is deserialized.
+fn max_message_size(tag: u32) -> Option<usize> {
+    // the tag, and the length of a list or string
+    let prefix = 4 + 8;
+    let size = match tag {
+        // Ping, Pong
+        0 | 1 => prefix + 256,
+        // NewBlockHashes, GetBlocks, NewTransactionHashes, GetTransactions
+        2 | 3 | 5 | 6 => prefix + MAX_INV_PER_MESSAGE * 32,
+        // Blocks, Transactions
+        4 | 7 => MAX_FRAME_SIZE,
+        // Version
+        8 => 1024,
+        // VerAck, GetAddr
+        9 | 10 => 4,
+        // Addr, each one an address of up to 26 bytes and a time
+        11 => prefix + MAX_ADDR_PER_MESSAGE * 40,
+        // GetHeaders
+        12 => prefix + MAX_LOCATOR_SIZE * 32,
+        // Headers, each one hashes, a nonce, a difficulty and a timestamp
+        13 => prefix + MAX_HEADERS_PER_MESSAGE * 128,
+        // CompactBlock, BlockTxn, no larger than a block with its hash or nonce
+        14 | 16 => 4 + 64 + MAX_BLOCK_SIZE,
+        // GetBlockTxn, a hash and an index of every transaction
+        15 => prefix + 32 + MAX_BLOCK_TRANSACTIONS * 4,
+        // NewUtxoTransactionHashes, GetUtxoTransactions
+        17 | 18 => prefix + MAX_INV_PER_MESSAGE * 32,
+        // UtxoTransactions
+        19 => MAX_FRAME_SIZE,
+        _ => return None,
+    };
+    Some(size)
+}
+
+/// Put a serialized message into a frame
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(payload));
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read a frame header, returning the length of the payload that follows and its checksum
+pub fn decode_header(header: &[u8; HEADER_SIZE]) -> Result<(usize, [u8; 4]), FrameError> {
+    let magic: [u8; 4] = header[0..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(FrameError::BadMagic(magic));
+    }
+    let size = u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(size));
+    }
+    Ok((size, header[8..12].try_into().unwrap()))
+}
+
+/// The tag of the message in a payload, the index of its variant
+pub fn tag(payload: &[u8]) -> Option<u32> {
+    payload.get(0..4).map(|tag| u32::from_le_bytes(tag.try_into().unwrap()))
+}
+
+/// Check a payload against the checksum from its header and the size limit of its message type
+pub fn check_payload(payload: &[u8], expected: &[u8; 4]) -> Result<(), FrameError> {
+    if checksum(payload) != *expected {
+        return Err(FrameError::BadChecksum);
+    }
+    let tag = tag(payload).ok_or(FrameError::UnknownMessage)?;
+    let max = max_message_size(tag).ok_or(FrameError::UnknownMessage)?;
+    if payload.len() > max {
+        return Err(FrameError::MessageTooLarge { tag, size: payload.len() });
+    }
+    Ok(())
+}
+
+/// Check a whole frame, header and payload, returning the payload
+pub fn decode(frame: &[u8]) -> Result<&[u8], FrameError> {
+    if frame.len() < HEADER_SIZE {
+        return Err(FrameError::BadLength);
+    }
+    let (size, checksum) = decode_header(frame[..HEADER_SIZE].try_into().unwrap())?;
+    if frame.len() - HEADER_SIZE != size {
+        return Err(FrameError::BadLength);
+    }
+    let payload = &frame[HEADER_SIZE..];
+    check_payload(payload, &checksum)?;
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::compact::CompactBlock;
+    use crate::network::message::{Message, PeerAddr, Version};
+    use crate::types::block::generate_random_block;
+    use crate::types::hash::H256;
+    use crate::types::transaction::SignedTransaction;
+    use crate::types::utxo::UtxoTransaction;
+    use crate::types::address::Address;
+
+    #[test]
+    fn limits_fit_every_message() {
+        let hash: H256 = [1; 32].into();
+        let block = generate_random_block(&hash);
+        let addr = PeerAddr { addr: "[2001:db8::1]:6000".parse().unwrap(), last_seen: u64::MAX };
+        let messages = vec![
+            Message::Ping("ping".to_string()),
+            Message::Pong("pong".to_string()),
+            Message::NewBlockHashes(vec![hash; MAX_INV_PER_MESSAGE]),
+            Message::GetBlocks(vec![hash; MAX_INV_PER_MESSAGE]),
+            Message::Blocks(vec![block.clone()]),
+            Message::NewTransactionHashes(vec![hash; MAX_INV_PER_MESSAGE]),
+            Message::GetTransactions(vec![hash; MAX_INV_PER_MESSAGE]),
+            Message::Transactions(vec![SignedTransaction::coinbase(Address::random(), 50, 1)]),
+            Message::Version(Version::new(hash, usize::MAX, "[2001:db8::1]:6000".parse().unwrap())),
+            Message::VerAck,
+            Message::GetAddr,
+            Message::Addr(vec![addr; MAX_ADDR_PER_MESSAGE]),
+            Message::GetHeaders(vec![hash; MAX_LOCATOR_SIZE]),
+            Message::Headers(vec![block.header(); MAX_HEADERS_PER_MESSAGE]),
+            Message::CompactBlock(CompactBlock::new(&block, rand::random())),
+            Message::GetBlockTxn(hash, vec![u32::MAX; MAX_BLOCK_TRANSACTIONS]),
+            Message::BlockTxn(hash, vec![SignedTransaction::coinbase(Address::random(), 50, 1)]),
+            Message::NewUtxoTransactionHashes(vec![hash; MAX_INV_PER_MESSAGE]),
+            Message::GetUtxoTransactions(vec![hash; MAX_INV_PER_MESSAGE]),
+            Message::UtxoTransactions(vec![UtxoTransaction::default()]),
+        ];
+        // the tags follow the order of the variants, which the limits rely on
+        for (tag, msg) in messages.iter().enumerate() {
+            let payload = bincode::serialize(msg).unwrap();
+            assert_eq!(u32::from_le_bytes(payload[0..4].try_into().unwrap()), tag as u32);
+            assert_eq!(decode(&encode(&payload)), Ok(&payload[..]));
+        }
+        assert_eq!(max_message_size(messages.len() as u32), None);
+    }
+
+    #[test]
+    fn refuse_bad_frames() {
+        let payload = bincode::serialize(&Message::Ping("ping".to_string())).unwrap();
+        let frame = encode(&payload);
+
+        let mut bad_magic = frame.clone();
+        bad_magic[0] ^= 1;
+        assert!(matches!(decode(&bad_magic), Err(FrameError::BadMagic(_))));
+
+        let mut too_large = frame.clone();
+        too_large[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(decode(&too_large), Err(FrameError::TooLarge(u32::MAX as usize)));
+
+        assert_eq!(decode(&frame[..frame.len() - 1]), Err(FrameError::BadLength));
+
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&corrupted), Err(FrameError::BadChecksum));
+
+        let long_ping = bincode::serialize(&Message::Ping("p".repeat(1000))).unwrap();
+        assert!(matches!(decode(&encode(&long_ping)), Err(FrameError::MessageTooLarge { tag: 0, .. })));
+
+        assert_eq!(decode(&encode(&[99, 0, 0, 0])), Err(FrameError::UnknownMessage));
+    }
+}
@@ -0,0 +1,72 @@
+use crate::types::hash::H256;
+use std::collections::{HashSet, VecDeque};
+
+/// Most hashes remembered per peer, the oldest are forgotten first
+pub const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// The blocks and transactions a peer is known to have, because it announced or sent them to us or
+/// we announced or sent them to it. Nothing known to a peer is announced to it again.
+pub struct KnownInventory {
+    hashes: HashSet<H256>,
+    order: VecDeque<H256>,
+}
+
+impl Default for KnownInventory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KnownInventory {
+    pub fn new() -> Self {
+        KnownInventory {
+            hashes: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Remember a hash, returning whether it was new
+    pub fn insert(&mut self, hash: H256) -> bool {
+        if !self.hashes.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > MAX_KNOWN_INVENTORY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Remember the hashes, returning the ones that were new
+    pub fn filter_new(&mut self, hashes: &[H256]) -> Vec<H256> {
+        hashes.iter().filter(|hash| self.insert(**hash)).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn filter_and_forget() {
+        let mut known = KnownInventory::new();
+        let a: H256 = [1; 32].into();
+        let b: H256 = [2; 32].into();
+        assert!(known.insert(a));
+        assert_eq!(known.filter_new(&[a, b, b]), vec![b]);
+        assert!(known.filter_new(&[a, b]).is_empty());
+        for i in 0..MAX_KNOWN_INVENTORY {
+            let mut bytes = [0u8; 32];
+            bytes[..8].copy_from_slice(&(i as u64).to_be_bytes());
+            known.insert(bytes.into());
+        }
+        assert!(!known.contains(&a));
+        assert!(!known.contains(&b));
+    }
+}
@@ -0,0 +1,233 @@
+use super::ban::{Misbehavior, BAN_THRESHOLD};
+use super::frame;
+use super::inventory::KnownInventory;
+use super::message::Message;
+use super::peer;
+use super::server::{announcement, PeerInfo, VersionSource};
+use super::shaping::{Shape, Shaper, ShapingRule, ShapingRules};
+use crate::types::hash::H256;
+use futures::channel::mpsc;
+use log::{trace, warn};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// A node's link to one of its peers
+struct Link {
+    handle: peer::Handle,
+    /// Messages written to the handle, not yet put on the link
+    outbox: mpsc::UnboundedReceiver<Vec<u8>>,
+    direction: peer::Direction,
+    /// When the link was made, in seconds of the virtual clock
+    connected_at: u64,
+    known: KnownInventory,
+    misbehavior: u32,
+    shaper: Shaper,
+}
+
+struct Node {
+    version: VersionSource,
+    peers: BTreeMap<SocketAddr, Link>,
+    shaping: ShapingRules,
+}
+
+/// Nodes of one process linked without sockets, for tests. Time only passes when the network is
+/// told to move its virtual clock, and then messages arrive in the order the shape of their link
+/// decides, with the randomness of jitter and drops drawn from a seed, so that a run can be
+/// repeated exactly.
+///
+/// The nodes are reached through `server::Handle::in_memory`. Links are made by hand, so addresses
+/// are not gossiped, and there is no handshake but the exchange of `Version` messages.
+pub struct Network {
+    start: Instant,
+    now: Instant,
+    rng: StdRng,
+    nodes: BTreeMap<SocketAddr, Node>,
+    /// Messages on their way by when and in which order they arrive, with their sender and receiver
+    in_flight: BTreeMap<(Instant, u64), (SocketAddr, SocketAddr, Vec<u8>)>,
+    next_sequence: u64,
+}
+
+impl Network {
+    pub fn new(seed: u64) -> Self {
+        let now = Instant::now();
+        Network {
+            start: now,
+            now,
+            rng: StdRng::seed_from_u64(seed),
+            nodes: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// The virtual clock
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Time passed on the virtual clock since the network was made
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.start
+    }
+
+    pub fn add_node(&mut self, addr: SocketAddr, version: VersionSource) {
+        let node = Node {
+            version,
+            peers: BTreeMap::new(),
+            shaping: ShapingRules::new(),
+        };
+        self.nodes.insert(addr, node);
+    }
+
+    /// Link `from` to `to`, and have each tell the other its version
+    pub fn connect(&mut self, from: SocketAddr, to: SocketAddr) -> io::Result<peer::Handle> {
+        if from == to || !self.nodes.contains_key(&from) || !self.nodes.contains_key(&to) {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("no node at {}", to)));
+        }
+        if self.nodes[&from].peers.contains_key(&to) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("already connected to {}", to)));
+        }
+        let connected_at = self.elapsed().as_secs();
+        for (node, peer, direction) in [(from, to, peer::Direction::Outgoing), (to, from, peer::Direction::Incoming)] {
+            let (outbox, handle) = peer::new_in_memory(peer);
+            let link = Link {
+                handle,
+                outbox,
+                direction,
+                connected_at,
+                known: KnownInventory::new(),
+                misbehavior: 0,
+                shaper: Shaper::new(self.now),
+            };
+            let node = self.nodes.get_mut(&node).unwrap();
+            let mut handle = link.handle.clone();
+            node.peers.insert(peer, link);
+            handle.write(Message::Version((node.version)()));
+        }
+        Ok(self.nodes[&from].peers[&to].handle.clone())
+    }
+
+    /// Cut the link between `a` and `b`, losing the messages on their way
+    pub fn disconnect(&mut self, a: SocketAddr, b: SocketAddr) -> bool {
+        let cut = self.nodes.get_mut(&a).and_then(|node| node.peers.remove(&b)).is_some();
+        let cut_back = self.nodes.get_mut(&b).and_then(|node| node.peers.remove(&a)).is_some();
+        cut || cut_back
+    }
+
+    /// When the next message arrives, if one is on its way
+    pub fn next_arrival(&mut self) -> Option<Instant> {
+        self.flush();
+        self.in_flight.keys().next().map(|(at, _)| *at)
+    }
+
+    /// Move the clock to the arrival of the next message and take it, returning the node it is
+    /// for, the message, and the handle the node replies to the sender with
+    pub fn deliver(&mut self) -> Option<(SocketAddr, Vec<u8>, peer::Handle)> {
+        self.flush();
+        while let Some(((at, _), (from, to, msg))) = self.in_flight.pop_first() {
+            self.now = at;
+            // the link may have been cut since
+            if let Some(link) = self.nodes.get(&to).and_then(|node| node.peers.get(&from)) {
+                return Some((to, msg, link.handle.clone()));
+            }
+        }
+        None
+    }
+
+    /// Move the clock forward to `to`, without delivering the messages that arrive before
+    pub fn advance(&mut self, to: Instant) {
+        self.flush();
+        self.now = self.now.max(to);
+    }
+
+    /// Put the messages written to peer handles on their link, which happens at the time they
+    /// were written since the clock only moves once they are
+    fn flush(&mut self) {
+        for (addr, node) in self.nodes.iter_mut() {
+            for (peer, link) in node.peers.iter_mut() {
+                while let Ok(Some(msg)) = link.outbox.try_next() {
+                    let shape = node.shaping.get(peer);
+                    match link.shaper.schedule(&shape, frame::HEADER_SIZE + msg.len(), self.now, &mut self.rng) {
+                        Some(at) => {
+                            self.in_flight.insert((at, self.next_sequence), (*addr, *peer, msg));
+                            self.next_sequence += 1;
+                        }
+                        None => trace!("Dropping a message from {} to {}", addr, peer),
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn broadcast(&mut self, from: &SocketAddr, msg: Message) {
+        if let Some(node) = self.nodes.get_mut(from) {
+            for (_, link) in node.peers.iter_mut() {
+                if let Some(msg) = announcement(&mut link.known, &msg) {
+                    link.handle.write(msg);
+                }
+            }
+        }
+    }
+
+    pub(super) fn send(&mut self, from: &SocketAddr, to: &SocketAddr, msg: Message) {
+        if let Some(link) = self.nodes.get_mut(from).and_then(|node| node.peers.get_mut(to)) {
+            link.handle.write(msg);
+        }
+    }
+
+    pub(super) fn mark_known(&mut self, node: &SocketAddr, peer: &SocketAddr, hashes: Vec<H256>) {
+        if let Some(link) = self.nodes.get_mut(node).and_then(|node| node.peers.get_mut(peer)) {
+            for hash in hashes {
+                link.known.insert(hash);
+            }
+        }
+    }
+
+    pub(super) fn peers(&self, node: &SocketAddr) -> Vec<PeerInfo> {
+        let peers = match self.nodes.get(node) {
+            Some(node) => &node.peers,
+            None => return vec![],
+        };
+        peers
+            .iter()
+            .map(|(addr, link)| PeerInfo {
+                addr: *addr,
+                listen_addr: *addr,
+                direction: link.direction,
+                connected_at: link.connected_at,
+                misbehavior: link.misbehavior,
+                public_key: None,
+                rtt_us: None,
+                min_rtt_us: None,
+            })
+            .collect()
+    }
+
+    /// Add to the misbehavior score of a peer, and cut the link to it once the score reaches
+    /// `BAN_THRESHOLD`
+    pub(super) fn misbehaving(&mut self, node: &SocketAddr, peer: &SocketAddr, misbehavior: Misbehavior) {
+        let link = match self.nodes.get_mut(node).and_then(|node| node.peers.get_mut(peer)) {
+            Some(link) => link,
+            None => return,
+        };
+        link.misbehavior = link.misbehavior.saturating_add(misbehavior.score());
+        if link.misbehavior >= BAN_THRESHOLD {
+            warn!("{} cuts the link to {} for misbehaving: {:?}", node, peer, misbehavior);
+            self.disconnect(*node, *peer);
+        }
+    }
+
+    pub(super) fn shaping(&self, node: &SocketAddr) -> Vec<ShapingRule> {
+        self.nodes.get(node).map(|node| node.shaping.list()).unwrap_or_default()
+    }
+
+    pub(super) fn set_shaping(&mut self, node: &SocketAddr, peer: Option<SocketAddr>, shape: Option<Shape>) {
+        if let Some(node) = self.nodes.get_mut(node) {
+            node.shaping.update(peer, shape);
+        }
+    }
+}
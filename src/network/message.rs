@@ -1,6 +1,49 @@
 use serde::{Serialize, Deserialize};
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use super::compact::CompactBlock;
+use crate::types::{hash::H256, block::{Block, Header}, transaction::SignedTransaction, utxo::UtxoTransaction};
+
+/// Version of the peer protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Oldest protocol version this build can talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+pub const USER_AGENT: &str = concat!("/bitcoin:", env!("CARGO_PKG_VERSION"), "/");
+/// Most headers sent in one `Headers` message, a full message means the peer has more
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+/// Most hashes in one announcement or request of blocks or transactions
+pub const MAX_INV_PER_MESSAGE: usize = 50_000;
+/// Most hashes in a block locator, which grows with the logarithm of the chain height
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+/// What a node tells a peer about itself when they connect
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub protocol_version: u32,
+    pub genesis: H256,
+    pub best_height: usize,
+    pub user_agent: String,
+    /// The address the node accepts connections at
+    pub listen_addr: std::net::SocketAddr,
+}
+
+impl Version {
+    pub fn new(genesis: H256, best_height: usize, listen_addr: std::net::SocketAddr) -> Self {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            genesis,
+            best_height,
+            user_agent: USER_AGENT.to_string(),
+            listen_addr,
+        }
+    }
+}
+
+/// A listening address of a node and when it was last seen, in seconds since the unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr {
+    pub addr: std::net::SocketAddr,
+    pub last_seen: u64,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -12,4 +55,26 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// The first message on a connection, in both directions
+    Version(Version),
+    /// Accepts the `Version` of the peer
+    VerAck,
+    /// Asks for addresses of other nodes
+    GetAddr,
+    Addr(Vec<PeerAddr>),
+    /// Asks for the headers of the longest chain after the first hash of a block locator the peer
+    /// knows, see `Blockchain::locator`
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// A new block with its transactions as short ids, pushed to peers instead of announced
+    CompactBlock(CompactBlock),
+    /// Asks for the transactions at these indexes of a block, missing from its compact block
+    GetBlockTxn(H256, Vec<u32>),
+    /// The transactions asked for with `GetBlockTxn`, in the order they were asked for
+    BlockTxn(H256, Vec<SignedTransaction>),
+    /// Like `NewTransactionHashes`, `GetTransactions` and `Transactions`, for the transactions of
+    /// the UTXO ledger model
+    NewUtxoTransactionHashes(Vec<H256>),
+    GetUtxoTransactions(Vec<H256>),
+    UtxoTransactions(Vec<UtxoTransaction>),
 }
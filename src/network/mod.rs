@@ -1,4 +1,15 @@
+pub mod address_book;
+pub mod ban;
+pub mod compact;
+pub mod frame;
+pub mod inventory;
+#[cfg(any(test,test_utilities))]
+pub mod memory;
 pub mod message;
+pub mod orphan;
 pub mod peer;
+pub mod secure;
 pub mod server;
+pub mod shaping;
+pub mod sync;
 pub mod worker;
@@ -0,0 +1,239 @@
+use crate::types::block::Block;
+use crate::types::clock::{self, Clock};
+use crate::types::hash::{Hashable, H256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most orphan blocks kept, the oldest are dropped first
+pub const MAX_ORPHANS: usize = 750;
+/// Most orphan blocks kept from a single peer, so one peer cannot fill the pool
+pub const MAX_ORPHANS_PER_PEER: usize = 100;
+/// How long an orphan block waits for its parent before it is dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan {
+    block: Block,
+    peer: SocketAddr,
+    added: Instant,
+    /// insertion order, to tell apart orphans added at the same instant
+    sequence: u64,
+}
+
+/// Blocks whose parent is not in the blockchain yet, kept until the parent arrives so they can be
+/// connected then
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    /// orphan hashes by the hash of their parent
+    children: HashMap<H256, Vec<H256>>,
+    /// number of orphans received from each peer
+    per_peer: HashMap<SocketAddr, usize>,
+    next_sequence: u64,
+    clock: Clock,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            children: HashMap::new(),
+            per_peer: HashMap::new(),
+            next_sequence: 0,
+            clock: clock::system(),
+        }
+    }
+
+    /// Set the clock orphans are timed by for their expiry
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Number of orphans kept that were received from `peer`
+    pub fn count_from(&self, peer: &SocketAddr) -> usize {
+        self.per_peer.get(peer).cloned().unwrap_or(0)
+    }
+
+    /// Keep a block received from `peer` until its parent arrives, returning false if it is
+    /// already kept. Expired orphans are dropped first, and if the peer or the pool is at its
+    /// limit the oldest orphan of the peer or the pool makes room.
+    pub fn insert(&mut self, block: Block, peer: SocketAddr) -> bool {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return false;
+        }
+        let now = (self.clock)();
+        self.expire(now);
+        if self.count_from(&peer) >= MAX_ORPHANS_PER_PEER {
+            if let Some(oldest) = self.oldest(|orphan| orphan.peer == peer) {
+                self.remove(&oldest);
+            }
+        }
+        if self.orphans.len() >= MAX_ORPHANS {
+            if let Some(oldest) = self.oldest(|_| true) {
+                self.remove(&oldest);
+            }
+        }
+        self.children.entry(block.get_parent()).or_default().push(hash);
+        *self.per_peer.entry(peer).or_insert(0) += 1;
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.orphans.insert(hash, Orphan { block, peer, added: now, sequence });
+        true
+    }
+
+    /// Remove an orphan, returning it with the peer it was received from
+    pub fn remove(&mut self, hash: &H256) -> Option<(Block, SocketAddr)> {
+        let orphan = self.orphans.remove(hash)?;
+        let parent = orphan.block.get_parent();
+        if let Some(siblings) = self.children.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+        if let Some(count) = self.per_peer.get_mut(&orphan.peer) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_peer.remove(&orphan.peer);
+            }
+        }
+        Some((orphan.block, orphan.peer))
+    }
+
+    /// Remove and return the orphans whose parent is `parent` with the peers they were received
+    /// from, oldest first
+    pub fn take_children(&mut self, parent: &H256) -> Vec<(Block, SocketAddr)> {
+        let hashes = self.children.get(parent).cloned().unwrap_or_default();
+        hashes.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    /// Drop every orphan descending from `hash`, used when `hash` turned out to be invalid.
+    /// Returns the number of orphans dropped.
+    pub fn remove_descendants(&mut self, hash: &H256) -> usize {
+        let mut dropped = 0;
+        let mut stack = vec![*hash];
+        while let Some(parent) = stack.pop() {
+            for (child, _) in self.take_children(&parent) {
+                stack.push(child.hash());
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    /// The first ancestor of `hash` that is not an orphan, which is the block to ask for when
+    /// `hash` is missing
+    pub fn root(&self, hash: &H256) -> H256 {
+        let mut root = *hash;
+        while let Some(orphan) = self.orphans.get(&root) {
+            root = orphan.block.get_parent();
+        }
+        root
+    }
+
+    /// Drop the orphans that have waited longer than `ORPHAN_EXPIRY` at `now`
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<H256> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| now.saturating_duration_since(orphan.added) > ORPHAN_EXPIRY)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            self.remove(&hash);
+        }
+    }
+
+    fn oldest<F: Fn(&Orphan) -> bool>(&self, filter: F) -> Option<H256> {
+        self.orphans
+            .iter()
+            .filter(|(_, orphan)| filter(orphan))
+            .min_by_key(|(_, orphan)| orphan.sequence)
+            .map(|(hash, _)| *hash)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::block::generate_random_block;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    /// A chain of `len` random blocks on top of `parent`
+    fn chain(parent: &H256, len: usize) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        let mut parent = *parent;
+        for _ in 0..len {
+            let block = generate_random_block(&parent);
+            parent = block.hash();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn connect_descendants() {
+        let mut pool = OrphanPool::new();
+        let missing: H256 = [1; 32].into();
+        let blocks = chain(&missing, 3);
+        let sibling = generate_random_block(&blocks[0].hash());
+        for block in blocks.iter().rev().chain(std::iter::once(&sibling)) {
+            assert!(pool.insert(block.clone(), addr(6000)));
+        }
+        assert!(!pool.insert(blocks[2].clone(), addr(6001)));
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pool.root(&blocks[2].hash()), missing);
+
+        let first: Vec<(H256, SocketAddr)> = pool.take_children(&missing).iter().map(|(b, peer)| (b.hash(), *peer)).collect();
+        assert_eq!(first, vec![(blocks[0].hash(), addr(6000))]);
+        let mut children: Vec<H256> = pool.take_children(&blocks[0].hash()).iter().map(|(b, _)| b.hash()).collect();
+        children.sort();
+        let mut expected = vec![blocks[1].hash(), sibling.hash()];
+        expected.sort();
+        assert_eq!(children, expected);
+        assert_eq!(pool.remove_descendants(&blocks[1].hash()), 1);
+        assert!(pool.is_empty());
+        assert_eq!(pool.count_from(&addr(6000)), 0);
+    }
+
+    #[test]
+    fn limit_per_peer_and_expire() {
+        let mut pool = OrphanPool::new();
+        let flood = chain(&[1; 32].into(), MAX_ORPHANS_PER_PEER + 1);
+        for block in flood.iter() {
+            pool.insert(block.clone(), addr(6000));
+        }
+        assert_eq!(pool.count_from(&addr(6000)), MAX_ORPHANS_PER_PEER);
+        assert!(!pool.contains(&flood[0].hash()));
+        assert!(pool.contains(&flood[MAX_ORPHANS_PER_PEER].hash()));
+
+        let other = generate_random_block(&[2; 32].into());
+        pool.insert(other.clone(), addr(6001));
+        assert_eq!(pool.len(), MAX_ORPHANS_PER_PEER + 1);
+
+        pool.expire(Instant::now() + ORPHAN_EXPIRY + Duration::from_secs(1));
+        assert!(pool.is_empty());
+        assert_eq!(pool.count_from(&addr(6001)), 0);
+    }
+}
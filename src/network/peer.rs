@@ -1,7 +1,9 @@
 use super::message::Message;
 use futures::{channel::mpsc, sink::SinkExt};
 use log::trace;
+use serde::Serialize;
 use smol::Async;
+use std::sync::{Arc, Mutex};
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
@@ -11,11 +13,24 @@ pub fn new(
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        public_key: Arc::new(Mutex::new(None)),
     };
     Ok((write_receiver, handle))
 }
 
-#[derive(Copy, Clone)]
+/// A handle to the peer at `addr` whose messages are queued for a transport other than TCP
+#[cfg(any(test,test_utilities))]
+pub fn new_in_memory(addr: std::net::SocketAddr) -> (mpsc::UnboundedReceiver<Vec<u8>>, Handle) {
+    let (write_sender, write_receiver) = mpsc::unbounded();
+    let handle = Handle {
+        write_queue: write_sender,
+        addr,
+        public_key: Arc::new(Mutex::new(None)),
+    };
+    (write_receiver, handle)
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -25,6 +40,9 @@ pub enum Direction {
 pub struct Handle {
     addr: std::net::SocketAddr,
     write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    /// Identity key of the peer, shared by the clones of the handle since it is only known once
+    /// the handshake is done
+    public_key: Arc<Mutex<Option<[u8; 32]>>>,
 }
 
 #[cfg(any(test,test_utilities))]
@@ -46,12 +64,22 @@ impl Handle {
         &self.addr
     }
 
+    /// The Ed25519 identity key the peer proved it holds, if the link to it is encrypted
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        *self.public_key.lock().unwrap()
+    }
+
+    pub(super) fn set_public_key(&self, key: [u8; 32]) {
+        *self.public_key.lock().unwrap() = Some(key);
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
             write_queue: s,
+            public_key: Arc::new(Mutex::new(None)),
         },
         TestReceiver {
             r
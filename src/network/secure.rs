@@ -0,0 +1,378 @@
+use super::frame::{FrameError, MAGIC};
+use super::peer::Direction;
+use crate::types::key_pair;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest;
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Name of the handshake, mixed into the keys so that they are only good for this protocol
+const PROTOCOL_NAME: &[u8] = b"bitcoin-p2p/X25519/Ed25519/ChaCha20Poly1305/SHA256";
+/// File in the data directory holding the seed of the identity key of the node
+const KEY_FILE: &str = "node_key";
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Largest sealed record, a whole frame and its tag
+const MAX_RECORD_SIZE: usize = super::frame::HEADER_SIZE + super::frame::MAX_FRAME_SIZE + aead::MAX_TAG_LEN;
+
+/// Whether links to peers are encrypted. A link is encrypted if neither side turned encryption
+/// off, and refused if one side requires it and the other turned it off.
+///
+/// The hellos are in plaintext, and only an encrypted link binds them into its keys and
+/// signatures. A man in the middle can make a node that prefers encryption believe its peer turned
+/// it off, and so keep the link in plaintext; only `Require` rules that out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    Off = 0,
+    Prefer = 1,
+    Require = 2,
+}
+
+impl FromStr for Encryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Encryption::Off),
+            "prefer" => Ok(Encryption::Prefer),
+            "require" => Ok(Encryption::Require),
+            other => Err(format!("unknown encryption mode {}, expected off, prefer or require", other)),
+        }
+    }
+}
+
+impl Encryption {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Encryption::Off),
+            1 => Some(Encryption::Prefer),
+            2 => Some(Encryption::Require),
+            _ => None,
+        }
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Nonces count the records sent in one direction, each key is only used in one
+fn next_nonce(counter: &mut u64) -> Nonce {
+    let mut bytes = [0; aead::NONCE_LEN];
+    bytes[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter += 1;
+    Nonce::assume_unique_for_key(bytes)
+}
+
+/// The sending half of an encrypted link
+pub struct Sealer {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Sealer {
+    /// Encrypt `plaintext` into a record: the 4-byte big endian length of the rest, then the
+    /// ciphertext and its tag. The length is authenticated too.
+    pub fn seal(&mut self, mut plaintext: Vec<u8>) -> Vec<u8> {
+        let len = ((plaintext.len() + aead::MAX_TAG_LEN) as u32).to_be_bytes();
+        self.key
+            .seal_in_place_append_tag(next_nonce(&mut self.counter), Aad::from(len), &mut plaintext)
+            .unwrap();
+        let mut record = Vec::with_capacity(4 + plaintext.len());
+        record.extend_from_slice(&len);
+        record.extend_from_slice(&plaintext);
+        record
+    }
+}
+
+/// The receiving half of an encrypted link
+pub struct Opener {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl Opener {
+    /// Read the next record and decrypt it. A record that is too large or was tampered with is an
+    /// `InvalidData` error.
+    pub async fn read<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).await?;
+        let size = u32::from_be_bytes(len) as usize;
+        if !(aead::MAX_TAG_LEN..=MAX_RECORD_SIZE).contains(&size) {
+            return Err(invalid(format!("record of {} bytes", size)));
+        }
+        let mut record = vec![0; size];
+        reader.read_exact(&mut record).await?;
+        let plaintext_len = self
+            .key
+            .open_in_place(next_nonce(&mut self.counter), Aad::from(len), &mut record)
+            .map_err(|_| invalid("record does not decrypt"))?
+            .len();
+        record.truncate(plaintext_len);
+        Ok(record)
+    }
+}
+
+/// What the handshake of an encrypted link established
+pub struct Session {
+    pub sealer: Sealer,
+    pub opener: Opener,
+    /// the identity key the peer proved it holds
+    pub peer_key: [u8; PUBLIC_KEY_LEN],
+}
+
+/// Parse the address of a peer to connect to, optionally followed by `@` and the hex encoded
+/// identity key the peer must prove it holds
+pub fn parse_peer(s: &str) -> Result<(SocketAddr, Option<[u8; PUBLIC_KEY_LEN]>), String> {
+    let (addr, key) = match s.split_once('@') {
+        Some((addr, key)) => (addr, Some(key)),
+        None => (s, None),
+    };
+    let addr = addr.parse::<SocketAddr>().map_err(|e| format!("bad address {}: {}", addr, e))?;
+    let key = match key {
+        Some(key) => {
+            let bytes = hex::decode(key).map_err(|e| format!("bad identity key {}: {}", key, e))?;
+            let key: [u8; PUBLIC_KEY_LEN] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| format!("identity key {} is not {} bytes", key, PUBLIC_KEY_LEN))?;
+            Some(key)
+        }
+        None => None,
+    };
+    Ok((addr, key))
+}
+
+/// Agree with a peer that just connected on whether to encrypt the link and, if so, run the
+/// handshake. Returns `None` if the link stays in plaintext.
+///
+/// Without `pinned`, the identity of the peer is whatever key it proves it holds, which keeps a
+/// passive eavesdropper out but not a man in the middle, who can run the handshake with each side
+/// under its own key. With `pinned`, the link must be encrypted whatever `encryption` says, and
+/// the peer must prove it holds that key.
+///
+/// Both sides first send a hello made of the frame magic, their `Encryption` and, unless it is
+/// off, an ephemeral X25519 key. The keys of both directions are derived with HKDF from the
+/// shared secret, salted with the hash of both hellos. Each side then sends, in the first sealed
+/// record, its Ed25519 identity key and its signature of that hash and its role, so that a man in
+/// the middle can neither read nor change the link without holding the identity key of a side.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    direction: Direction,
+    identity: &Ed25519KeyPair,
+    encryption: Encryption,
+    pinned: Option<&[u8; PUBLIC_KEY_LEN]>,
+) -> io::Result<Option<Session>> {
+    let encryption = if pinned.is_some() { Encryption::Require } else { encryption };
+    let rng = SystemRandom::new();
+    let ephemeral = EphemeralPrivateKey::generate(&X25519, &rng).unwrap();
+    let mut hello = MAGIC.to_vec();
+    hello.push(encryption as u8);
+    if encryption != Encryption::Off {
+        hello.extend_from_slice(ephemeral.compute_public_key().unwrap().as_ref());
+    }
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+
+    let mut their_hello = vec![0; MAGIC.len() + 1];
+    stream.read_exact(&mut their_hello).await?;
+    let magic: [u8; 4] = their_hello[..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(invalid(FrameError::BadMagic(magic)));
+    }
+    let theirs = Encryption::from_byte(their_hello[4]).ok_or_else(|| invalid("unknown encryption mode"))?;
+    if theirs != Encryption::Off {
+        let mut their_ephemeral = [0; PUBLIC_KEY_LEN];
+        stream.read_exact(&mut their_ephemeral).await?;
+        their_hello.extend_from_slice(&their_ephemeral);
+    }
+    match (encryption, theirs) {
+        (Encryption::Off, Encryption::Require) | (Encryption::Require, Encryption::Off) => {
+            return Err(invalid("the peer does not agree on encryption"));
+        }
+        (Encryption::Off, _) | (_, Encryption::Off) => return Ok(None),
+        _ => {}
+    }
+
+    // the keys and signatures are bound to both hellos, taken in the order of the roles
+    let (ours_role, theirs_role): (&[u8], &[u8]) = match direction {
+        Direction::Outgoing => (b"initiator", b"responder"),
+        Direction::Incoming => (b"responder", b"initiator"),
+    };
+    let mut transcript = digest::Context::new(&digest::SHA256);
+    transcript.update(PROTOCOL_NAME);
+    match direction {
+        Direction::Outgoing => {
+            transcript.update(&hello);
+            transcript.update(&their_hello);
+        }
+        Direction::Incoming => {
+            transcript.update(&their_hello);
+            transcript.update(&hello);
+        }
+    }
+    let transcript = transcript.finish();
+    let their_ephemeral = UnparsedPublicKey::new(&X25519, &their_hello[MAGIC.len() + 1..]);
+    let (sending, receiving) = agreement::agree_ephemeral(ephemeral, &their_ephemeral, invalid("bad ephemeral key"), |shared| {
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, transcript.as_ref()).extract(shared);
+        let key = |info: &[u8]| LessSafeKey::new(UnboundKey::from(prk.expand(&[info], &CHACHA20_POLY1305).unwrap()));
+        Ok((key(ours_role), key(theirs_role)))
+    })?;
+    let mut sealer = Sealer { key: sending, counter: 0 };
+    let mut opener = Opener { key: receiving, counter: 0 };
+
+    // then, both sides prove who they are, sealed so that only the peer learns it
+    let signature = identity.sign(&[transcript.as_ref(), ours_role].concat());
+    let auth = [identity.public_key().as_ref(), signature.as_ref()].concat();
+    stream.write_all(&sealer.seal(auth)).await?;
+    stream.flush().await?;
+    let their_auth = opener.read(stream).await?;
+    if their_auth.len() <= PUBLIC_KEY_LEN {
+        return Err(invalid("identity proof too short"));
+    }
+    let (peer_key, their_signature) = their_auth.split_at(PUBLIC_KEY_LEN);
+    signature::UnparsedPublicKey::new(&signature::ED25519, peer_key)
+        .verify(&[transcript.as_ref(), theirs_role].concat(), their_signature)
+        .map_err(|_| invalid("identity signature does not verify"))?;
+    if pinned.is_some_and(|pinned| pinned[..] != *peer_key) {
+        return Err(invalid(format!("peer identity {} is not the pinned one", hex::encode(peer_key))));
+    }
+    Ok(Some(Session {
+        sealer,
+        opener,
+        peer_key: peer_key.try_into().unwrap(),
+    }))
+}
+
+/// Load the identity key of the node from `dir`, creating it on the first start so that the node
+/// keeps its identity across restarts
+pub fn load_identity(dir: &Path) -> io::Result<Ed25519KeyPair> {
+    let path = dir.join(KEY_FILE);
+    match fs::read(&path) {
+        Ok(seed) => {
+            let seed: [u8; 32] = seed.as_slice().try_into().map_err(|_| invalid("the identity key file is corrupted"))?;
+            return Ok(key_pair::from_seed(seed));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut seed = [0; 32];
+    SystemRandom::new().fill(&mut seed).unwrap();
+    fs::create_dir_all(dir)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // the seed is the private key, only the owner may read it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path)?;
+    file.write_all(&seed)?;
+    file.sync_data()?;
+    Ok(key_pair::from_seed(seed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use smol::Async;
+    use std::net;
+
+    /// Run the handshake over a loopback connection, with `a` dialing `b`
+    fn connect(a: (&Ed25519KeyPair, Encryption), b: (&Ed25519KeyPair, Encryption)) -> (io::Result<Option<Session>>, io::Result<Option<Session>>) {
+        connect_pinned(a, None, b)
+    }
+
+    /// Like `connect`, with `a` pinning the identity key of `b`
+    fn connect_pinned(
+        a: (&Ed25519KeyPair, Encryption),
+        pinned: Option<&[u8; PUBLIC_KEY_LEN]>,
+        b: (&Ed25519KeyPair, Encryption),
+    ) -> (io::Result<Option<Session>>, io::Result<Option<Session>>) {
+        smol::block_on(async {
+            let listener = Async::<net::TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let dial = async {
+                let mut stream = Async::<net::TcpStream>::connect(addr).await.unwrap();
+                handshake(&mut stream, Direction::Outgoing, a.0, a.1, pinned).await
+            };
+            let accept = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                handshake(&mut stream, Direction::Incoming, b.0, b.1, None).await
+            };
+            futures::future::join(dial, accept).await
+        })
+    }
+
+    #[test]
+    fn encrypt_and_authenticate() {
+        let a_key = key_pair::random();
+        let b_key = key_pair::random();
+        let (a, b) = connect((&a_key, Encryption::Prefer), (&b_key, Encryption::Require));
+        let (mut a, mut b) = (a.unwrap().unwrap(), b.unwrap().unwrap());
+        assert_eq!(a.peer_key, b_key.public_key().as_ref());
+        assert_eq!(b.peer_key, a_key.public_key().as_ref());
+        for msg in [b"first".to_vec(), b"second".to_vec()] {
+            let record = a.sealer.seal(msg.clone());
+            assert!(!record.windows(msg.len()).any(|window| window == &msg[..]));
+            assert_eq!(smol::block_on(b.opener.read(&mut &record[..])).unwrap(), msg);
+        }
+        // the way back uses another key
+        let record = b.sealer.seal(b"back".to_vec());
+        assert_eq!(smol::block_on(a.opener.read(&mut &record[..])).unwrap(), b"back");
+        // a changed record, or one replayed, does not decrypt
+        let mut record = a.sealer.seal(b"third".to_vec());
+        *record.last_mut().unwrap() ^= 1;
+        let error = smol::block_on(b.opener.read(&mut &record[..])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn negotiate() {
+        let a_key = key_pair::random();
+        let b_key = key_pair::random();
+        let (a, b) = connect((&a_key, Encryption::Off), (&b_key, Encryption::Prefer));
+        assert!(a.unwrap().is_none() && b.unwrap().is_none());
+        let (a, b) = connect((&a_key, Encryption::Require), (&b_key, Encryption::Off));
+        assert!(a.is_err() && b.is_err());
+        assert_eq!("require".parse(), Ok(Encryption::Require));
+        assert!("on".parse::<Encryption>().is_err());
+    }
+
+    #[test]
+    fn pin_identity() {
+        let a_key = key_pair::random();
+        let b_key = key_pair::random();
+        let b_public_key: [u8; PUBLIC_KEY_LEN] = b_key.public_key().as_ref().try_into().unwrap();
+        let (a, b) = connect_pinned((&a_key, Encryption::Off), Some(&b_public_key), (&b_key, Encryption::Prefer));
+        assert_eq!(a.unwrap().unwrap().peer_key, b_public_key);
+        assert!(b.unwrap().is_some());
+        // a peer that proves another key, or does not encrypt, is refused
+        let (a, _) = connect_pinned((&a_key, Encryption::Prefer), Some(&[0; PUBLIC_KEY_LEN]), (&b_key, Encryption::Prefer));
+        assert!(a.is_err());
+        let (a, _) = connect_pinned((&a_key, Encryption::Prefer), Some(&b_public_key), (&b_key, Encryption::Off));
+        assert!(a.is_err());
+
+        let peer = format!("127.0.0.1:6000@{}", hex::encode(b_public_key));
+        assert_eq!(parse_peer(&peer), Ok(("127.0.0.1:6000".parse().unwrap(), Some(b_public_key))));
+        assert_eq!(parse_peer("127.0.0.1:6000"), Ok(("127.0.0.1:6000".parse().unwrap(), None)));
+        assert!(parse_peer("127.0.0.1:6000@00").is_err());
+        assert!(parse_peer("127.0.0.1@00").is_err());
+    }
+
+    #[test]
+    fn keep_identity() {
+        let data_dir = std::env::temp_dir().join(format!("bitcoin-test-{}", rand::random::<u64>()));
+        let key = load_identity(&data_dir).unwrap();
+        assert_eq!(load_identity(&data_dir).unwrap().public_key().as_ref(), key.public_key().as_ref());
+        fs::write(data_dir.join(KEY_FILE), b"short").unwrap();
+        assert!(load_identity(&data_dir).is_err());
+        fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
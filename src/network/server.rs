@@ -1,51 +1,267 @@
-use crate::types::address::Address;
+use super::address_book::{self, AddressBook};
+use super::ban::{Ban, BanList, Banned, Misbehavior, BAN_THRESHOLD};
+use super::frame;
+use super::inventory::KnownInventory;
+#[cfg(any(test,test_utilities))]
+use super::memory;
 use super::peer;
-use super::message;
+use super::message::{self, Message, PeerAddr};
+use super::secure::{self, Encryption};
+use super::shaping::{Shape, Shaper, ShapingRule, ShapingRules};
 
 use async_dup::Arc as AsyncArc;
-use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
+use futures::{channel::mpsc, channel::oneshot, stream::StreamExt};
+use serde::Serialize;
+use crate::types::hash::H256;
+use crate::types::key_pair;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use ring::signature::{Ed25519KeyPair, KeyPair};
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::net;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Builds the `Version` this node announces, so that it carries the current best height
+pub type VersionSource = Arc<dyn Fn() -> message::Version + Send + Sync>;
 
+/// A peer that has not completed the handshake within this time is disconnected
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the number of outbound peers is topped up from the address book
+const DIAL_INTERVAL: Duration = Duration::from_secs(5);
+/// A dial that has not connected within this time is given up
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TARGET_OUTBOUND: usize = 8;
+/// Most outbound peers, counting the ones connected to on request
+pub const DEFAULT_MAX_OUTBOUND: usize = 16;
+pub const DEFAULT_MAX_INBOUND: usize = 117;
+/// How often peers are pinged, once they answered the last ping
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+/// A peer that has not answered a ping within this time is disconnected
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(90);
+/// bincode tag of `Message::Pong`, to time keepalive replies as they are read
+const PONG_TAG: u32 = 1;
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    version: VersionSource,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
-        control_chan: control_signal_sender.clone(),
+        transport: Transport::Server(control_signal_sender.clone()),
     };
     let ctx = Context {
-        peers: std::collections::HashMap::new(),
+        peers: HashMap::new(),
+        address_book: AddressBook::new(),
+        dialing: HashSet::new(),
+        handshaking: HashMap::new(),
+        target_outbound: DEFAULT_TARGET_OUTBOUND,
+        max_outbound: DEFAULT_MAX_OUTBOUND,
+        max_inbound: DEFAULT_MAX_INBOUND,
+        ping_interval: DEFAULT_PING_INTERVAL,
+        ping_timeout: DEFAULT_PING_TIMEOUT,
         addr,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        version,
+        bans: BanList::new(),
+        identity: Arc::new(key_pair::random()),
+        encryption: Encryption::Require,
+        pinned_keys: HashMap::new(),
+        shaping: Arc::new(Mutex::new(ShapingRules::new())),
     };
     Ok((ctx, handle))
 }
 
+/// A peer that completed the handshake
+struct ConnectedPeer {
+    handle: peer::Handle,
+    direction: peer::Direction,
+    /// The address the peer accepts connections at
+    listen_addr: std::net::SocketAddr,
+    /// When the handshake completed, in seconds since the unix epoch
+    connected_at: u64,
+    known: KnownInventory,
+    /// Sum of the scores of what the peer did wrong, it is banned once this reaches `BAN_THRESHOLD`
+    misbehavior: u32,
+    stream: AsyncArc<Async<net::TcpStream>>,
+    /// Nonce of the ping the peer has not answered yet and when it was sent
+    ping: Option<(String, Instant)>,
+    /// Round-trip time of the last ping answered, and the lowest one
+    rtt: Option<Duration>,
+    min_rtt: Option<Duration>,
+}
+
+impl ConnectedPeer {
+    /// Close the connection, the reader task then reports the peer as dropped
+    fn disconnect(&self) {
+        let _ = self.stream.get_ref().shutdown(net::Shutdown::Both);
+    }
+}
+
+/// A connected peer, as returned by `Handle::peers`
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub listen_addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    pub connected_at: u64,
+    pub misbehavior: u32,
+    /// Hex encoded identity key of the peer, if the link to it is encrypted
+    pub public_key: Option<String>,
+    /// Round-trip time of the last ping the peer answered, and the lowest one, in microseconds
+    pub rtt_us: Option<u64>,
+    pub min_rtt_us: Option<u64>,
+}
+
 pub struct Context {
-    peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
+    peers: HashMap<std::net::SocketAddr, ConnectedPeer>,
+    address_book: AddressBook,
+    /// Addresses the outbound connection manager is connecting to
+    dialing: HashSet<std::net::SocketAddr>,
+    /// Connections that have not completed the handshake, which count towards the limits
+    handshaking: HashMap<std::net::SocketAddr, peer::Direction>,
+    target_outbound: usize,
+    max_outbound: usize,
+    max_inbound: usize,
+    ping_interval: Duration,
+    ping_timeout: Duration,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    version: VersionSource,
+    bans: BanList,
+    /// Key the node proves its identity with to the peers of encrypted links
+    identity: Arc<Ed25519KeyPair>,
+    encryption: Encryption,
+    /// Identity keys the peers at these addresses must prove they hold when dialed
+    pinned_keys: HashMap<std::net::SocketAddr, [u8; secure::PUBLIC_KEY_LEN]>,
+    /// Delay, bandwidth and loss put on the messages sent to each peer, shared with the writers
+    shaping: Arc<Mutex<ShapingRules>>,
+}
+
+/// Read the next frame from a peer, decrypting it if the link is encrypted, and return its
+/// payload. An `InvalidData` error means the peer sent something it must not have, and the size of
+/// a plaintext frame is checked before anything is allocated for it.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, opener: Option<&mut secure::Opener>) -> io::Result<Vec<u8>> {
+    let bytes = match opener {
+        Some(opener) => opener.read(reader).await?,
+        None => {
+            let mut header = [0; frame::HEADER_SIZE];
+            reader.read_exact(&mut header).await?;
+            let (size, _) = frame::decode_header(&header).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mut bytes = vec![0; frame::HEADER_SIZE + size];
+            bytes[..frame::HEADER_SIZE].copy_from_slice(&header);
+            reader.read_exact(&mut bytes[frame::HEADER_SIZE..]).await?;
+            bytes
+        }
+    };
+    frame::decode(&bytes)
+        .map(|payload| payload.to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// What of a broadcast `msg` to send to a peer that knows of the inventory in `known`, if anything,
+/// recording that the peer knows of what is sent
+pub(super) fn announcement(known: &mut KnownInventory, msg: &Message) -> Option<Message> {
+    // announce to each peer only what it does not know of yet
+    let msg = match msg {
+        Message::NewBlockHashes(hashes) => Message::NewBlockHashes(known.filter_new(hashes)),
+        Message::NewTransactionHashes(hashes) => Message::NewTransactionHashes(known.filter_new(hashes)),
+        Message::NewUtxoTransactionHashes(hashes) => Message::NewUtxoTransactionHashes(known.filter_new(hashes)),
+        msg => msg.clone(),
+    };
+    match &msg {
+        Message::NewBlockHashes(hashes) | Message::NewTransactionHashes(hashes) | Message::NewUtxoTransactionHashes(hashes)
+            if hashes.is_empty() => None,
+        Message::CompactBlock(compact) if !known.insert(compact.hash()) => None,
+        _ => Some(msg),
+    }
+}
+
+/// Check the `Version` of a peer against ours, returning why it is rejected
+fn check_version(theirs: &message::Version, ours: &message::Version) -> Result<(), String> {
+    if theirs.protocol_version < message::MIN_PROTOCOL_VERSION {
+        return Err(format!("protocol version {} is too old", theirs.protocol_version));
+    }
+    if theirs.genesis != ours.genesis {
+        return Err(format!("genesis {} differs from ours", theirs.genesis));
+    }
+    Ok(())
 }
 
 impl Context {
+    /// Set the number of outbound peers the server keeps by dialing addresses it has heard of
+    pub fn set_target_outbound(&mut self, target_outbound: usize) {
+        self.target_outbound = target_outbound;
+    }
+
+    /// Set the most outbound peers, including the ones connected to on request
+    pub fn set_max_outbound(&mut self, max_outbound: usize) {
+        self.max_outbound = max_outbound;
+    }
+
+    /// Set the most inbound peers, connections beyond it are refused
+    pub fn set_max_inbound(&mut self, max_inbound: usize) {
+        self.max_inbound = max_inbound;
+    }
+
+    /// Set how often peers are pinged to measure their round-trip time and check they are alive
+    pub fn set_ping_interval(&mut self, interval: Duration) {
+        self.ping_interval = interval;
+    }
+
+    /// Set how long a peer has to answer a ping before it is disconnected
+    pub fn set_ping_timeout(&mut self, timeout: Duration) {
+        self.ping_timeout = timeout;
+    }
+
+    /// Set how long a peer that misbehaved too much is banned for
+    pub fn set_ban_duration(&mut self, duration: Duration) {
+        self.bans.set_duration(duration);
+    }
+
+    /// Set the identity key of the node, a random one is used otherwise
+    pub fn set_identity(&mut self, identity: Ed25519KeyPair) {
+        self.identity = Arc::new(identity);
+    }
+
+    /// Set whether links to peers are encrypted
+    pub fn set_encryption(&mut self, encryption: Encryption) {
+        self.encryption = encryption;
+    }
+
+    /// Require the peer at `addr`, when dialed, to encrypt the link and prove it holds the
+    /// identity key `key`
+    pub fn pin_key(&mut self, addr: std::net::SocketAddr, key: [u8; secure::PUBLIC_KEY_LEN]) {
+        self.pinned_keys.insert(addr, key);
+    }
+
+    /// Set the shape of the links to peers that have none of their own, which can be changed
+    /// once the server runs with `Handle::set_shaping`
+    pub fn set_shaping(&mut self, shape: Shape) {
+        self.shaping.lock().unwrap().set(None, shape);
+    }
+
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
         // initialize the server socket
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
         info!("P2P server listening at {}", self.addr);
+        info!("P2P identity {}, encryption {:?}", hex::encode(self.identity.public_key()), self.encryption);
         let control_chan = self.control_sender.clone();
+        let keepalive_chan = control_chan.clone();
+        let ping_interval = self.ping_interval;
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -53,10 +269,25 @@ impl Context {
             self.dispatch_control(ex_clone).await.unwrap();
         })
             .detach();
+        let tick_chan = control_chan.clone();
         ex.spawn(async move {
             Self::listener_loop(listener, control_chan).await.unwrap();
         })
             .detach();
+        ex.spawn(async move {
+            loop {
+                smol::Timer::after(DIAL_INTERVAL).await;
+                tick_chan.send(ControlSignal::Tick).await.unwrap();
+            }
+        })
+            .detach();
+        ex.spawn(async move {
+            loop {
+                smol::Timer::after(ping_interval).await;
+                keepalive_chan.send(ControlSignal::Keepalive).await.unwrap();
+            }
+        })
+            .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
         return Ok(());
     }
@@ -82,32 +313,256 @@ impl Context {
             match ctrl {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
+                    if self.connections(peer::Direction::Outgoing) >= self.max_outbound {
+                        let error = std::io::Error::other("too many outbound peers");
+                        result_chan.send(Err(error)).unwrap();
+                        continue;
+                    }
                     let handle = self.connect(&addr, ex.clone()).await;
                     result_chan.send(handle).unwrap();
                 }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
-                    for (_, hd) in self.peers.iter_mut() {
-                        hd.write(msg.clone());
+                    for (_, peer) in self.peers.iter_mut() {
+                        if let Some(msg) = announcement(&mut peer.known, &msg) {
+                            peer.handle.write(msg);
+                        }
+                    }
+                }
+                ControlSignal::MarkKnown(addr, hashes) => {
+                    trace!("Processing MarkKnown({})", addr);
+                    if let Some(peer) = self.peers.get_mut(&addr) {
+                        for hash in hashes {
+                            peer.known.insert(hash);
+                        }
                     }
                 }
                 ControlSignal::GetNewPeer(stream) => {
                     trace!("Processing GetNewPeer command");
+                    if let Ok(addr) = stream.get_ref().peer_addr() {
+                        if self.bans.is_banned(&Banned::Ip(addr.ip()), address_book::now()) {
+                            debug!("Refusing banned peer {}", addr);
+                            continue;
+                        }
+                    }
+                    if self.connections(peer::Direction::Incoming) >= self.max_inbound {
+                        debug!("Refusing incoming peer, {} inbound connections already", self.max_inbound);
+                        continue;
+                    }
                     self.accept(stream, ex.clone()).await?;
                 }
+                ControlSignal::PeerReady(addr, mut peer) => {
+                    trace!("Processing PeerReady({})", addr);
+                    self.handshaking.remove(&addr);
+                    // a node banned by its identity key is only recognized once the link is encrypted
+                    if self.bans.is_peer_banned(addr.ip(), peer.handle.public_key(), address_book::now()) {
+                        debug!("Disconnecting banned peer {}", addr);
+                        peer.disconnect();
+                        continue;
+                    }
+                    let seen = PeerAddr { addr: peer.listen_addr, last_seen: address_book::now() };
+                    self.address_book.add(seen.addr, seen.last_seen);
+                    match peer.direction {
+                        // learn about more nodes from the ones we dial
+                        peer::Direction::Outgoing => peer.handle.write(Message::GetAddr),
+                        // and tell the others about the ones dialing us
+                        peer::Direction::Incoming => {
+                            for (_, other) in self.peers.iter_mut() {
+                                other.handle.write(Message::Addr(vec![seen]));
+                            }
+                        }
+                    }
+                    self.peers.insert(addr, peer);
+                }
+                ControlSignal::AddAddresses(addrs) => {
+                    trace!("Processing AddAddresses command");
+                    for peer_addr in addrs.into_iter().take(address_book::MAX_ADDR_PER_MESSAGE) {
+                        if peer_addr.addr != self.addr && !self.bans.is_banned(&Banned::Ip(peer_addr.addr.ip()), address_book::now()) {
+                            self.address_book.add(peer_addr.addr, peer_addr.last_seen);
+                        }
+                    }
+                }
+                ControlSignal::GetAddresses(result_chan) => {
+                    trace!("Processing GetAddresses command");
+                    let _ = result_chan.send(self.address_book.sample(address_book::MAX_ADDR_PER_MESSAGE));
+                }
+                ControlSignal::Tick => {
+                    trace!("Processing Tick command");
+                    self.maintain_outbound(ex.clone());
+                }
+                ControlSignal::Dialed(addr, stream) => {
+                    trace!("Processing Dialed({})", addr);
+                    self.dialing.remove(&addr);
+                    if self.connections(peer::Direction::Outgoing) >= self.max_outbound {
+                        debug!("Not keeping connection to {}, {} outbound connections already", addr, self.max_outbound);
+                        continue;
+                    }
+                    match self.register(stream, peer::Direction::Outgoing, ex.clone()).await {
+                        Ok(_) => info!("Connected to outgoing peer {}", addr),
+                        Err(e) => debug!("Error registering peer {}: {}", addr, e),
+                    }
+                }
+                ControlSignal::DialFailed(addr) => {
+                    trace!("Processing DialFailed({})", addr);
+                    self.dialing.remove(&addr);
+                    self.address_book.remove(&addr);
+                }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
+                    self.handshaking.remove(&addr);
                     self.peers.remove(&addr);
                     info!("Peer {} disconnected", addr);
                 }
-                ControlSignal::SendToPeer((_receiver, _msg)) => {
-                    unimplemented!()
+                ControlSignal::SendToPeer(addr, msg) => {
+                    trace!("Processing SendToPeer({})", addr);
+                    match self.peers.get_mut(&addr) {
+                        Some(peer) => peer.handle.write(msg),
+                        None => debug!("Not sending to {}, which is not a connected peer", addr),
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let peers = self
+                        .peers
+                        .iter()
+                        .map(|(addr, peer)| PeerInfo {
+                            addr: *addr,
+                            listen_addr: peer.listen_addr,
+                            direction: peer.direction,
+                            connected_at: peer.connected_at,
+                            misbehavior: peer.misbehavior,
+                            public_key: peer.handle.public_key().map(hex::encode),
+                            rtt_us: peer.rtt.map(|rtt| rtt.as_micros() as u64),
+                            min_rtt_us: peer.min_rtt.map(|rtt| rtt.as_micros() as u64),
+                        })
+                        .collect();
+                    let _ = result_chan.send(peers);
+                }
+                ControlSignal::Misbehaving(addr, misbehavior) => {
+                    trace!("Processing Misbehaving({})", addr);
+                    self.misbehaving(addr, misbehavior);
+                }
+                ControlSignal::GetBans(result_chan) => {
+                    trace!("Processing GetBans command");
+                    let _ = result_chan.send(self.bans.list(address_book::now()));
+                }
+                ControlSignal::GetShaping(result_chan) => {
+                    trace!("Processing GetShaping command");
+                    let _ = result_chan.send(self.shaping.lock().unwrap().list());
+                }
+                ControlSignal::SetShaping(peer, shape) => {
+                    trace!("Processing SetShaping command");
+                    self.shaping.lock().unwrap().update(peer, shape);
+                }
+                ControlSignal::Keepalive => {
+                    trace!("Processing Keepalive command");
+                    self.keepalive();
+                }
+                ControlSignal::Pong(addr, nonce, received) => {
+                    trace!("Processing Pong({})", addr);
+                    if let Some(peer) = self.peers.get_mut(&addr) {
+                        // pongs to pings sent by someone else, like the API, are not timed
+                        if let Some((_, sent)) = peer.ping.as_ref().filter(|(expected, _)| *expected == nonce) {
+                            let rtt = received.saturating_duration_since(*sent);
+                            peer.rtt = Some(rtt);
+                            peer.min_rtt = Some(peer.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+                            peer.ping = None;
+                        }
+                    }
                 }
             }
         }
         return Ok(());
     }
 
+    /// Add to the misbehavior score of a peer, and disconnect and ban it once the score reaches
+    /// `BAN_THRESHOLD`
+    fn misbehaving(&mut self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        let peer = match self.peers.get_mut(&addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.misbehavior = peer.misbehavior.saturating_add(misbehavior.score());
+        warn!("Peer {} misbehaved: {:?}, score {}", addr, misbehavior, peer.misbehavior);
+        if peer.misbehavior >= BAN_THRESHOLD {
+            let banned = Banned::of(addr.ip(), peer.handle.public_key());
+            let until = self.bans.ban(banned, address_book::now());
+            warn!("Banning {} as {:?} until {}", addr, banned, until);
+            peer.disconnect();
+            self.address_book.remove(&peer.listen_addr);
+        }
+    }
+
+    /// Number of connections in `direction`, including the ones still in the handshake
+    fn connections(&self, direction: peer::Direction) -> usize {
+        let connected = self.peers.values().filter(|peer| peer.direction == direction).count();
+        connected + self.handshaking.values().filter(|d| **d == direction).count()
+    }
+
+    /// Ping the peers that answered their last ping, and disconnect the ones that have not
+    /// answered it within the timeout
+    fn keepalive(&mut self) {
+        let now = Instant::now();
+        for (addr, peer) in self.peers.iter_mut() {
+            match &peer.ping {
+                Some((_, sent)) if now.saturating_duration_since(*sent) > self.ping_timeout => {
+                    warn!("Peer {} did not answer a ping within {:?}, disconnecting", addr, self.ping_timeout);
+                    peer.disconnect();
+                }
+                Some(_) => {}
+                None => {
+                    let nonce = rand::random::<u64>().to_string();
+                    peer.handle.write(Message::Ping(nonce.clone()));
+                    peer.ping = Some((nonce, now));
+                }
+            }
+        }
+    }
+
+    /// Dial addresses from the address book until there are as many outbound peers as targeted.
+    /// If the address book has run out of new addresses, ask the outbound peers for more.
+    fn maintain_outbound(&mut self, ex: Arc<Executor<'_>>) {
+        let target_outbound = self.target_outbound.min(self.max_outbound);
+        let pending = self.connections(peer::Direction::Outgoing) + self.dialing.len();
+        if pending >= target_outbound {
+            return;
+        }
+        let mut exclude: HashSet<std::net::SocketAddr> = self.peers.values().map(|peer| peer.listen_addr).collect();
+        exclude.extend(self.dialing.iter().cloned());
+        exclude.insert(self.addr);
+        let now = address_book::now();
+        let candidates: Vec<std::net::SocketAddr> = self
+            .address_book
+            .candidates(target_outbound - pending, &exclude)
+            .into_iter()
+            .filter(|addr| !self.bans.is_banned(&Banned::Ip(addr.ip()), now))
+            .collect();
+        if candidates.is_empty() {
+            for peer in self.peers.values_mut().filter(|peer| peer.direction == peer::Direction::Outgoing) {
+                peer.handle.write(Message::GetAddr);
+            }
+            return;
+        }
+        for addr in candidates {
+            debug!("Dialing {}", addr);
+            self.dialing.insert(addr);
+            let control_chan = self.control_sender.clone();
+            ex.spawn(async move {
+                let dial = async { Async::<std::net::TcpStream>::connect(addr).await.ok() };
+                let timeout = async {
+                    smol::Timer::after(DIAL_TIMEOUT).await;
+                    None
+                };
+                let signal = match smol::future::or(dial, timeout).await {
+                    Some(stream) => ControlSignal::Dialed(addr, stream),
+                    None => ControlSignal::DialFailed(addr),
+                };
+                control_chan.send(signal).await.unwrap();
+            })
+                .detach();
+        }
+    }
+
     /// Connect to a peer, and register this peer
     async fn connect(
         &mut self,
@@ -133,75 +588,190 @@ impl Context {
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (mut write_queue, mut handle) = peer::new(&stream)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
-        let handle_copy = handle.clone();
+        let mut handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        self.handshaking.insert(addr, direction);
+
+        // both sides announce themselves first; the peer only gets messages from us and the worker
+        // only gets messages from the peer once both have accepted the other's version
+        let our_version = (self.version)();
+        handle.write(Message::Version(our_version.clone()));
+        let handshake_done = Arc::new(AtomicBool::new(false));
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
         let mut reader = BufReader::new(stream.clone());
+        let reader_stream = stream.clone();
+        let reader_done = handshake_done.clone();
+        let identity = self.identity.clone();
+        let encryption = self.encryption;
+        // an incoming peer connects from a port of its choosing, so only a dialed one can be pinned
+        let pinned = match direction {
+            peer::Direction::Outgoing => self.pinned_keys.get(&addr).copied(),
+            peer::Direction::Incoming => None,
+        };
+        // the writer gets the sending half of the link once the reader has set it up
+        let (sealer_sink, sealer_chan) = oneshot::channel::<Option<secure::Sealer>>();
         ex.spawn(async move {
-            // the buffer to store the frame header, which contains the length of the frame
-            let mut size_buffer: [u8; 4] = [0; 4];
-            // the buffer to store the message content
-            let mut msg_buffer: Vec<u8> = vec![];
+            // first, agree with the peer on whether and how to encrypt the link
+            let mut opener = match secure::handshake(&mut reader, direction, &identity, encryption, pinned.as_ref()).await {
+                Ok(Some(session)) => {
+                    debug!("Link to {} is encrypted, peer identity {}", addr, hex::encode(session.peer_key));
+                    handle_copy.set_public_key(session.peer_key);
+                    let _ = sealer_sink.send(Some(session.sealer));
+                    Some(session.opener)
+                }
+                Ok(None) => {
+                    let _ = sealer_sink.send(None);
+                    None
+                }
+                Err(e) => {
+                    warn!("Cutting peer {}: {}", addr, e);
+                    let _ = reader_stream.get_ref().shutdown(net::Shutdown::Both);
+                    control_chan.send(ControlSignal::DroppedPeer(addr)).await.unwrap();
+                    return;
+                }
+            };
+            let mut peer_version: Option<message::Version> = None;
+            let mut got_verack = false;
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
+                let new_payload = match read_frame(&mut reader, opener.as_mut()).await {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::InvalidData {
+                            warn!("Cutting peer {}: {}", addr, e);
+                        }
                         break;
                     }
                 };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
-                            .await
-                            .unwrap();
+                if reader_done.load(Ordering::SeqCst) {
+                    // time keepalive replies as they arrive, rather than after the worker queue
+                    if frame::tag(&new_payload) == Some(PONG_TAG) {
+                        if let Ok(Message::Pong(nonce)) = bincode::deserialize(&new_payload) {
+                            control_chan.send(ControlSignal::Pong(addr, nonce, Instant::now())).await.unwrap();
+                        }
                     }
-                    Err(_) => {
+                    new_msg_chan
+                        .send((new_payload, handle_copy.clone()))
+                        .await
+                        .unwrap();
+                    continue;
+                }
+                match bincode::deserialize::<Message>(&new_payload) {
+                    Ok(Message::Version(version)) if peer_version.is_none() => {
+                        if let Err(reason) = check_version(&version, &our_version) {
+                            warn!("Rejecting peer {}: {}", addr, reason);
+                            break;
+                        }
+                        handle_copy.write(Message::VerAck);
+                        peer_version = Some(version);
+                    }
+                    Ok(Message::VerAck) if !got_verack => {
+                        got_verack = true;
+                    }
+                    _ => {
+                        warn!("Peer {} broke the handshake, disconnecting", addr);
                         break;
                     }
                 }
+                if let (Some(version), true) = (&peer_version, got_verack) {
+                    info!(
+                        "Handshake with {} done: {} speaking protocol {}, at height {}",
+                        addr,
+                        version.user_agent,
+                        version.protocol_version.min(message::PROTOCOL_VERSION),
+                        version.best_height
+                    );
+                    reader_done.store(true, Ordering::SeqCst);
+                    // we know where to reach an outgoing peer, an incoming one tells us its port
+                    let listen_addr = match direction {
+                        peer::Direction::Outgoing => addr,
+                        peer::Direction::Incoming => std::net::SocketAddr::new(addr.ip(), version.listen_addr.port()),
+                    };
+                    let ready = ConnectedPeer {
+                        handle: handle_copy.clone(),
+                        direction,
+                        listen_addr,
+                        connected_at: address_book::now(),
+                        known: KnownInventory::new(),
+                        misbehavior: 0,
+                        stream: reader_stream.clone(),
+                        ping: None,
+                        rtt: None,
+                        min_rtt: None,
+                    };
+                    control_chan
+                        .send(ControlSignal::PeerReady(addr, ready))
+                        .await
+                        .unwrap();
+                    // let the worker know about the peer
+                    let payload = bincode::serialize(&Message::Version(version.clone())).unwrap();
+                    new_msg_chan
+                        .send((payload, handle_copy.clone()))
+                        .await
+                        .unwrap();
+                }
             }
             // the peer is disconnected
+            let _ = reader_stream.get_ref().shutdown(net::Shutdown::Both);
+            control_chan
+                .send(ControlSignal::DroppedPeer(addr))
+                .await
+                .unwrap();
         })
             .detach();
 
-        // second, start a task that keeps writing to this guy
-        let mut writer = BufWriter::new(stream.clone());
+        // second, start a task that decides when each message goes out, as it is queued, so that
+        // the delay of a message does not add to the delay of the ones behind it
+        let (shaped_sink, mut shaped_queue) = mpsc::unbounded::<(Instant, Vec<u8>)>();
+        let shaping = self.shaping.clone();
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
-
-                // second, encode the length of the message
-                let size_buffer = (new_msg.len() as u32).to_be_bytes();
-
-                // third, write the frame header and the payload
-                match writer.write_all(&size_buffer).await {
-                    Ok(_) => {}
-                    Err(_) => {
-                        break;
+            let mut shaper = Shaper::new(Instant::now());
+            let mut rng = StdRng::from_entropy();
+            // stop once every handle is gone
+            while let Some(new_msg) = write_queue.next().await {
+                let shape = shaping.lock().unwrap().get(&addr);
+                match shaper.schedule(&shape, frame::HEADER_SIZE + new_msg.len(), Instant::now(), &mut rng) {
+                    Some(at) => {
+                        if shaped_sink.unbounded_send((at, new_msg)).is_err() {
+                            break;
+                        }
                     }
+                    None => trace!("Dropping a message to {}", addr),
                 }
-                match writer.write_all(&new_msg).await {
+            }
+        })
+            .detach();
+
+        // third, start a task that keeps writing to this guy
+        let mut writer = BufWriter::new(stream.clone());
+        let writer_stream = stream.clone();
+        ex.spawn(async move {
+            let mut sealer = match sealer_chan.await {
+                Ok(sealer) => sealer,
+                Err(_) => {
+                    // the handshake failed
+                    return;
+                }
+            };
+            while let Some((at, new_msg)) = shaped_queue.next().await {
+                if at > Instant::now() {
+                    smol::Timer::at(at).await;
+                }
+                // write the frame header and the payload, sealed if the link is encrypted
+                let mut bytes = frame::encode(&new_msg);
+                if let Some(sealer) = sealer.as_mut() {
+                    bytes = sealer.seal(bytes);
+                }
+                match writer.write_all(&bytes).await {
                     Ok(_) => {}
                     Err(_) => {
                         break;
@@ -214,23 +784,39 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
-            control_chan
-                .send(ControlSignal::DroppedPeer(addr))
-                .await
-                .unwrap();
+            // the peer is disconnected, which the reader notices
+            let _ = writer_stream.get_ref().shutdown(net::Shutdown::Both);
+        })
+            .detach();
+
+        // drop peers that do not complete the handshake in time
+        let timeout_stream = stream.clone();
+        ex.spawn(async move {
+            smol::Timer::after(HANDSHAKE_TIMEOUT).await;
+            if !handshake_done.load(Ordering::SeqCst) {
+                warn!("Peer {} did not complete the handshake in time", addr);
+                let _ = timeout_stream.get_ref().shutdown(net::Shutdown::Both);
+            }
         })
             .detach();
 
-        // insert the peer handle so that we can broadcast to this guy later
-        self.peers.insert(addr, handle.clone());
         Ok(handle)
     }
 }
 
 #[derive(Clone)]
 pub struct Handle {
-    control_chan: smol::channel::Sender<ControlSignal>,
+    transport: Transport,
+}
+
+/// Where the requests made through a handle go
+#[derive(Clone)]
+enum Transport {
+    /// to the server context
+    Server(smol::channel::Sender<ControlSignal>),
+    /// to the in-memory network, on behalf of the node at this address
+    #[cfg(any(test,test_utilities))]
+    Memory(Arc<Mutex<memory::Network>>, std::net::SocketAddr),
 }
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver{
@@ -239,38 +825,155 @@ pub struct TestReceiver{
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&self) -> Option<message::Message> {
-        let sig = smol::block_on(self.control_chan.recv()).unwrap();
-        match sig {
-            // in this test, only return broadcast msg
-            ControlSignal::BroadcastMessage(msg) => Some(msg),
-            _ => None,
+        // in this test, only return broadcast msg
+        loop {
+            let sig = smol::block_on(self.control_chan.recv()).ok()?;
+            if let ControlSignal::BroadcastMessage(msg) = sig {
+                return Some(msg);
+            }
         }
     }
 }
 
 impl Handle {
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        let (sender, receiver) = oneshot::channel();
-        smol::block_on(
-            self.control_chan
-                .send(ControlSignal::ConnectNewPeer(addr, sender)),
-        )
-            .unwrap();
-        smol::block_on(receiver).unwrap()
+        match &self.transport {
+            Transport::Server(control_chan) => {
+                let (sender, receiver) = oneshot::channel();
+                smol::block_on(
+                    control_chan
+                        .send(ControlSignal::ConnectNewPeer(addr, sender)),
+                )
+                    .unwrap();
+                smol::block_on(receiver).unwrap()
+            }
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().connect(*node, addr),
+        }
     }
 
     pub fn broadcast(&self, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
+        match &self.transport {
+            Transport::Server(control_chan) => smol::block_on(control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap(),
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().broadcast(node, msg),
+        }
     }
 
-    pub fn send(&self, receiver: Address, msg: message::Message) {
-        smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
+    /// Record addresses of other nodes heard from a peer
+    pub fn add_addresses(&self, addrs: Vec<PeerAddr>) {
+        match &self.transport {
+            Transport::Server(control_chan) => smol::block_on(control_chan.send(ControlSignal::AddAddresses(addrs))).unwrap(),
+            // the links of an in-memory network are made by hand
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(..) => {}
+        }
+    }
+
+    /// Addresses of other nodes to tell a peer about
+    pub fn addresses(&self) -> Vec<PeerAddr> {
+        match &self.transport {
+            Transport::Server(control_chan) => {
+                let (sender, receiver) = oneshot::channel();
+                smol::block_on(control_chan.send(ControlSignal::GetAddresses(sender))).unwrap();
+                smol::block_on(receiver).unwrap()
+            }
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(..) => vec![],
+        }
+    }
+
+    /// Record that the peer at `addr` has the blocks or transactions with `hashes`, so that they
+    /// are not announced to it
+    pub fn mark_known(&self, addr: std::net::SocketAddr, hashes: Vec<H256>) {
+        match &self.transport {
+            Transport::Server(control_chan) => smol::block_on(control_chan.send(ControlSignal::MarkKnown(addr, hashes))).unwrap(),
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().mark_known(node, &addr, hashes),
+        }
+    }
+
+    /// Send a message to the connected peer at `addr` only
+    pub fn send(&self, addr: std::net::SocketAddr, msg: message::Message) {
+        match &self.transport {
+            Transport::Server(control_chan) => smol::block_on(control_chan.send(ControlSignal::SendToPeer(addr, msg))).unwrap(),
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().send(node, &addr, msg),
+        }
+    }
+
+    /// The peers that completed the handshake
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        match &self.transport {
+            Transport::Server(control_chan) => {
+                let (sender, receiver) = oneshot::channel();
+                smol::block_on(control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+                smol::block_on(receiver).unwrap()
+            }
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().peers(node),
+        }
+    }
+
+    /// Report that the peer at `addr` sent something invalid
+    pub fn misbehaving(&self, addr: std::net::SocketAddr, misbehavior: Misbehavior) {
+        match &self.transport {
+            Transport::Server(control_chan) => smol::block_on(control_chan.send(ControlSignal::Misbehaving(addr, misbehavior))).unwrap(),
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().misbehaving(node, &addr, misbehavior),
+        }
+    }
+
+    /// The IP addresses and identity keys banned for misbehaving and when their ban ends
+    pub fn bans(&self) -> Vec<Ban> {
+        match &self.transport {
+            Transport::Server(control_chan) => {
+                let (sender, receiver) = oneshot::channel();
+                smol::block_on(control_chan.send(ControlSignal::GetBans(sender))).unwrap();
+                smol::block_on(receiver).unwrap()
+            }
+            // an in-memory network cuts the link to a peer that misbehaves instead
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(..) => vec![],
+        }
+    }
+
+    /// The shape of the links to peers, first the one of the peers that have none of their own
+    pub fn shaping(&self) -> Vec<ShapingRule> {
+        match &self.transport {
+            Transport::Server(control_chan) => {
+                let (sender, receiver) = oneshot::channel();
+                smol::block_on(control_chan.send(ControlSignal::GetShaping(sender))).unwrap();
+                smol::block_on(receiver).unwrap()
+            }
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().shaping(node),
+        }
+    }
+
+    /// Shape the links to the peer at `peer`, or to every peer at its IP address if the port is 0,
+    /// or to the peers that have no shape of their own if there is no address. No shape gives the
+    /// peer the shape of the others again, or with no address stops shaping the others.
+    pub fn set_shaping(&self, peer: Option<std::net::SocketAddr>, shape: Option<Shape>) {
+        match &self.transport {
+            Transport::Server(control_chan) => smol::block_on(control_chan.send(ControlSignal::SetShaping(peer, shape))).unwrap(),
+            #[cfg(any(test,test_utilities))]
+            Transport::Memory(network, node) => network.lock().unwrap().set_shaping(node, peer, shape),
+        }
+    }
+
+    /// A handle of the node at `addr` of an in-memory network, which has to be added to it
+    #[cfg(any(test,test_utilities))]
+    pub fn in_memory(network: &Arc<Mutex<memory::Network>>, addr: std::net::SocketAddr) -> Handle {
+        Handle {
+            transport: Transport::Memory(Arc::clone(network), addr),
+        }
     }
 
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
-        let h = Handle {control_chan: s};
+        let h = Handle {transport: Transport::Server(s)};
         let t = TestReceiver {control_chan: r};
         (h,t)
     }
@@ -283,6 +986,316 @@ enum ControlSignal {
     ),
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
+    /// A peer completed the handshake
+    PeerReady(std::net::SocketAddr, ConnectedPeer),
+    AddAddresses(Vec<PeerAddr>),
+    GetAddresses(oneshot::Sender<Vec<PeerAddr>>),
+    /// Time to check the number of outbound peers
+    Tick,
+    /// The outbound connection manager connected to an address
+    Dialed(std::net::SocketAddr, Async<net::TcpStream>),
+    DialFailed(std::net::SocketAddr),
     DroppedPeer(std::net::SocketAddr),
-    SendToPeer((Address,message::Message)),
+    SendToPeer(std::net::SocketAddr, message::Message),
+    GetPeers(oneshot::Sender<Vec<PeerInfo>>),
+    MarkKnown(std::net::SocketAddr, Vec<H256>),
+    Misbehaving(std::net::SocketAddr, Misbehavior),
+    GetBans(oneshot::Sender<Vec<Ban>>),
+    /// Time to ping the peers and drop the ones that stopped answering
+    Keepalive,
+    /// A peer answered a ping with this nonce at this time
+    Pong(std::net::SocketAddr, String, Instant),
+    GetShaping(oneshot::Sender<Vec<ShapingRule>>),
+    SetShaping(Option<std::net::SocketAddr>, Option<Shape>),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn start(port: u16, genesis: H256) -> (Handle, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        start_with(port, genesis, |_| {})
+    }
+
+    /// Start a server configured by `configure`
+    fn start_with<F: FnOnce(&mut Context)>(port: u16, genesis: H256, configure: F) -> (Handle, smol::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        let addr: net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let (msg_sink, msg_chan) = smol::channel::unbounded();
+        let version: VersionSource = Arc::new(move || message::Version::new(genesis, 0, addr));
+        let (mut ctx, handle) = new(addr, msg_sink, version).unwrap();
+        configure(&mut ctx);
+        ctx.start().unwrap();
+        (handle, msg_chan)
+    }
+
+    /// The next message forwarded to the worker, if any arrives within a second
+    fn recv(msg_chan: &smol::channel::Receiver<(Vec<u8>, peer::Handle)>) -> Option<Message> {
+        smol::block_on(smol::future::or(
+            async { msg_chan.recv().await.ok().map(|(bytes, _)| bincode::deserialize(&bytes).unwrap()) },
+            async {
+                smol::Timer::after(Duration::from_secs(1)).await;
+                None
+            },
+        ))
+    }
+
+    #[test]
+    fn handshake() {
+        let genesis: H256 = [1; 32].into();
+        let (a, a_chan) = start(16401, genesis);
+        let (b, b_chan) = start(16402, genesis);
+        let peer = a.connect("127.0.0.1:16402".parse().unwrap()).unwrap();
+        // each worker hears about the other node once the handshake is done
+        match recv(&a_chan) {
+            Some(Message::Version(version)) => assert_eq!(version.listen_addr, "127.0.0.1:16402".parse().unwrap()),
+            _ => panic!(),
+        }
+        match recv(&b_chan) {
+            Some(Message::Version(version)) => assert_eq!(version.listen_addr, "127.0.0.1:16401".parse().unwrap()),
+            _ => panic!(),
+        }
+        // the dialing side asks for addresses first
+        assert!(matches!(recv(&b_chan), Some(Message::GetAddr)));
+        let a_peers = a.peers();
+        assert_eq!(a_peers.len(), 1);
+        assert_eq!(a_peers[0].addr, *peer.addr());
+        assert_eq!(a_peers[0].direction, peer::Direction::Outgoing);
+        let b_peers = b.peers();
+        assert_eq!(b_peers[0].direction, peer::Direction::Incoming);
+        assert_eq!(b_peers[0].listen_addr, "127.0.0.1:16401".parse().unwrap());
+        a.send(*peer.addr(), Message::Ping("after the handshake".to_string()));
+        assert!(matches!(recv(&b_chan), Some(Message::Ping(_))));
+        // unknown peers are skipped
+        a.send("127.0.0.1:1".parse().unwrap(), Message::Ping("nobody".to_string()));
+    }
+
+    #[test]
+    fn announce_only_new_inventory() {
+        let genesis: H256 = [1; 32].into();
+        let (a, a_chan) = start(16408, genesis);
+        let (_b, b_chan) = start(16409, genesis);
+        let peer = a.connect("127.0.0.1:16409".parse().unwrap()).unwrap();
+        assert!(matches!(recv(&a_chan), Some(Message::Version(_))));
+        assert!(matches!(recv(&b_chan), Some(Message::Version(_))));
+        assert!(matches!(recv(&b_chan), Some(Message::GetAddr)));
+        let known: H256 = [2; 32].into();
+        let new: H256 = [3; 32].into();
+        // b announced or sent us the first one
+        a.mark_known(*peer.addr(), vec![known]);
+        a.broadcast(Message::NewBlockHashes(vec![known, new]));
+        match recv(&b_chan) {
+            Some(Message::NewBlockHashes(hashes)) => assert_eq!(hashes, vec![new]),
+            _ => panic!(),
+        }
+        // nothing is announced twice
+        a.broadcast(Message::NewBlockHashes(vec![known, new]));
+        a.broadcast(Message::Ping("next".to_string()));
+        assert!(matches!(recv(&b_chan), Some(Message::Ping(_))));
+    }
+
+    #[test]
+    fn learn_addresses_from_seed() {
+        use crate::blockchain::Blockchain;
+        use crate::mempool::Mempool;
+        use std::sync::Mutex;
+        // full nodes without miners, so that GetAddr and Addr get answered
+        let node = |port: u16| {
+            let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+            let genesis = blockchain.lock().unwrap().genesis();
+            let (server, msg_chan) = start(port, genesis);
+            let mempool = Arc::new(Mutex::new(Mempool::new()));
+            super::super::worker::Worker::new(&blockchain, &mempool, 1, msg_chan, &server).start();
+            server
+        };
+        let seed = node(16405);
+        let b = node(16406);
+        let c = node(16407);
+        b.connect("127.0.0.1:16405".parse().unwrap()).unwrap();
+        c.connect("127.0.0.1:16405".parse().unwrap()).unwrap();
+        let c_addr: net::SocketAddr = "127.0.0.1:16407".parse().unwrap();
+        for _ in 0..100 {
+            if b.addresses().iter().any(|peer_addr| peer_addr.addr == c_addr) {
+                assert!(seed.addresses().len() >= 2);
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("b never heard of c");
+    }
+
+    #[test]
+    fn reject_other_genesis() {
+        let (a, a_chan) = start(16403, [1; 32].into());
+        let (_c, c_chan) = start(16404, [2; 32].into());
+        let mut peer = a.connect("127.0.0.1:16404".parse().unwrap()).unwrap();
+        peer.write(Message::Ping("before the handshake".to_string()));
+        assert!(recv(&c_chan).is_none());
+        assert!(recv(&a_chan).is_none());
+    }
+
+    #[test]
+    fn ban_misbehaving_peer() {
+        let genesis: H256 = [1; 32].into();
+        let (a, a_chan) = start(16410, genesis);
+        let b_key = key_pair::from_seed([6; 32]);
+        let b_public_key = hex::encode(b_key.public_key());
+        let (b, _b_chan) = start_with(16411, genesis, |ctx| ctx.set_identity(b_key));
+        let (c, _c_chan) = start(16426, genesis);
+        b.connect("127.0.0.1:16410".parse().unwrap()).unwrap();
+        assert!(matches!(recv(&a_chan), Some(Message::Version(_))));
+        let b_addr = a.peers()[0].addr;
+        a.misbehaving(b_addr, Misbehavior::MalformedMessage);
+        assert_eq!(a.peers()[0].misbehavior, Misbehavior::MalformedMessage.score());
+        a.misbehaving(b_addr, Misbehavior::InvalidBlock);
+        thread::sleep(Duration::from_millis(200));
+        assert!(a.peers().is_empty());
+        // the ban is on the identity key b proved, not on the address it says it listens at
+        let banned: Vec<Option<String>> = a.bans().iter().map(|ban| ban.public_key.clone()).collect();
+        assert_eq!(banned, vec![Some(b_public_key)]);
+        // a banned node that connects again is dropped once it proves its key
+        b.connect("127.0.0.1:16410".parse().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(a.peers().is_empty());
+        // but another node on the same machine is not
+        c.connect("127.0.0.1:16410".parse().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(a.peers().len(), 1);
+    }
+
+    #[test]
+    fn encrypt_links() {
+        let genesis: H256 = [1; 32].into();
+        let b_key = key_pair::from_seed([4; 32]);
+        let b_public_key = hex::encode(b_key.public_key());
+        let (a, a_chan) = start_with(16413, genesis, |ctx| {
+            ctx.set_identity(key_pair::from_seed([3; 32]));
+            ctx.set_encryption(Encryption::Require);
+            ctx.pin_key("127.0.0.1:16425".parse().unwrap(), [5; 32]);
+        });
+        let (_b, b_chan) = start_with(16414, genesis, |ctx| ctx.set_identity(b_key));
+        let (_c, _c_chan) = start_with(16415, genesis, |ctx| ctx.set_encryption(Encryption::Off));
+        let (_d, _d_chan) = start(16425, genesis);
+        let peer = a.connect("127.0.0.1:16414".parse().unwrap()).unwrap();
+        assert!(matches!(recv(&a_chan), Some(Message::Version(_))));
+        assert!(matches!(recv(&b_chan), Some(Message::Version(_))));
+        assert_eq!(peer.public_key().map(hex::encode), Some(b_public_key.clone()));
+        assert_eq!(a.peers()[0].public_key, Some(b_public_key));
+        a.send(*peer.addr(), Message::Ping("sealed".to_string()));
+        assert!(matches!(recv(&b_chan), Some(Message::GetAddr)));
+        assert!(matches!(recv(&b_chan), Some(Message::Ping(_))));
+        // a node that does not encrypt is refused by one that requires it
+        a.connect("127.0.0.1:16415".parse().unwrap()).unwrap();
+        assert!(recv(&a_chan).is_none());
+        assert_eq!(a.peers().len(), 1);
+        // and so is a node that does not prove the identity key pinned for its address
+        a.connect("127.0.0.1:16425".parse().unwrap()).unwrap();
+        assert!(recv(&a_chan).is_none());
+        assert_eq!(a.peers().len(), 1);
+    }
+
+    #[test]
+    fn cut_bad_frames() {
+        use std::io::{Read, Write};
+        let genesis: H256 = [1; 32].into();
+        let (_a, a_chan) = start_with(16412, genesis, |ctx| ctx.set_encryption(Encryption::Prefer));
+        let oversized = [&frame::MAGIC[..], &u32::MAX.to_be_bytes(), &[0; 4]].concat();
+        let mut bad_magic = frame::encode(&bincode::serialize(&Message::VerAck).unwrap());
+        bad_magic[0] ^= 1;
+        let mut bad_checksum = frame::encode(&bincode::serialize(&Message::VerAck).unwrap());
+        bad_checksum[frame::HEADER_SIZE - 1] ^= 1;
+        // a plaintext hello, so that the frames are not encrypted
+        let hello = [&frame::MAGIC[..], &[Encryption::Off as u8]].concat();
+        for bytes in [oversized, bad_magic, bad_checksum].iter() {
+            let mut stream = net::TcpStream::connect("127.0.0.1:16412").unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            stream.write_all(&hello).unwrap();
+            stream.write_all(bytes).unwrap();
+            // the node closes the connection long before the handshake times out
+            let mut received = vec![];
+            if let Err(e) = stream.read_to_end(&mut received) {
+                assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+            }
+        }
+        assert!(recv(&a_chan).is_none());
+    }
+
+    #[test]
+    fn keepalive() {
+        let genesis: H256 = [1; 32].into();
+        let keepalive = |ctx: &mut Context| {
+            ctx.set_ping_interval(Duration::from_millis(100));
+            ctx.set_ping_timeout(Duration::from_millis(300));
+        };
+        let (a, _a_chan) = start_with(16416, genesis, keepalive);
+        let (_b, b_chan) = start(16417, genesis);
+        let (_c, _c_chan) = start(16418, genesis);
+        // b answers pings like a worker would, c does not
+        thread::spawn(move || {
+            while let Ok((bytes, mut peer)) = smol::block_on(b_chan.recv()) {
+                if let Ok(Message::Ping(nonce)) = bincode::deserialize(&bytes) {
+                    peer.write(Message::Pong(nonce));
+                }
+            }
+        });
+        let b = a.connect("127.0.0.1:16417".parse().unwrap()).unwrap();
+        a.connect("127.0.0.1:16418".parse().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(a.peers().len(), 2);
+        thread::sleep(Duration::from_millis(500));
+        let peers = a.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, *b.addr());
+        let (rtt, min_rtt) = (peers[0].rtt_us.unwrap(), peers[0].min_rtt_us.unwrap());
+        assert!(min_rtt <= rtt && rtt < 100_000);
+    }
+
+    #[test]
+    fn limit_connections() {
+        let genesis: H256 = [1; 32].into();
+        let (a, _a_chan) = start_with(16419, genesis, |ctx| {
+            ctx.set_max_inbound(1);
+            ctx.set_max_outbound(1);
+        });
+        let (b, _b_chan) = start(16420, genesis);
+        let (c, _c_chan) = start(16421, genesis);
+        let (_d, _d_chan) = start(16422, genesis);
+        b.connect("127.0.0.1:16419".parse().unwrap()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        // the connection is accepted and closed right away
+        c.connect("127.0.0.1:16419".parse().unwrap()).unwrap();
+        a.connect("127.0.0.1:16420".parse().unwrap()).unwrap();
+        assert!(a.connect("127.0.0.1:16422".parse().unwrap()).is_err());
+        thread::sleep(Duration::from_millis(200));
+        let peers = a.peers();
+        assert_eq!(peers.len(), 2);
+        assert!(peers.iter().all(|peer| peer.listen_addr.port() == 16420));
+        assert!(c.peers().is_empty());
+    }
+
+    #[test]
+    fn shape_links() {
+        let genesis: H256 = [1; 32].into();
+        let slow = Shape { delay_ms: 300, ..Default::default() };
+        let (a, a_chan) = start_with(16423, genesis, |ctx| ctx.set_shaping(slow));
+        let (_b, b_chan) = start(16424, genesis);
+        let peer = a.connect("127.0.0.1:16424".parse().unwrap()).unwrap();
+        assert!(matches!(recv(&a_chan), Some(Message::Version(_))));
+        assert!(matches!(recv(&b_chan), Some(Message::Version(_))));
+        assert!(matches!(recv(&b_chan), Some(Message::GetAddr)));
+        let sent = Instant::now();
+        a.send(*peer.addr(), Message::Ping("slow".to_string()));
+        assert!(matches!(recv(&b_chan), Some(Message::Ping(_))));
+        assert!(sent.elapsed() >= Duration::from_millis(300));
+        // everything to b is lost until its own shape is cleared
+        a.set_shaping(Some(*peer.addr()), Some(Shape { drop_rate: 1.0, ..Default::default() }));
+        a.send(*peer.addr(), Message::Ping("lost".to_string()));
+        assert!(recv(&b_chan).is_none());
+        assert_eq!(a.shaping(), vec![
+            ShapingRule { peer: None, shape: slow },
+            ShapingRule { peer: Some(*peer.addr()), shape: Shape { drop_rate: 1.0, ..Default::default() } },
+        ]);
+        a.set_shaping(Some(*peer.addr()), None);
+        a.send(*peer.addr(), Message::Ping("slow again".to_string()));
+        assert!(matches!(recv(&b_chan), Some(Message::Ping(_))));
+    }
 }
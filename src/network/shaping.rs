@@ -0,0 +1,198 @@
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How the messages sent to a peer are held back, to reproduce a wide-area link on one machine.
+/// The default shapes nothing.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Shape {
+    /// Milliseconds every message is held back
+    pub delay_ms: u64,
+    /// Most milliseconds the delay of a message is randomly shortened or lengthened by
+    pub jitter_ms: u64,
+    /// Kilobits per second the messages are sent at, 0 for no limit
+    pub bandwidth_kbps: u64,
+    /// Share of the messages dropped, from 0 to 1
+    pub drop_rate: f64,
+}
+
+impl Shape {
+    pub fn check(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.drop_rate) {
+            return Err(format!("drop rate {} is not between 0 and 1", self.drop_rate));
+        }
+        Ok(())
+    }
+
+    /// How long sending `size` bytes takes at the bandwidth of the shape
+    fn transmission_time(&self, size: usize) -> Duration {
+        if self.bandwidth_kbps == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_micros(size as u64 * 8 * 1000 / self.bandwidth_kbps)
+    }
+}
+
+/// A shape for the peers at one address, as returned by `Handle::shaping`. No address means the
+/// shape of the peers that have none of their own.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ShapingRule {
+    pub peer: Option<SocketAddr>,
+    #[serde(flatten)]
+    pub shape: Shape,
+}
+
+/// The shape of the link to each peer
+#[derive(Default)]
+pub struct ShapingRules {
+    default: Shape,
+    peers: HashMap<SocketAddr, Shape>,
+}
+
+impl ShapingRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shape the links to the peer at `peer`, or to every peer at its IP address if the port is 0,
+    /// or to the other peers if there is no address
+    pub fn set(&mut self, peer: Option<SocketAddr>, shape: Shape) {
+        match peer {
+            Some(peer) => {
+                self.peers.insert(peer, shape);
+            }
+            None => self.default = shape,
+        }
+    }
+
+    /// Give the peer at `peer` the shape of the other peers again
+    pub fn clear(&mut self, peer: &SocketAddr) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+
+    /// Set the shape of `peer` like `set`, or without a shape clear it, or without either stop
+    /// shaping the links to the other peers
+    pub fn update(&mut self, peer: Option<SocketAddr>, shape: Option<Shape>) {
+        match (peer, shape) {
+            (peer, Some(shape)) => self.set(peer, shape),
+            (Some(peer), None) => {
+                self.clear(&peer);
+            }
+            (None, None) => self.set(None, Shape::default()),
+        }
+    }
+
+    /// The shape of the link to the peer connected from `addr`
+    pub fn get(&self, addr: &SocketAddr) -> Shape {
+        let ip = SocketAddr::new(addr.ip(), 0);
+        self.peers.get(addr).or_else(|| self.peers.get(&ip)).cloned().unwrap_or(self.default)
+    }
+
+    pub fn list(&self) -> Vec<ShapingRule> {
+        let mut rules = vec![ShapingRule { peer: None, shape: self.default }];
+        let mut peers: Vec<ShapingRule> = self.peers.iter().map(|(peer, shape)| ShapingRule { peer: Some(*peer), shape: *shape }).collect();
+        peers.sort_by_key(|rule| rule.peer);
+        rules.extend(peers);
+        rules
+    }
+}
+
+/// Decides when each message to a peer goes out. Messages queue behind each other for the
+/// bandwidth, and keep their order whatever their jitter, as they would on a TCP connection.
+pub struct Shaper {
+    /// When the messages scheduled so far are done being sent at the bandwidth of the link
+    busy_until: Instant,
+    /// When the last message scheduled goes out
+    last_sent: Instant,
+}
+
+impl Shaper {
+    pub fn new(now: Instant) -> Self {
+        Shaper {
+            busy_until: now,
+            last_sent: now,
+        }
+    }
+
+    /// When a message of `size` bytes queued at `now` is to be written to the peer, or `None` if
+    /// it is dropped
+    pub fn schedule<R: Rng>(&mut self, shape: &Shape, size: usize, now: Instant, rng: &mut R) -> Option<Instant> {
+        if shape.drop_rate > 0.0 && rng.gen_bool(shape.drop_rate) {
+            return None;
+        }
+        self.busy_until = self.busy_until.max(now) + shape.transmission_time(size);
+        let jitter = shape.jitter_ms as i64;
+        let delay = (shape.delay_ms as i64 + rng.gen_range(-jitter..=jitter)).max(0);
+        let sent = self.busy_until + Duration::from_millis(delay as u64);
+        self.last_sent = self.last_sent.max(sent);
+        Some(self.last_sent)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn delay_and_keep_order() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = Instant::now();
+        let mut shaper = Shaper::new(now);
+        let shape = Shape { delay_ms: 100, jitter_ms: 50, ..Default::default() };
+        let mut last = now;
+        for i in 0..100 {
+            let sent = shaper.schedule(&shape, 100, now + Duration::from_millis(i), &mut rng).unwrap();
+            assert!(sent >= last);
+            assert!(sent >= now + Duration::from_millis(i + 50));
+            assert!(sent <= now + Duration::from_millis(i + 150));
+            last = sent;
+        }
+        // no shape sends right away
+        let mut shaper = Shaper::new(now);
+        assert_eq!(shaper.schedule(&Shape::default(), 1000, now, &mut rng), Some(now));
+    }
+
+    #[test]
+    fn queue_for_bandwidth_and_drop() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let now = Instant::now();
+        let mut shaper = Shaper::new(now);
+        // 1000 bytes take 100 milliseconds at 80 kilobits per second
+        let shape = Shape { delay_ms: 10, bandwidth_kbps: 80, ..Default::default() };
+        assert_eq!(shaper.schedule(&shape, 1000, now, &mut rng), Some(now + Duration::from_millis(110)));
+        assert_eq!(shaper.schedule(&shape, 1000, now, &mut rng), Some(now + Duration::from_millis(210)));
+        // the link is idle again later on
+        let later = now + Duration::from_secs(1);
+        assert_eq!(shaper.schedule(&shape, 1000, later, &mut rng), Some(later + Duration::from_millis(110)));
+
+        let lossy = Shape { drop_rate: 0.5, ..Default::default() };
+        let delivered = (0..1000).filter_map(|_| shaper.schedule(&lossy, 1, later, &mut rng)).count();
+        assert!(delivered > 400 && delivered < 600);
+        let dead = Shape { drop_rate: 1.0, ..Default::default() };
+        assert_eq!(shaper.schedule(&dead, 1, later, &mut rng), None);
+        assert!(Shape { drop_rate: 1.5, ..Default::default() }.check().is_err());
+    }
+
+    #[test]
+    fn rules_by_peer() {
+        let mut rules = ShapingRules::new();
+        let peer: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let slow = Shape { delay_ms: 200, ..Default::default() };
+        let lossy = Shape { drop_rate: 0.1, ..Default::default() };
+        rules.set(None, slow);
+        assert_eq!(rules.get(&peer), slow);
+        rules.set(Some("10.0.0.1:0".parse().unwrap()), lossy);
+        rules.set(Some(peer), Shape::default());
+        assert_eq!(rules.get(&peer), Shape::default());
+        assert_eq!(rules.get(&"10.0.0.1:6001".parse().unwrap()), lossy);
+        assert_eq!(rules.get(&"10.0.0.2:6000".parse().unwrap()), slow);
+        assert_eq!(rules.list().len(), 3);
+        assert!(rules.clear(&peer));
+        assert!(!rules.clear(&peer));
+        assert_eq!(rules.get(&peer), lossy);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::types::hash::H256;
+use log::debug;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most blocks requested from one peer at a time
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+/// Number of missing blocks, counted from the lowest one, that are requested at a time. Blocks
+/// arriving out of order wait in the orphan pool, so this bounds how many they take up there.
+pub const DOWNLOAD_WINDOW: usize = 64;
+/// How long a peer has to deliver the blocks it was asked for before they are asked from another
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the worker hands out block requests
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which missing blocks were requested from which peer, so that the blocks of a validated header
+/// chain are downloaded from several peers in parallel and asked again when a peer stalls
+pub struct BlockDownload {
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    /// the peer a block was last asked from before its request timed out, avoided next time
+    stalled: HashMap<H256, SocketAddr>,
+    /// best height of each peer, from its version and the headers it sent
+    heights: HashMap<SocketAddr, usize>,
+}
+
+impl Default for BlockDownload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlockDownload {
+    pub fn new() -> Self {
+        BlockDownload {
+            in_flight: HashMap::new(),
+            stalled: HashMap::new(),
+            heights: HashMap::new(),
+        }
+    }
+
+    pub fn in_flight_len(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn is_in_flight(&self, hash: &H256) -> bool {
+        self.in_flight.contains_key(hash)
+    }
+
+    /// Record that `addr` has the chain up to `height`
+    pub fn set_height(&mut self, addr: SocketAddr, height: usize) {
+        let entry = self.heights.entry(addr).or_insert(height);
+        *entry = (*entry).max(height);
+    }
+
+    /// Record that a block arrived, returning whether it was requested
+    pub fn received(&mut self, hash: &H256) -> bool {
+        self.stalled.remove(hash);
+        self.in_flight.remove(hash).is_some()
+    }
+
+    /// Hand the `missing` blocks, given with their height lowest first, to the connected `peers`
+    /// that have them, fewest requests in flight first. Requests that timed out at `now` or were
+    /// sent to a peer that is gone are handed out again. Returns the hashes to ask each peer for.
+    pub fn schedule(&mut self, missing: &[(H256, usize)], peers: &[SocketAddr], now: Instant) -> Vec<(SocketAddr, Vec<H256>)> {
+        let expired: Vec<(H256, SocketAddr)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (addr, requested))| !peers.contains(addr) || now.saturating_duration_since(*requested) > BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, (addr, _))| (*hash, *addr))
+            .collect();
+        for (hash, addr) in expired {
+            debug!("Request of block {} from {} timed out", hash, addr);
+            self.in_flight.remove(&hash);
+            self.stalled.insert(hash, addr);
+        }
+        self.heights.retain(|addr, _| peers.contains(addr));
+
+        let mut load: HashMap<SocketAddr, usize> = peers.iter().map(|addr| (*addr, 0)).collect();
+        for (addr, _) in self.in_flight.values() {
+            *load.entry(*addr).or_insert(0) += 1;
+        }
+        let mut requests: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        for (hash, height) in missing {
+            if self.in_flight.contains_key(hash) {
+                continue;
+            }
+            let stalled = self.stalled.get(hash);
+            let candidates: Vec<SocketAddr> = peers
+                .iter()
+                .filter(|addr| self.heights.get(addr).is_some_and(|best| best >= height))
+                .filter(|addr| load[addr] < MAX_BLOCKS_IN_FLIGHT_PER_PEER)
+                .cloned()
+                .collect();
+            // a peer that stalled on this block is only asked again if no other peer can be
+            let peer = candidates
+                .iter()
+                .filter(|addr| Some(*addr) != stalled)
+                .min_by_key(|addr| load[addr])
+                .or_else(|| candidates.first())
+                .cloned();
+            if let Some(addr) = peer {
+                *load.get_mut(&addr).unwrap() += 1;
+                self.in_flight.insert(*hash, (addr, now));
+                requests.entry(addr).or_default().push(*hash);
+            }
+        }
+        requests.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("127.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn hash(i: usize) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        bytes.into()
+    }
+
+    #[test]
+    fn spread_over_peers() {
+        let mut download = BlockDownload::new();
+        let peers = vec![addr(6000), addr(6001), addr(6002)];
+        download.set_height(addr(6000), 100);
+        download.set_height(addr(6001), 100);
+        // the third peer is behind and is not asked for these blocks
+        download.set_height(addr(6002), 5);
+        let missing: Vec<(H256, usize)> = (10..50).map(|height| (hash(height), height)).collect();
+        let now = Instant::now();
+        let requests = download.schedule(&missing, &peers, now);
+        assert_eq!(requests.len(), 2);
+        for (peer, hashes) in requests.iter() {
+            assert_ne!(*peer, addr(6002));
+            assert_eq!(hashes.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        }
+        assert_eq!(download.in_flight_len(), 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        // nothing more until blocks arrive
+        assert!(download.schedule(&missing, &peers, now).is_empty());
+        assert!(download.received(&hash(10)));
+        assert!(!download.received(&hash(10)));
+        let requests = download.schedule(&missing[1..], &peers, now);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1, vec![hash(10 + 2 * MAX_BLOCKS_IN_FLIGHT_PER_PEER)]);
+    }
+
+    #[test]
+    fn retry_after_timeout() {
+        let mut download = BlockDownload::new();
+        let peers = vec![addr(6000), addr(6001)];
+        download.set_height(addr(6000), 10);
+        let missing = vec![(hash(1), 1)];
+        let now = Instant::now();
+        assert_eq!(download.schedule(&missing, &peers, now), vec![(addr(6000), vec![hash(1)])]);
+        download.set_height(addr(6001), 10);
+        // before the timeout the request stays with the first peer
+        assert!(download.schedule(&missing, &peers, now + BLOCK_DOWNLOAD_TIMEOUT).is_empty());
+        // after it, another peer is asked
+        let later = now + BLOCK_DOWNLOAD_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(download.schedule(&missing, &peers, later), vec![(addr(6001), vec![hash(1)])]);
+        // and when that peer disconnects, the one that stalled is asked again
+        assert_eq!(download.schedule(&missing, &peers[..1], later), vec![(addr(6000), vec![hash(1)])]);
+    }
+}
@@ -1,13 +1,25 @@
-use super::message::Message;
+use super::address_book::MAX_ADDR_PER_MESSAGE;
+use super::ban::Misbehavior;
+use super::compact::{CompactBlock, PartialBlock, PartialBlocks};
+use super::frame::MAX_FRAME_SIZE;
+use super::message::{Message, MAX_HEADERS_PER_MESSAGE};
+use super::orphan::OrphanPool;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::sync::{BlockDownload, DOWNLOAD_WINDOW, SYNC_INTERVAL};
 use crate::types::block::{Block, Content, Header};
+use crate::types::clock::Clock;
 use crate::types::hash::{Hashable, H256};
-use crate::types::transaction::SignedTransaction;
-use crate::blockchain::{State, Blockchain};
+use crate::types::transaction::{verify, SignedTransaction};
+use crate::types::utxo::UtxoTransaction;
+use crate::blockchain::{check_header_proof_of_work, check_proof_of_work, BlockValidationError, Blockchain};
+use crate::mempool::Mempool;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::collections::VecDeque;
-use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use log::{debug, warn, error};
 
 use std::thread;
@@ -16,50 +28,28 @@ use std::thread;
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test,test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+/// Most bytes of blocks sent in reply to one request, leaving room in the frame for the rest of
+/// the message
+const MAX_BLOCKS_REPLY_SIZE: usize = MAX_FRAME_SIZE - 1024;
+
 #[derive(Clone)]
 pub struct Worker {
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
+    orphans: Arc<Mutex<OrphanPool>>,
+    download: Arc<Mutex<BlockDownload>>,
+    partial_blocks: Arc<Mutex<PartialBlocks>>,
+    /// draws the salts of the compact blocks relayed
+    rng: Arc<Mutex<StdRng>>,
 }
 
-pub struct OrphanBuffer {
-    // key is parent hash value is block
-    buffer : HashMap<H256, Vec<Block>>
-}
-
-impl OrphanBuffer {
-    pub fn new() -> Self {        
-        let mut buffer: HashMap<H256, Vec<Block>> = HashMap::new();
-        Self{buffer}
-    }
-    pub fn add(&mut self, block : Block, parent_hash: H256) {
-        if self.contains(parent_hash){
-            let mut arr : Vec<Block> = self.buffer.get(&parent_hash).unwrap().clone();
-            arr.push(block);
-            self.buffer.insert(parent_hash, arr.clone());
-        }
-        else{
-            self.buffer.insert(parent_hash, vec![block]);
-        }
-    }
-    pub fn contains(&self, parent_hash:H256) -> bool {
-        self.buffer.contains_key(&parent_hash)
-    }
-    pub fn get(&mut self, parent_hash:H256) -> Vec<Block> {
-        let mut temp_vec = vec![];
-        if self.contains(parent_hash){
-            temp_vec = self.buffer.remove(&parent_hash).unwrap().clone()
-        }
-        temp_vec
-    }
-}
 impl Worker {
     pub fn new(
         blockchain: &Arc<Mutex<Blockchain>>,
-        mempool: &Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+        mempool: &Arc<Mutex<Mempool>>,
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
@@ -70,9 +60,24 @@ impl Worker {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
+            orphans: Arc::new(Mutex::new(OrphanPool::new())),
+            download: Arc::new(Mutex::new(BlockDownload::new())),
+            partial_blocks: Arc::new(Mutex::new(PartialBlocks::new())),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
         }
     }
 
+    /// Set the clock orphans and compact blocks waiting for transactions are timed by
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.orphans.lock().unwrap().set_clock(Arc::clone(&clock));
+        self.partial_blocks.lock().unwrap().set_clock(clock);
+    }
+
+    /// Draw the salts of relayed compact blocks from `seed` rather than from entropy
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(seed)));
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
@@ -82,22 +87,166 @@ impl Worker {
                 warn!("Worker thread {} exited", i);
             });
         }
+        thread::spawn(move || loop {
+            thread::sleep(SYNC_INTERVAL);
+            self.request_blocks(Instant::now());
+        });
+    }
+    /// Ask peers for the blocks of validated headers that are still missing, a few from each peer,
+    /// retrying the requests that have not been answered by `now`
+    pub fn request_blocks(&self, now: Instant) {
+        let missing = self.blockchain.lock().unwrap().missing_blocks(DOWNLOAD_WINDOW);
+        let missing: Vec<(H256, usize)> = {
+            let orphans = self.orphans.lock().unwrap();
+            missing.into_iter().filter(|(hash, _)| !orphans.contains(hash)).collect()
+        };
+        if missing.is_empty() {
+            return;
+        }
+        let peers: Vec<SocketAddr> = self.server.peers().iter().map(|peer| peer.addr).collect();
+        let requests = self.download.lock().unwrap().schedule(&missing, &peers, now);
+        for (addr, hashes) in requests {
+            debug!("Requesting {} blocks from {}", hashes.len(), addr);
+            self.server.send(addr, Message::GetBlocks(hashes));
+        }
+    }
+    /// Verify a block whose parent is in the blockchain and insert it, updating the mempool. Returns
+    /// the orphans that were waiting for it, or why the block is invalid, in which case its orphan
+    /// descendants are dropped too.
+    fn connect_block(&self, block: &Block) -> Result<Vec<(Block, SocketAddr)>, BlockValidationError> {
+        let hash = block.hash();
+        let mut blockchain = self.blockchain.lock().unwrap();
+        let mut mempool = self.mempool.lock().unwrap();
+        if let Err(e) = blockchain.verify_block(block) {
+            warn!("Rejected block {}: {:?}", hash, e);
+            // a body that does not match its header says nothing about the header, which may
+            // still be downloaded from another peer
+            if e != BlockValidationError::MerkleRootMismatch {
+                blockchain.discard_header(&hash);
+            }
+            self.orphans.lock().unwrap().remove_descendants(&hash);
+            return Err(e);
+        }
+        // verify_block applied the transactions to the parent state already, so this succeeds
+        blockchain.update_state(block);
+        let reorg = blockchain.insert(block);
+        for transaction in block.get_content(){
+            mempool.remove(&transaction.hash());
+        }
+        for transaction in block.get_utxo_content() {
+            mempool.remove_utxo(&transaction.hash());
+        }
+        let state = blockchain.get_state(block);
+        println!("State After Insertion: {:?}",state);
+        // need to validate the mempool everytime we update the state
+        let tip_state = blockchain.get_tip_state();
+        mempool.revalidate(&tip_state);
+        // return transactions of the abandoned fork to the mempool
+        if let Some(reorg) = reorg {
+            for transaction in blockchain.reorg_transactions(&reorg) {
+                mempool.insert(transaction, &tip_state);
+            }
+            for transaction in blockchain.reorg_utxo_transactions(&reorg) {
+                mempool.insert_utxo(transaction, &tip_state);
+            }
+        }
+        let children = self.orphans.lock().unwrap().take_children(&hash);
+        Ok(children)
     }
-    pub fn validate_mempool(&self, tx_set: Vec<SignedTransaction>, curr_state: State) -> Vec<H256>{
-        let accounts = curr_state.get_accounts();
-        let mut tx_delete = vec![];
-        for transaction in tx_set {
-            let tx_sender = transaction.t.sender;
-            let tx_sender_nonce = transaction.t.nonce;
-            if accounts.contains_key(&tx_sender){
-                let state_account_nonce = accounts.get(&tx_sender).unwrap().0;
-                if state_account_nonce <= tx_sender_nonce {
-                    tx_delete.push(transaction.hash().clone());
+
+    /// Connect blocks received from `peer`, keeping the ones whose parent is missing as orphans,
+    /// and relay the ones that are new
+    fn process_blocks(&self, blocks: Vec<Block>, peer: &mut peer::Handle) {
+        self.server.mark_known(*peer.addr(), blocks.iter().map(|block| block.hash()).collect());
+        {
+            let mut download = self.download.lock().unwrap();
+            for block in blocks.iter() {
+                download.received(&block.hash());
+            }
+        };
+        // add these blocks to blockchain if they're not already in it, noting the ones that are new
+        let mut new_hashes : Vec<H256> = Vec::new();
+        for block in blocks{
+            let hash : H256 = block.hash();
+            // PoW Validity Check
+            if let Err(e) = check_proof_of_work(&block) {
+                warn!("Rejected block {}: {:?}", hash, e);
+                self.server.misbehaving(*peer.addr(), Misbehavior::InvalidProofOfWork);
+                continue;
+            }
+            let parent = block.get_parent();
+            let mut queue = {
+                let blockchain = self.blockchain.lock().unwrap();
+                if blockchain.contains(&hash){
+                    continue;
+                }
+                // keep the block until its parent arrives, the blockchain stays locked so
+                // the parent cannot be connected in between without seeing this orphan
+                if !blockchain.contains(&parent){
+                    let mut orphans = self.orphans.lock().unwrap();
+                    if orphans.insert(block, *peer.addr()) {
+                        // ask the peer that sent the block for the first ancestor we
+                        // miss, unless its header is known and it is being downloaded
+                        let root = orphans.root(&parent);
+                        if !blockchain.has_header(&root) {
+                            peer.write(Message::GetBlocks(vec![root]));
+                        }
+                    }
+                    continue;
+                }
+                VecDeque::from(vec![(block, *peer.addr())])
+            };
+            // connect the block, then every orphan waiting for it or for one of its
+            // descendants, blaming an invalid one on the peer that sent it
+            while let Some((block, sender)) = queue.pop_front() {
+                match self.connect_block(&block) {
+                    Ok(children) => {
+                        new_hashes.push(block.hash());
+                        queue.extend(children);
+                    }
+                    Err(e) => {
+                        if let Some(misbehavior) = block_misbehavior(&e) {
+                            self.server.misbehaving(sender, misbehavior);
+                        }
+                    }
                 }
             }
         }
-        tx_delete        
+        // and announce the new ones to the peers that do not have them yet
+        self.relay_blocks(new_hashes);
+    }
+
+    /// Process a compact block whose transactions are all there, or ask the peer for the whole
+    /// block if they do not match the header
+    fn reconstructed(&self, partial: PartialBlock, peer: &mut peer::Handle) {
+        let hash = partial.hash();
+        match partial.into_block() {
+            Some(block) => self.process_blocks(vec![block], peer),
+            None => {
+                debug!("Transactions of compact block {} do not match its header, asking for the block", hash);
+                peer.write(Message::GetBlocks(vec![hash]));
+            }
+        }
+    }
+
+    /// Announce newly connected blocks to the peers that do not have them yet. The new tip is
+    /// pushed as a compact block, which peers rebuild from their mempool without a round trip.
+    fn relay_blocks(&self, hashes: Vec<H256>) {
+        let (tip, tip_block) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            (tip, if hashes.contains(&tip) { blockchain.get_block(&tip) } else { None })
+        };
+        let others: Vec<H256> = hashes.into_iter().filter(|hash| *hash != tip).collect();
+        if !others.is_empty() {
+            self.server.broadcast(Message::NewBlockHashes(others));
+        }
+        if let Some(block) = tip_block {
+            let nonce = self.rng.lock().unwrap().gen();
+            self.server.broadcast(Message::CompactBlock(CompactBlock::new(&block, nonce)));
+        }
     }
+
     fn worker_loop(&self) {
         loop {
             let result = smol::block_on(self.msg_chan.recv());
@@ -105,199 +254,309 @@ impl Worker {
                 error!("network worker terminated {}", e);
                 break;
             }
-            let msg = result.unwrap();
-            let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            let mut buffer = OrphanBuffer::new();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+            let (msg, peer) = result.unwrap();
+            self.handle_message(msg, peer);
+        }
+    }
+
+    /// Handle a message received from `peer`
+    pub fn handle_message(&self, msg: Vec<u8>, mut peer: peer::Handle) {
+        let msg: Message = match bincode::deserialize(&msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Undecodable message from {}: {}", peer.addr(), e);
+                self.server.misbehaving(*peer.addr(), Misbehavior::MalformedMessage);
+                return;
+            }
+        };
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                peer.write(Message::Pong(nonce.to_string()));
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+            }
+            Message::Version(version) => {
+                debug!("Peer {} is at height {}", peer.addr(), version.best_height);
+                self.download.lock().unwrap().set_height(*peer.addr(), version.best_height);
+                // a peer ahead of us is asked for its headers first, the blocks follow
+                let locator = {
+                    let blockchain = self.blockchain.lock().unwrap();
+                    if version.best_height > blockchain.best_header_height() {
+                        Some(blockchain.locator())
+                    } else {
+                        None
+                    }
+                };
+                if let Some(locator) = locator {
+                    peer.write(Message::GetHeaders(locator));
                 }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self.blockchain.lock().unwrap().headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                peer.write(Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                if headers.len() > MAX_HEADERS_PER_MESSAGE {
+                    warn!("Peer {} sent {} headers, ignoring them", peer.addr(), headers.len());
+                    self.server.misbehaving(*peer.addr(), Misbehavior::OversizedMessage);
+                    return;
                 }
-                Message::NewBlockHashes(hashes) => {
-                    // if hashes are not in blockchain, send the following:
-                    let mut new_hashes : Vec<H256> = Vec::new();
-                    for hash in hashes{
-                        // if blockchain doesn't contain a hash, add it to new hashes
-                        if !self.blockchain.lock().unwrap().contains(&hash){
-                            new_hashes.push(hash);
+                // headers come in chain order, the first one that is invalid ends the chain
+                let mut last = None;
+                let mut rejected = None;
+                {
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    for header in headers.iter() {
+                        if let Err(e) = blockchain.insert_header(header) {
+                            warn!("Rejected header {} from {}: {:?}", header.hash(), peer.addr(), e);
+                            rejected = block_misbehavior(&e);
+                            break;
                         }
+                        last = Some((header.hash(), blockchain.header_height(&header.hash()).unwrap()));
                     }
-                    // ask for hashes the local miner doesn't have
-                    if new_hashes.len() != 0 {
-                        peer.write(Message::GetBlocks(new_hashes.clone()));
-                        self.server.broadcast(Message::GetBlocks(new_hashes));
+                };
+                if let Some(misbehavior) = rejected {
+                    self.server.misbehaving(*peer.addr(), misbehavior);
+                }
+                if let Some((last, height)) = last {
+                    self.download.lock().unwrap().set_height(*peer.addr(), height);
+                    // a full message means the peer has more
+                    if headers.len() == MAX_HEADERS_PER_MESSAGE {
+                        peer.write(Message::GetHeaders(vec![last]));
                     }
                 }
-                Message::GetBlocks(hashes) => {
-                    // if hashes are in blockchain, get blocks and send out a message with them
-                    let mut blocks: Vec<Block> = Vec::new();
-                    for hash in hashes{
-                        match self.blockchain.lock().unwrap().get_block(&hash) {
-                            Some(block) => blocks.push(block),
-                            _ => {}
-                        }
+            }
+            Message::GetAddr => {
+                peer.write(Message::Addr(self.server.addresses()));
+            }
+            Message::Addr(addrs) => {
+                if addrs.len() > MAX_ADDR_PER_MESSAGE {
+                    warn!("Peer {} sent {} addresses, ignoring them", peer.addr(), addrs.len());
+                    self.server.misbehaving(*peer.addr(), Misbehavior::OversizedMessage);
+                } else {
+                    self.server.add_addresses(addrs);
+                }
+            }
+            Message::NewBlockHashes(hashes) => {
+                self.server.mark_known(*peer.addr(), hashes.clone());
+                // if hashes are not in blockchain, send the following:
+                let mut new_hashes : Vec<H256> = Vec::new();
+                for hash in hashes{
+                    // if blockchain doesn't contain a hash, add it to new hashes
+                    if !self.blockchain.lock().unwrap().contains(&hash){
+                        new_hashes.push(hash);
                     }
-                    // push the blocks it does have
-                    peer.write(Message::Blocks(blocks.clone()));
-                    self.server.broadcast(Message::Blocks(blocks));
                 }
-                Message::Blocks(blocks) => {
-                    // add these blocks to blockchain if they're not already in it, noting the ones that are new
-                    let mut new_blocks : Vec<Block> = Vec::new();
-                    for block in blocks{
-                        let hash : H256 = block.hash();
-                        // PoW Validity Check
-                        if block.hash() > block.get_difficulty(){
-                            continue;
-                        }
-                        { let blockchain = self.blockchain.lock().unwrap();
-                            if blockchain.contains(&hash){
-                                continue;
-                            }
-                        };
-                        // check if blockchain has block's parent, add to buffer if it doesn't
-                        if !self.blockchain.lock().unwrap().contains(&block.get_parent()){
-                            buffer.add(block.clone(), block.get_parent());
-                            // broadcast that we're missing a block's parent
-                            peer.write(Message::GetBlocks(vec![block.get_parent()]));
-                            self.server.broadcast(Message::GetBlocks(vec![block.get_parent()]));
-                        }
-                        else{
-
-                            // verify block's validity and add it to chain
-                            {
-                            let mut blockchain = self.blockchain.lock().unwrap();
-                            let mut mempool = self.mempool.lock().unwrap();
-                            if blockchain.verify_block(&block) && blockchain.block_state.contains_key(&block.get_parent()){
-                                let valid_block = blockchain.update_state(&block);
-                                if !valid_block {
-                                    continue;
-                                }
-                                blockchain.insert(&block);
-                                for transaction in block.get_content(){
-                                    mempool.remove(&transaction.hash());
-                                }
-                                let state = blockchain.get_state(&block);
-                                println!("State After Insertion: {:?}",state);
-                                let tx_set : Vec<SignedTransaction> = mempool.values().cloned().collect();
-                                let tx_delete = self.validate_mempool(tx_set, state);
-                                for tx_hash in tx_delete{
-                                    mempool.remove(&tx_hash);
-                                }
-                                new_blocks.push(block.clone());
-                                // do this iteratively
-                                // check if the block is a parent in the buffer, iteratively add all blocks
-                                if buffer.contains(hash){
-                                    let mut orphans = VecDeque::new();
-                                    let mut temp_orphans = buffer.get(hash);
-                                    for block in temp_orphans{
-                                        orphans.push_back(block);
-                                    }
-                                    while !orphans.is_empty(){
-                                        let temp_block = orphans.pop_front().unwrap();
-                                        if blockchain.verify_block(&temp_block){
-                                            blockchain.update_state(&temp_block);
-                                            blockchain.insert(&temp_block);
-                                            // need to validate the mempool everytime we update the state
-                                            let tx_set : Vec<SignedTransaction> = mempool.values().cloned().collect();
-                                            let tx_delete = self.validate_mempool(tx_set, blockchain.get_state(&block));
-                                            for tx_hash in tx_delete {
-                                                mempool.remove(&tx_hash);
-                                            }
-                                        }
-                                        for transaction in block.get_content(){
-                                            mempool.remove(&transaction.hash());
-                                            
-                                        }
-                                        new_blocks.push(temp_block.clone());
-                                        if buffer.contains(temp_block.hash()){
-                                            temp_orphans = buffer.get(block.hash());
-                                            for block in temp_orphans{
-                                                orphans.push_back(block.clone());
-                                            }
-                                        }
-                                    }
-                                }
+                // ask the announcing peer for the blocks the local miner doesn't have
+                if new_hashes.len() != 0 {
+                    peer.write(Message::GetBlocks(new_hashes));
+                }
+            }
+            Message::GetBlocks(hashes) => {
+                // if hashes are in blockchain, get blocks and send out a message with them, as
+                // many as fit in a frame; the peer asks for the rest again
+                let mut blocks: Vec<Block> = Vec::new();
+                let mut size = 0;
+                for hash in hashes{
+                    match self.blockchain.lock().unwrap().get_block(&hash) {
+                        Some(block) => {
+                            size += bincode::serialized_size(&block).unwrap() as usize;
+                            if size > MAX_BLOCKS_REPLY_SIZE {
+                                break;
                             }
-                            };
+                            blocks.push(block)
                         }
+                        _ => {}
                     }
- 
-                    // then get the hashes of the blocks that are new
-                    let mut new_hashes : Vec<H256> = Vec::new();
-                    for block in new_blocks {
-                        new_hashes.push(block.hash());
-                    }
-                    // and broadcast these new hash blocks
-                    peer.write(Message::NewBlockHashes(new_hashes.clone()));
-                    self.server.broadcast(Message::NewBlockHashes(new_hashes));
                 }
-                Message::NewTransactionHashes(hashes) => {
-                    let mut new_hashes : Vec<H256> = Vec::new();
-                    for hash in hashes{
-                        // if blockchain doesn't contain a hash, add it to new hashes
-                        {let m = self.mempool.lock().unwrap();
-                            if !m.contains_key(&hash){
-                                new_hashes.push(hash);
-                            }
-                        };
+                // push the blocks it does have
+                self.server.mark_known(*peer.addr(), blocks.iter().map(|block| block.hash()).collect());
+                peer.write(Message::Blocks(blocks));
+            }
+            Message::Blocks(blocks) => {
+                self.process_blocks(blocks, &mut peer);
+            }
+            Message::CompactBlock(compact) => {
+                let hash = compact.hash();
+                self.server.mark_known(*peer.addr(), vec![hash]);
+                if self.blockchain.lock().unwrap().contains(&hash) {
+                    return;
+                }
+                if let Err(e) = check_header_proof_of_work(&compact.header) {
+                    warn!("Rejected compact block {}: {:?}", hash, e);
+                    self.server.misbehaving(*peer.addr(), Misbehavior::InvalidProofOfWork);
+                    return;
+                }
+                let partial = PartialBlock::new(compact, &self.mempool.lock().unwrap());
+                let partial = match partial {
+                    Some(partial) => partial,
+                    None => {
+                        warn!("Peer {} sent a malformed compact block {}", peer.addr(), hash);
+                        self.server.misbehaving(*peer.addr(), Misbehavior::MalformedMessage);
+                        return;
                     }
-                    // ask for hashes the local miner doesn't have
-                    if new_hashes.len() != 0 {
-                        peer.write(Message::GetTransactions(new_hashes.clone()));
-                        self.server.broadcast(Message::GetTransactions(new_hashes));
+                };
+                // rebuild the block from the mempool, asking the peer for what is missing
+                let missing = partial.missing();
+                if missing.is_empty() {
+                    self.reconstructed(partial, &mut peer);
+                } else {
+                    debug!("Asking {} for {} transactions of block {}", peer.addr(), missing.len(), hash);
+                    self.partial_blocks.lock().unwrap().insert(partial, *peer.addr());
+                    peer.write(Message::GetBlockTxn(hash, missing));
+                }
+            }
+            Message::GetBlockTxn(hash, indexes) => {
+                let block = self.blockchain.lock().unwrap().get_block(&hash);
+                if let Some(block) = block {
+                    let content = block.get_content();
+                    let transactions: Option<Vec<SignedTransaction>> =
+                        indexes.iter().map(|index| content.get(*index as usize).cloned()).collect();
+                    match transactions {
+                        Some(transactions) => peer.write(Message::BlockTxn(hash, transactions)),
+                        None => {
+                            warn!("Peer {} asked for transactions block {} does not have", peer.addr(), hash);
+                            self.server.misbehaving(*peer.addr(), Misbehavior::MalformedMessage);
+                        }
                     }
                 }
-                Message::GetTransactions(hashes) => {
-                    // if hashes are in blockchain, get blocks and send out a message with them
-                    let mut transactions: Vec<SignedTransaction> = Vec::new();
-                    for hash in hashes{
-                        {let m = self.mempool.lock().unwrap();
-                            match m.get(&hash) {
-                                Some(transaction) => transactions.push(transaction.clone()),
-                                _ => {}
-                            }
-                        };
+            }
+            Message::BlockTxn(hash, transactions) => {
+                let partial = self.partial_blocks.lock().unwrap().remove(&hash, peer.addr());
+                if let Some(mut partial) = partial {
+                    if partial.fill(transactions) {
+                        self.reconstructed(partial, &mut peer);
+                    } else {
+                        debug!("Peer {} sent the wrong number of transactions of block {}", peer.addr(), hash);
+                        peer.write(Message::GetBlocks(vec![hash]));
                     }
-                    // push the blocks it does have
-                    peer.write(Message::Transactions(transactions.clone()));
-                    self.server.broadcast(Message::Transactions(transactions));
                 }
-                Message::Transactions(transactions) => {
-                    // add these blocks to blockchain if they're not already in it, noting the ones that are new
-                    let mut new_transactions : Vec<SignedTransaction> = Vec::new();
-                    let mut b = self.blockchain.lock().unwrap();
-                    let mut m = self.mempool.lock().unwrap();
-                    for transaction in transactions{
-                        let hash : H256 = transaction.hash();
-                        let curr_state = b.get_tip_state();
-                        if transaction.verify(&curr_state){
-                            m.insert(transaction.hash(), transaction.clone());
-                            new_transactions.push(transaction.clone());
+            }
+            Message::NewTransactionHashes(hashes) => {
+                self.server.mark_known(*peer.addr(), hashes.clone());
+                let mut new_hashes : Vec<H256> = Vec::new();
+                for hash in hashes{
+                    // if blockchain doesn't contain a hash, add it to new hashes
+                    {let m = self.mempool.lock().unwrap();
+                        if !m.contains(&hash){
+                            new_hashes.push(hash);
                         }
+                    };
+                }
+                // ask the announcing peer for the transactions the local miner doesn't have
+                if new_hashes.len() != 0 {
+                    peer.write(Message::GetTransactions(new_hashes));
+                }
+            }
+            Message::GetTransactions(hashes) => {
+                // if hashes are in blockchain, get blocks and send out a message with them
+                let mut transactions: Vec<SignedTransaction> = Vec::new();
+                for hash in hashes{
+                    {let m = self.mempool.lock().unwrap();
+                        match m.get(&hash) {
+                            Some(transaction) => transactions.push(transaction.clone()),
+                            _ => {}
+                        }
+                    };
+                }
+                // push the transactions it does have
+                self.server.mark_known(*peer.addr(), transactions.iter().map(|transaction| transaction.hash()).collect());
+                peer.write(Message::Transactions(transactions));
+            }
+            Message::Transactions(transactions) => {
+                self.server.mark_known(*peer.addr(), transactions.iter().map(|transaction| transaction.hash()).collect());
+                // add these blocks to blockchain if they're not already in it, noting the ones that are new
+                let mut new_transactions : Vec<SignedTransaction> = Vec::new();
+                let mut invalid = 0;
+                let mut b = self.blockchain.lock().unwrap();
+                let mut m = self.mempool.lock().unwrap();
+                for transaction in transactions{
+                    // a transaction may not apply for many honest reasons, but a coinbase or a
+                    // bad signature is never sent by an honest peer
+                    if transaction.is_coinbase() || !verify(&transaction.t, &transaction.pub_key, &transaction.sig) {
+                        invalid += 1;
+                        continue;
                     }
-                    // then get the hashes of the blocks that are new
-                    let mut new_hashes : Vec<H256> = Vec::new();
-                    for transaction in new_transactions {
-                        new_hashes.push(transaction.hash());
+                    let curr_state = b.get_tip_state();
+                    if m.insert(transaction.clone(), &curr_state){
+                        new_transactions.push(transaction);
                     }
-                    drop(b);
-                    drop(m);
+                }
+                // then get the hashes of the blocks that are new
+                let mut new_hashes : Vec<H256> = Vec::new();
+                for transaction in new_transactions {
+                    new_hashes.push(transaction.hash());
+                }
+                drop(b);
+                drop(m);
+                if invalid > 0 {
+                    warn!("Peer {} sent {} invalid transactions", peer.addr(), invalid);
+                    self.server.misbehaving(*peer.addr(), Misbehavior::InvalidTransaction);
+                }
 
-                    // and broadcast these new hash blocks
-                    peer.write(Message::NewBlockHashes(new_hashes.clone()));
-                    self.server.broadcast(Message::NewBlockHashes(new_hashes));
+                // and announce them to the peers that do not have them yet
+                if !new_hashes.is_empty() {
+                    self.server.broadcast(Message::NewTransactionHashes(new_hashes));
+                }
+            }
+            Message::NewUtxoTransactionHashes(hashes) => {
+                self.server.mark_known(*peer.addr(), hashes.clone());
+                let new_hashes: Vec<H256> = {
+                    let mempool = self.mempool.lock().unwrap();
+                    hashes.into_iter().filter(|hash| !mempool.contains_utxo(hash)).collect()
+                };
+                if !new_hashes.is_empty() {
+                    peer.write(Message::GetUtxoTransactions(new_hashes));
                 }
-                _ =>{}
             }
+            Message::GetUtxoTransactions(hashes) => {
+                let transactions: Vec<UtxoTransaction> = {
+                    let mempool = self.mempool.lock().unwrap();
+                    hashes.iter().filter_map(|hash| mempool.get_utxo(hash).cloned()).collect()
+                };
+                self.server.mark_known(*peer.addr(), transactions.iter().map(|transaction| transaction.hash()).collect());
+                peer.write(Message::UtxoTransactions(transactions));
+            }
+            Message::UtxoTransactions(transactions) => {
+                self.server.mark_known(*peer.addr(), transactions.iter().map(|transaction| transaction.hash()).collect());
+                // whether a UTXO transaction is signed right depends on the outputs it spends, which
+                // may be spent already for honest reasons, so a peer is not blamed for any of them
+                let new_hashes: Vec<H256> = {
+                    let mut blockchain = self.blockchain.lock().unwrap();
+                    let mut mempool = self.mempool.lock().unwrap();
+                    let state = blockchain.get_tip_state();
+                    transactions
+                        .into_iter()
+                        .filter_map(|transaction| {
+                            let hash = transaction.hash();
+                            if mempool.insert_utxo(transaction, &state) { Some(hash) } else { None }
+                        })
+                        .collect()
+                };
+                if !new_hashes.is_empty() {
+                    self.server.broadcast(Message::NewUtxoTransactionHashes(new_hashes));
+                }
+            }
+            _ =>{}
         }
     }
 }
 
+/// How a peer that sent a block or header rejected for `e` misbehaved, if it did. A parent we do
+/// not know yet or a timestamp ahead of our clock can happen to honest peers.
+fn block_misbehavior(e: &BlockValidationError) -> Option<Misbehavior> {
+    match e {
+        BlockValidationError::UnknownParent
+        | BlockValidationError::TimestampInFuture
+        | BlockValidationError::OutOfReach
+        | BlockValidationError::TooManyHeaders => None,
+        BlockValidationError::InsufficientProofOfWork => Some(Misbehavior::InvalidProofOfWork),
+        _ => Some(Misbehavior::InvalidBlock),
+    }
+}
+
 #[cfg(any(test,test_utilities))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
@@ -310,7 +569,10 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_bytes(bincode::serialize(&msg).unwrap())
+    }
+
+    fn send_bytes(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -322,7 +584,8 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
     let blockchain = Arc::new(Mutex::new(Blockchain::new()));
-    let worker = Worker::new(&blockchain, 1, msg_chan, &server);
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let worker = Worker::new(&blockchain, &mempool, 1, msg_chan, &server);
     worker.start();
     let mut hashes : Vec<H256> = vec![];
     let mut curr = Some(blockchain.lock().unwrap().head());
@@ -342,9 +605,62 @@ mod test {
     use crate::types::block::generate_random_block;
     use crate::types::hash::Hashable;
 
-    use super::super::message::Message;
+    use crate::blockchain::Blockchain;
+    use crate::types::address::Address;
+    use crate::types::block::Block;
+    use crate::types::hash::H256;
+    use crate::types::merkle::MerkleTree;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::super::compact::CompactBlock;
+    use super::super::message::{Message, Version};
     use super::generate_test_worker_and_start;
 
+    /// Mine `len` valid blocks extending the tip of a fresh blockchain, which shares its genesis
+    /// with the worker's
+    fn mine_chain(len: usize) -> Vec<Block> {
+        let mut blockchain = Blockchain::new();
+        (0..len).map(|_| mine_on(&mut blockchain, vec![])).collect()
+    }
+
+    /// Mine a valid block with a coinbase and `transactions` on the tip of `blockchain`
+    fn mine_on(blockchain: &mut Blockchain, transactions: Vec<SignedTransaction>) -> Block {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        let parent = blockchain.tip();
+        let height = blockchain.height(&parent).unwrap() + 1;
+        let mut content = vec![SignedTransaction::coinbase(Address::random(), blockchain.block_subsidy(height), height)];
+        content.extend(transactions);
+        let hashes: Vec<H256> = content.iter().map(|transaction| transaction.hash()).collect();
+        let merkle_root = MerkleTree::new(&hashes).root();
+        let difficulty = blockchain.next_difficulty(&parent);
+        let mut nonce: u32 = 0;
+        let block = loop {
+            let block = Block::new(parent, nonce, now + height as u128, difficulty, merkle_root, content.clone());
+            if block.hash() <= difficulty {
+                break block;
+            }
+            nonce += 1;
+        };
+        assert!(blockchain.update_state(&block));
+        blockchain.insert(&block);
+        block
+    }
+
+    fn signed(key: &Ed25519KeyPair, nonce: usize) -> SignedTransaction {
+        let t = Transaction {
+            sender: Address::from_public_key_bytes(key.public_key().as_ref()),
+            nonce,
+            receiver: Address::random(),
+            value: 10,
+            fee: 1,
+        };
+        let sig = sign(&t, key).as_ref().to_vec();
+        SignedTransaction { t, sig, pub_key: key.public_key().as_ref().to_vec() }
+    }
+
     #[test]
     #[timeout(60000)]
     fn reply_new_block_hashes() {
@@ -375,12 +691,143 @@ mod test {
     #[test]
     #[timeout(1000)]
     fn reply_blocks() {
-        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
-        let random_block = generate_random_block(v.last().unwrap());
-        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![random_block.clone()]));
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let block = mine_chain(1).remove(0);
+        let mut _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
         let reply = server_receiver.recv().unwrap();
-        if let Message::NewBlockHashes(v) = reply {
-            assert_eq!(v, vec![random_block.hash()]);
+        if let Message::CompactBlock(compact) = reply {
+            assert_eq!(compact.hash(), block.hash());
+            assert_eq!(compact.short_ids.len(), 0);
+            assert_eq!(compact.prefilled.len(), 1);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn connect_orphans_across_messages() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let blocks = mine_chain(3);
+        // the tip first, the worker asks for its missing parent each time
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![blocks[2].clone()]));
+        if let Message::GetBlocks(v) = peer_receiver.recv() {
+            assert_eq!(v, vec![blocks[1].hash()]);
+        } else {
+            panic!();
+        }
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![blocks[1].clone()]));
+        if let Message::GetBlocks(v) = peer_receiver.recv() {
+            assert_eq!(v, vec![blocks[0].hash()]);
+        } else {
+            panic!();
+        }
+        // the missing parent connects both waiting descendants
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![blocks[0].clone()]));
+        // the new tip is pushed as a compact block, the blocks before it are announced
+        if let Message::NewBlockHashes(v) = server_receiver.recv().unwrap() {
+            let expected: Vec<H256> = blocks[..2].iter().map(|block| block.hash()).collect();
+            assert_eq!(v, expected);
+        } else {
+            panic!();
+        }
+        if let Message::CompactBlock(compact) = server_receiver.recv().unwrap() {
+            assert_eq!(compact.hash(), blocks[2].hash());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn connect_orphans_in_one_message() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let blocks = mine_chain(3);
+        let reversed: Vec<Block> = blocks.iter().rev().cloned().collect();
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(reversed));
+        // the second orphan is asked for by its first missing ancestor
+        for expected in [blocks[1].hash(), blocks[0].hash()].iter() {
+            if let Message::GetBlocks(v) = peer_receiver.recv() {
+                assert_eq!(&v, &vec![*expected]);
+            } else {
+                panic!();
+            }
+        }
+        // the new tip is pushed as a compact block, the blocks before it are announced
+        if let Message::NewBlockHashes(v) = server_receiver.recv().unwrap() {
+            let expected: Vec<H256> = blocks[..2].iter().map(|block| block.hash()).collect();
+            assert_eq!(v, expected);
+        } else {
+            panic!();
+        }
+        if let Message::CompactBlock(compact) = server_receiver.recv().unwrap() {
+            assert_eq!(compact.hash(), blocks[2].hash());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn sync_headers_from_peer_ahead() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let genesis = *v.last().unwrap();
+        let version = Version::new(genesis, 3, "127.0.0.1:12321".parse().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::Version(version));
+        if let Message::GetHeaders(locator) = peer_receiver.recv() {
+            assert_eq!(locator, vec![genesis]);
+        } else {
+            panic!();
+        }
+        // a peer behind is served headers from where its locator forks off
+        let blocks = mine_chain(2);
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(blocks.clone()));
+        let mut peer_receiver = test_msg_sender.send(Message::GetHeaders(vec![blocks[0].hash(), genesis]));
+        if let Message::Headers(headers) = peer_receiver.recv() {
+            assert_eq!(headers.len(), 1);
+            assert_eq!(headers[0].hash(), blocks[1].hash());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn survive_malformed_message() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let _peer_receiver = test_msg_sender.send_bytes(vec![255; 8]);
+        let mut peer_receiver = test_msg_sender.send(Message::Ping(String::from("still there")));
+        if let Message::Pong(nonce) = peer_receiver.recv() {
+            assert_eq!(nonce, "still there");
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reconstruct_compact_block() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        // the worker has the first transaction of the block in its mempool, not the second
+        let key = key_pair::from_seed([0; 32]);
+        let (first, second) = (signed(&key, 1), signed(&key, 2));
+        let _peer_receiver = test_msg_sender.send(Message::Transactions(vec![first.clone()]));
+        assert!(matches!(server_receiver.recv(), Some(Message::NewTransactionHashes(_))));
+        let block = mine_on(&mut Blockchain::new(), vec![first, second.clone()]);
+        let mut peer_receiver = test_msg_sender.send(Message::CompactBlock(CompactBlock::new(&block, rand::random())));
+        if let Message::GetBlockTxn(hash, indexes) = peer_receiver.recv() {
+            assert_eq!(hash, block.hash());
+            assert_eq!(indexes, vec![2]);
+        } else {
+            panic!();
+        }
+        let _peer_receiver = test_msg_sender.send(Message::BlockTxn(block.hash(), vec![second.clone()]));
+        if let Some(Message::CompactBlock(compact)) = server_receiver.recv() {
+            assert_eq!(compact.hash(), block.hash());
+        } else {
+            panic!();
+        }
+        // and it serves the transactions of the block to peers missing them
+        let mut peer_receiver = test_msg_sender.send(Message::GetBlockTxn(block.hash(), vec![2]));
+        if let Message::BlockTxn(hash, transactions) = peer_receiver.recv() {
+            assert_eq!(hash, block.hash());
+            assert_eq!(transactions.len(), 1);
+            assert_eq!(transactions[0].hash(), second.hash());
         } else {
             panic!();
         }
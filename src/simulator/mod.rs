@@ -0,0 +1,307 @@
+use crate::blockchain::Blockchain;
+use crate::mempool::Mempool;
+use crate::miner;
+use crate::network::memory::Network;
+use crate::network::message::Version;
+use crate::network::server::{self, VersionSource};
+use crate::network::shaping::Shape;
+use crate::network::sync::SYNC_INTERVAL;
+use crate::network::worker::Worker as NetworkWorker;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::clock::Clock;
+use crate::types::hash::{Hashable, H256};
+use crate::types::key_pair;
+use crate::types::merkle::MerkleTree;
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use ring::signature::KeyPair;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Milliseconds since the unix epoch the virtual clock reads when a simulation starts, which block
+/// timestamps are taken from
+pub const START_TIME: u128 = 1_600_000_000_000;
+
+/// A full node of a simulation, whose network worker and miner are run by the simulation rather
+/// than by threads of their own
+pub struct Node {
+    pub addr: SocketAddr,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub server: server::Handle,
+    worker: NetworkWorker,
+    miner: miner::worker::Worker,
+    /// Address the coinbases of the blocks the node mines pay to
+    address: Address,
+}
+
+impl Node {
+    pub fn tip(&self) -> H256 {
+        self.blockchain.lock().unwrap().tip()
+    }
+
+    pub fn height(&self) -> usize {
+        self.blockchain.lock().unwrap().get_tip_height()
+    }
+}
+
+/// Nodes of one process on an in-memory network, run on a virtual clock. Events happen one at a
+/// time in the order of the clock, the nodes time their mempool, orphans and compact blocks by it,
+/// and every random choice is drawn from the seed, so a simulation run again with the same seed
+/// makes the same blocks and delivers the same messages.
+pub struct Simulation {
+    network: Arc<Mutex<Network>>,
+    nodes: Vec<Node>,
+    rng: StdRng,
+    /// Mean time between two blocks mined by any of the nodes, while they mine
+    block_interval: Option<Duration>,
+    next_block: Option<Instant>,
+    next_sync: Instant,
+}
+
+/// The address of the `index`th node
+fn node_addr(index: usize) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a00_0001 + index as u32)), 6000)
+}
+
+impl Simulation {
+    /// A simulation of `len` nodes that share the genesis block and are not linked yet
+    pub fn new(len: usize, seed: u64) -> Self {
+        let network = Arc::new(Mutex::new(Network::new(seed)));
+        let mut rng = StdRng::seed_from_u64(seed);
+        let clock_network = Arc::clone(&network);
+        let clock: Clock = Arc::new(move || clock_network.lock().unwrap().now());
+        let mut nodes = vec![];
+        for index in 0..len {
+            let addr = node_addr(index);
+            let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+            let mempool = Arc::new(Mutex::new(Mempool::new()));
+            mempool.lock().unwrap().set_clock(Arc::clone(&clock));
+            let version_blockchain = Arc::clone(&blockchain);
+            let version: VersionSource = Arc::new(move || {
+                let blockchain = version_blockchain.lock().unwrap();
+                Version::new(blockchain.genesis(), blockchain.get_tip_height(), addr)
+            });
+            network.lock().unwrap().add_node(addr, version);
+            let server = server::Handle::in_memory(&network, addr);
+            // nothing arrives on the channels, messages and blocks are handed over directly
+            let (_, msg_chan) = smol::channel::unbounded();
+            let mut worker = NetworkWorker::new(&blockchain, &mempool, 1, msg_chan, &server);
+            worker.set_clock(Arc::clone(&clock));
+            worker.set_seed(rng.gen());
+            let (_, finished_block_chan) = crossbeam::channel::unbounded();
+            let mut miner = miner::worker::Worker::new(&blockchain, &mempool, &server, finished_block_chan);
+            miner.set_seed(rng.gen());
+            // the same keys as nodes started at 127.0.0.1:6000 and up, the first one is funded
+            let key = key_pair::from_seed([index as u8; 32]);
+            let address = Address::from_public_key_bytes(key.public_key().as_ref());
+            nodes.push(Node { addr, blockchain, mempool, server, worker, miner, address });
+        }
+        let now = network.lock().unwrap().now();
+        Simulation {
+            network,
+            nodes,
+            rng,
+            block_interval: None,
+            next_block: None,
+            next_sync: now + SYNC_INTERVAL,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Time passed on the virtual clock since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.network.lock().unwrap().elapsed()
+    }
+
+    /// Link node `from` to node `to`
+    pub fn connect(&mut self, from: usize, to: usize) -> io::Result<()> {
+        self.nodes[from].server.connect(self.nodes[to].addr).map(|_| ())
+    }
+
+    /// Cut the link between nodes `a` and `b`
+    pub fn disconnect(&mut self, a: usize, b: usize) -> bool {
+        self.network.lock().unwrap().disconnect(self.nodes[a].addr, self.nodes[b].addr)
+    }
+
+    /// Shape what node `from` sends to node `to`, or to every peer without a shape of its own
+    pub fn shape(&mut self, from: usize, to: Option<usize>, shape: Shape) {
+        let to = to.map(|to| self.nodes[to].addr);
+        self.nodes[from].server.set_shaping(to, Some(shape));
+    }
+
+    /// Have the nodes find a block every `block_interval` on average, each as likely as the others
+    pub fn start_mining(&mut self, block_interval: Duration) {
+        self.block_interval = Some(block_interval);
+        let now = self.network.lock().unwrap().now();
+        self.next_block = Some(now + self.time_to_next_block(block_interval));
+    }
+
+    pub fn stop_mining(&mut self) {
+        self.block_interval = None;
+        self.next_block = None;
+    }
+
+    /// Blocks are found at random, so the time to the next one is exponentially distributed
+    fn time_to_next_block(&mut self, block_interval: Duration) -> Duration {
+        let uniform: f64 = self.rng.gen();
+        block_interval.mul_f64(-(1.0 - uniform).ln())
+    }
+
+    /// Have node `index` mine a block on its tip now and relay it
+    pub fn mine(&mut self, index: usize) -> Block {
+        let timestamp = START_TIME + self.elapsed().as_millis();
+        let node = &self.nodes[index];
+        let (parent, difficulty, (content, utxo_content)) = {
+            let blockchain = node.blockchain.lock().unwrap();
+            let mempool = node.mempool.lock().unwrap();
+            let parent = blockchain.tip();
+            let height = blockchain.get_tip_height() + 1;
+            let content = miner::block_content(&blockchain, &mempool, &parent, height, node.address);
+            (parent, blockchain.next_difficulty(&parent), content)
+        };
+        let mut hashes: Vec<H256> = content.iter().map(|transaction| transaction.hash()).collect();
+        hashes.extend(utxo_content.iter().map(|transaction| transaction.hash()));
+        let merkle_root = MerkleTree::new(&hashes).root();
+        let block = loop {
+            let block = Block::new_with_utxo(parent, self.rng.gen(), timestamp, difficulty, merkle_root, content.clone(), utxo_content.clone());
+            if block.hash() <= difficulty {
+                break block;
+            }
+        };
+        debug!("{} mined block {} at {:?}", node.addr, block.hash(), self.elapsed());
+        node.miner.process_block(block.clone());
+        block
+    }
+
+    /// Run the simulation for `duration` of virtual time: deliver the messages that arrive, mine
+    /// the blocks that are found and download missing blocks, in the order they happen
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.network.lock().unwrap().now() + duration;
+        loop {
+            let arrival = self.network.lock().unwrap().next_arrival();
+            let next = [arrival, self.next_block, Some(self.next_sync)].iter().flatten().min().cloned().unwrap();
+            if next > end {
+                self.network.lock().unwrap().advance(end);
+                return;
+            }
+            if arrival == Some(next) {
+                // the network is not locked while the node handles the message, since the node
+                // sends its replies through it
+                let delivery = self.network.lock().unwrap().deliver();
+                if let Some((to, msg, peer)) = delivery {
+                    let node = self.nodes.iter().find(|node| node.addr == to).unwrap();
+                    node.worker.handle_message(msg, peer);
+                }
+            } else if self.next_block == Some(next) {
+                self.network.lock().unwrap().advance(next);
+                let miner = self.rng.gen_range(0..self.nodes.len());
+                self.mine(miner);
+                let block_interval = self.block_interval.unwrap();
+                self.next_block = Some(next + self.time_to_next_block(block_interval));
+            } else {
+                self.network.lock().unwrap().advance(next);
+                for node in self.nodes.iter() {
+                    node.worker.request_blocks(next);
+                }
+                self.next_sync = next + SYNC_INTERVAL;
+            }
+        }
+    }
+
+    /// The tip of each node
+    pub fn tips(&self) -> Vec<H256> {
+        self.nodes.iter().map(|node| node.tip()).collect()
+    }
+
+    /// Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        let tips = self.tips();
+        tips.iter().all(|tip| *tip == tips[0])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A ring of `len` nodes over links of `delay` with some jitter
+    fn ring(len: usize, seed: u64, delay: u64) -> Simulation {
+        let mut simulation = Simulation::new(len, seed);
+        for index in 0..len {
+            simulation.shape(index, None, Shape { delay_ms: delay, jitter_ms: delay / 2, ..Default::default() });
+            simulation.connect(index, (index + 1) % len).unwrap();
+        }
+        simulation
+    }
+
+    #[test]
+    fn converge_on_one_chain() {
+        let mut simulation = ring(6, 1, 100);
+        simulation.start_mining(Duration::from_secs(2));
+        simulation.run_for(Duration::from_secs(60));
+        simulation.stop_mining();
+        simulation.run_for(Duration::from_secs(10));
+        assert!(simulation.converged(), "{:?}", simulation.tips());
+        assert!(simulation.node(0).height() >= 10);
+        for node in simulation.nodes() {
+            assert_eq!(node.server.peers().len(), 2);
+        }
+    }
+
+    #[test]
+    fn heal_partition() {
+        let mut simulation = Simulation::new(4, 2);
+        simulation.connect(0, 1).unwrap();
+        simulation.connect(2, 3).unwrap();
+        simulation.start_mining(Duration::from_secs(1));
+        simulation.run_for(Duration::from_secs(20));
+        let (left, right) = (simulation.node(0).tip(), simulation.node(2).tip());
+        assert_ne!(left, right);
+        assert_eq!(simulation.node(1).tip(), left);
+        assert_eq!(simulation.node(3).tip(), right);
+        // once linked, the halves agree on the chain with the most work
+        simulation.connect(1, 2).unwrap();
+        simulation.run_for(Duration::from_secs(20));
+        simulation.stop_mining();
+        simulation.run_for(Duration::from_secs(20));
+        assert!(simulation.converged(), "{:?}", simulation.tips());
+        let blockchain = simulation.node(0).blockchain.lock().unwrap();
+        assert!(blockchain.contains(&left) && blockchain.contains(&right));
+    }
+
+    #[test]
+    fn repeat_with_the_same_seed() {
+        let run = |seed: u64| {
+            let mut simulation = ring(4, seed, 300);
+            simulation.shape(0, Some(1), Shape { delay_ms: 500, drop_rate: 0.1, ..Default::default() });
+            simulation.start_mining(Duration::from_secs(1));
+            let mut tips = vec![];
+            for _ in 0..30 {
+                simulation.run_for(Duration::from_secs(1));
+                tips.push(simulation.tips());
+            }
+            tips
+        };
+        assert_eq!(run(3), run(3));
+    }
+}
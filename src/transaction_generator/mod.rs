@@ -1,19 +1,20 @@
 use log::info;
-use crate::blockchain::{State, Blockchain};
+use crate::blockchain::{State, Blockchain, LedgerModel};
+use crate::mempool::Mempool;
 use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
 use crate::types::merkle::MerkleTree;
 use crate::types::transaction;
 use crate::types::address::Address;
-use crate::types::transaction::SignedTransaction;
 use crate::network::server::Handle as ServerHandle;
 use crate::network::message::Message;
 use crate::types::key_pair;
+use crate::types::utxo::{Input, OutPoint, Output, UtxoTransaction};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 use ring::signature::{KeyPair, Ed25519KeyPair};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
 use std::thread;
 use std::time;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -33,7 +34,7 @@ enum OperatingState {
 pub struct Context {
     /// Channel for receiving control signal
     blockchain: Arc<Mutex<Blockchain>>,
-    mempool: Arc<Mutex<HashMap<H256, SignedTransaction>>>,
+    mempool: Arc<Mutex<Mempool>>,
     server: ServerHandle,
     key_pair: Ed25519KeyPair,
     control_chan: Receiver<ControlSignal>,
@@ -46,7 +47,7 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256,SignedTransaction>>>, server: &ServerHandle, key_pair: Ed25519KeyPair) -> (Context, Handle) {
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, server: &ServerHandle, key_pair: Ed25519KeyPair) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let blockchain = Arc::clone(blockchain);
     let mempool = Arc::clone(mempool);
@@ -66,9 +67,10 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<HashMap<H256
 }
 
 #[cfg(any(test, test_utilities))]
-fn test_new() -> (Context, Handle, Receiver<Block>) {
+fn test_new() -> (Context, Handle) {
     let blockchain = Blockchain::new();
-    new(&Arc::new(Mutex::new(blockchain)))
+    let (server, _server_receiver) = ServerHandle::new_for_test();
+    new(&Arc::new(Mutex::new(blockchain)), &Arc::new(Mutex::new(Mempool::new())), &server, key_pair::from_seed([0; 32]))
 }
 
 impl Handle {
@@ -147,6 +149,12 @@ impl Context {
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
+            // on a UTXO chain the generated transactions spend outputs instead of moving balances
+            if self.blockchain.lock().unwrap().ledger_model() == LedgerModel::Utxo {
+                self.spend_output();
+                self.pause();
+                continue;
+            }
             let mut b = self.blockchain.lock().unwrap();
             let mut m = self.mempool.lock().unwrap();
             let mut rng = thread_rng();
@@ -161,25 +169,66 @@ impl Context {
                 b.get_tip_state()
             };
             let sender_addr = Address::from_public_key_bytes(sender_key_pair.public_key().as_ref());
-            let (sender_nonce, sender_bal) = state.get_accounts().get(&sender_addr).unwrap().clone();
+            let sender_bal = state.get_accounts().get(&sender_addr).unwrap().1;
             
             if sender_bal > 1 {
+                let mut m = self.mempool.lock().unwrap();
+                // queue behind the transactions of ours still waiting in the mempool
+                let sender_nonce = m.next_nonce(&sender_addr, &state) - 1;
                 let random_transaction = transaction::generate_signed_transaction(sender_key_pair, &receiver_addr, &sender_nonce, &sender_bal);
-                {let mut m = self.mempool.lock().unwrap();
-                    println!("inserting to mempool: {:?}", m.len());
-                    m.insert(random_transaction.hash(), random_transaction.clone());
-                }
-                
+                println!("inserting to mempool: {:?}", m.len());
+                let admitted = m.insert(random_transaction.clone(), &state);
+                drop(m);
 
-                self.server.broadcast(Message::NewTransactionHashes(vec![random_transaction.hash()]))
-            }
-            
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval * 10000);                    
+                if admitted {
+                    self.server.broadcast(Message::NewTransactionHashes(vec![random_transaction.hash()]))
                 }
             }
+            self.pause();
+        }
+    }
+
+    /// Wait for the interval between two generated transactions
+    fn pause(&self) {
+        if let OperatingState::Run(i) = self.operating_state {
+            if i != 0 {
+                let interval = time::Duration::from_micros(i as u64);
+                thread::sleep(interval * 10000);
+            }
+        }
+    }
+
+    /// Spend an output of our key that no transaction in the mempool spends yet, paying part of it
+    /// to the owner of a random output and what is left but a fee of 1 back to us
+    fn spend_output(&self) {
+        let address = Address::from_public_key_bytes(self.key_pair.public_key().as_ref());
+        let state = self.blockchain.lock().unwrap().get_tip_state();
+        let mut m = self.mempool.lock().unwrap();
+        let mut rng = thread_rng();
+        let owned: Vec<(OutPoint, u64)> = state
+            .utxos
+            .iter()
+            .filter(|(out_point, (value, owner))| *owner == address && *value > 1 && !m.spends(out_point))
+            .map(|(out_point, (value, _))| (*out_point, *value))
+            .collect();
+        let (out_point, value) = match owned.choose(&mut rng) {
+            Some(output) => *output,
+            None => return,
+        };
+        let owners: Vec<Address> = state.utxos.values().map(|(_, owner)| *owner).collect();
+        let receiver = *owners.choose(&mut rng).unwrap();
+        let amount = rng.gen_range(1..value);
+        let mut outputs = vec![Output { value: amount, recipient: receiver }];
+        if value - amount > 1 {
+            outputs.push(Output { value: value - amount - 1, recipient: address });
+        }
+        let mut transaction = UtxoTransaction { inputs: vec![Input::new(out_point.0, out_point.1)], outputs };
+        transaction.sign_input(0, &self.key_pair);
+        let hash = transaction.hash();
+        let admitted = m.insert_utxo(transaction, &state);
+        drop(m);
+        if admitted {
+            self.server.broadcast(Message::NewUtxoTransactionHashes(vec![hash]));
         }
     }
 }
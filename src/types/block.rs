@@ -1,5 +1,6 @@
 use super::address::Address;
 use super::transaction::{SignedTransaction, Transaction};
+use super::utxo::UtxoTransaction;
 use crate::types::hash::{Hashable, H256};
 use bincode;
 use rand::{thread_rng, Rng};
@@ -24,6 +25,8 @@ pub struct Header {
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Content {
     content: Vec<SignedTransaction>,
+    /// transactions of the UTXO ledger model, only used on chains whose genesis selects it
+    utxo_content: Vec<UtxoTransaction>,
 }
 
 impl Hashable for Block {
@@ -40,6 +43,20 @@ impl Hashable for Header {
         new_hash
     }
 }
+impl Header {
+    pub fn get_parent(&self) -> H256 {
+        self.parent
+    }
+    pub fn get_difficulty(&self) -> H256 {
+        self.difficulty
+    }
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+    pub fn get_merkle_root(&self) -> H256 {
+        self.merkle_root
+    }
+}
 impl Block {
     pub fn new(
         parent: H256,
@@ -56,16 +73,43 @@ impl Block {
             difficulty: difficulty,
             merkle_root: merkle_root,
         };
-        let content = Content { content };
+        let content = Content { content, utxo_content: vec![] };
         Self { header, content }
     }
+    /// Create a block that also holds UTXO transactions
+    pub fn new_with_utxo(
+        parent: H256,
+        nonce: u32,
+        timestamp: u128,
+        difficulty: H256,
+        merkle_root: H256,
+        content: Vec<SignedTransaction>,
+        utxo_content: Vec<UtxoTransaction>,
+    ) -> Self {
+        let mut block = Block::new(parent, nonce, timestamp, difficulty, merkle_root, content);
+        block.content.utxo_content = utxo_content;
+        block
+    }
+    /// Put a block back together from a header and transactions received separately
+    pub fn from_parts(header: Header, content: Vec<SignedTransaction>, utxo_content: Vec<UtxoTransaction>) -> Self {
+        Self { header, content: Content { content, utxo_content } }
+    }
     pub fn get_parent(&self) -> H256 {
         self.header.parent
     }
+    pub fn header(&self) -> Header {
+        self.header.clone()
+    }
 
     pub fn get_difficulty(&self) -> H256 {
         self.header.difficulty
     }
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.timestamp
+    }
+    pub fn get_merkle_root(&self) -> H256 {
+        self.header.merkle_root
+    }
     pub fn get_content(&self) -> Vec<SignedTransaction>{
         let content = &self.content.content;
         let mut transactions : Vec<SignedTransaction> = vec![];
@@ -74,6 +118,15 @@ impl Block {
         }
         transactions
     }
+    pub fn get_utxo_content(&self) -> Vec<UtxoTransaction> {
+        self.content.utxo_content.clone()
+    }
+    /// Hashes the merkle root is computed over, the account model transactions first
+    pub fn transaction_hashes(&self) -> Vec<H256> {
+        let mut hashes: Vec<H256> = self.content.content.iter().map(|t| t.hash()).collect();
+        hashes.extend(self.content.utxo_content.iter().map(|t| t.hash()));
+        hashes
+    }
 }
 
 #[cfg(any(test, test_utilities))]
@@ -99,7 +152,7 @@ pub fn generate_random_block(parent: &H256) -> Block {
         difficulty: difficulty,
         merkle_root: empty_root,
     };
-    let content = Content { content: vec![] };
+    let content = Content { content: vec![], utxo_content: vec![] };
 
     Block {
         header: header,
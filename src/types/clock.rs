@@ -0,0 +1,10 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Where a node reads the time from, so that a simulation can run it on a virtual clock
+pub type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+/// The clock of the system
+pub fn system() -> Clock {
+    Arc::new(Instant::now)
+}
@@ -89,6 +89,101 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl H256 {
+    fn to_limbs(&self) -> [u64; 4] {
+        let mut limbs: [u64; 4] = [0; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_be_bytes(self.0[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+        limbs
+    }
+
+    fn from_limbs(limbs: &[u64; 4]) -> H256 {
+        let mut result: [u8; 32] = [0; 32];
+        for i in 0..4 {
+            result[i * 8..(i + 1) * 8].copy_from_slice(&limbs[i].to_be_bytes());
+        }
+        H256(result)
+    }
+
+    /// Add two hashes read as big endian 256-bit integers, saturating at the largest value.
+    pub fn saturating_add(&self, other: &H256) -> H256 {
+        let (lhs, rhs) = (self.to_limbs(), other.to_limbs());
+        let mut sum: [u64; 4] = [0; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (value, overflow_1) = lhs[i].overflowing_add(rhs[i]);
+            let (value, overflow_2) = value.overflowing_add(carry as u64);
+            sum[i] = value;
+            carry = overflow_1 || overflow_2;
+        }
+        if carry {
+            return H256([0xff; 32]);
+        }
+        H256::from_limbs(&sum)
+    }
+
+    /// Expected number of hashes needed to find a block with this hash as its difficulty target,
+    /// that is 2^256 / (target + 1).
+    pub fn work(&self) -> H256 {
+        // 2^256 / (target + 1) = (2^256 - target - 1) / (target + 1) + 1, where the numerator
+        // is the bitwise complement of the target and both sides fit in 256 bits
+        let target = self.to_limbs();
+        if target == [u64::MAX; 4] {
+            return H256::from_limbs(&[0, 0, 0, 1]);
+        }
+        let divisor = H256::from_limbs(&target).saturating_add(&H256::from_limbs(&[0, 0, 0, 1])).to_limbs();
+        let numerator: Vec<u64> = target.iter().map(|limb| !limb).collect();
+        let mut quotient: [u64; 4] = [0; 4];
+        let mut remainder: [u64; 4] = [0; 4];
+        // schoolbook long division, one bit at a time
+        for bit in 0..256 {
+            let overflow = remainder[0] >> 63 == 1;
+            for i in 0..4 {
+                let next = if i < 3 { remainder[i + 1] >> 63 } else { (numerator[bit / 64] >> (63 - bit % 64)) & 1 };
+                remainder[i] = (remainder[i] << 1) | next;
+            }
+            if overflow || remainder >= divisor {
+                let mut borrow = false;
+                for i in (0..4).rev() {
+                    let (value, underflow_1) = remainder[i].overflowing_sub(divisor[i]);
+                    let (value, underflow_2) = value.overflowing_sub(borrow as u64);
+                    remainder[i] = value;
+                    borrow = underflow_1 || underflow_2;
+                }
+                quotient[bit / 64] |= 1 << (63 - bit % 64);
+            }
+        }
+        H256::from_limbs(&quotient).saturating_add(&H256::from_limbs(&[0, 0, 0, 1]))
+    }
+
+    /// Multiply the hash, read as a big endian 256-bit integer, by `numerator / denominator`.
+    /// Saturates at the largest 256-bit integer.
+    pub fn scale(&self, numerator: u64, denominator: u64) -> H256 {
+        let limbs = self.to_limbs();
+        // multiply, least significant limb first, keeping the overflow in an extra limb
+        let mut product: [u64; 5] = [0; 5];
+        let mut carry: u128 = 0;
+        for i in (0..4).rev() {
+            let value = limbs[i] as u128 * numerator as u128 + carry;
+            product[i + 1] = value as u64;
+            carry = value >> 64;
+        }
+        product[0] = carry as u64;
+        // divide, most significant limb first
+        let mut remainder: u128 = 0;
+        for limb in product.iter_mut() {
+            let value = (remainder << 64) | *limb as u128;
+            *limb = (value / denominator as u128) as u64;
+            remainder = value % denominator as u128;
+        }
+        if product[0] != 0 {
+            return H256([0xff; 32]);
+        }
+        H256::from_limbs(&[product[1], product[2], product[3], product[4]])
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -1,6 +1,8 @@
 pub mod address;
 pub mod block;
+pub mod clock;
 pub mod hash;
 pub mod merkle;
 pub mod key_pair;
-pub mod transaction;
\ No newline at end of file
+pub mod transaction;
+pub mod utxo;
\ No newline at end of file
@@ -16,6 +16,8 @@ pub struct Transaction {
     pub nonce : usize,
     pub receiver: Address,
     pub value: usize,
+    /// Paid to the miner of the block that includes the transaction
+    pub fee: usize,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
@@ -25,6 +27,26 @@ pub struct SignedTransaction {
     pub pub_key: Vec<u8>,
 }
 impl SignedTransaction{
+    /// Create the coinbase transaction of the block at `height`, minting `value` to `receiver`. It
+    /// has no sender, signature or public key, and carries the height as its nonce so that every
+    /// coinbase has a distinct hash.
+    pub fn coinbase(receiver: Address, value: usize, height: usize) -> Self {
+        let t = Transaction {
+            sender: Address::default(),
+            nonce: height,
+            receiver: receiver,
+            value: value,
+            fee: 0,
+        };
+        SignedTransaction{
+            t: t,
+            sig: vec![],
+            pub_key: vec![],
+        }
+    }
+    pub fn is_coinbase(&self) -> bool {
+        self.t.sender == Address::default() && self.sig.is_empty() && self.pub_key.is_empty()
+    }
     pub fn verify(&self, curr_state: &State) -> bool{
         let peer_public_key = UnparsedPublicKey::new(&ED25519, self.pub_key.clone());
         let hash : [u8; 32] = self.t.hash().into();
@@ -41,11 +63,14 @@ impl SignedTransaction{
         }
 
         let account_nonce = self.t.nonce;
-        let value = self.t.value;
+        let value = match self.t.value.checked_add(self.t.fee) {
+            Some(value) => value,
+            None => return false,
+        };
         let sig = self.sig.clone();
         let key = self.pub_key.clone();
         let (sender_nonce, sender_bal) = accounts.get(&sender).unwrap();
-        // check sender has enough funds and account_nonce has only been incremented once
+        // check sender has enough funds for the value and the fee and account_nonce has only been incremented once
         if sender_bal > &value && (sender_nonce + 1) == account_nonce {
             // println!("Sender Balance: {:?}", sender_bal);
             // println!("Value of Transaction: {:?}", value);
@@ -74,7 +99,7 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
 }
 
 /// Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8], state: State) -> bool {
+pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     let peer_public_key = UnparsedPublicKey::new(&ED25519, public_key);
     let hash : [u8; 32] = t.hash().into();
     peer_public_key.verify(&hash, signature).is_ok()
@@ -99,11 +124,13 @@ pub fn generate_random_transaction() -> Transaction {
     }
     let addr_sender = Address::new(addr_arr_sender);
     let addr_receiver = Address::new(addr_arr_receiver);
-    let val: u8 = rng.gen();
+    let val: usize = rng.gen_range(1..=255);
     Transaction {
         sender: addr_sender,
+        nonce: 1,
         receiver: addr_receiver,
         value: val,
+        fee: 0,
     }
 }
 pub fn generate_signed_transaction(key_pair_sender: &Ed25519KeyPair, receiver_addr: &Address, nonce: &usize, bal: &usize) -> SignedTransaction {
@@ -113,12 +140,14 @@ pub fn generate_signed_transaction(key_pair_sender: &Ed25519KeyPair, receiver_ad
     let addr_sender = Address::from_public_key_bytes(pub_key_sender.as_ref());
 
     let val: usize = rng.gen_range(1..=100);
+    let fee: usize = rng.gen_range(0..=10);
 
     let t = Transaction {
         sender: addr_sender,
         receiver: *receiver_addr,
         nonce: nonce + 1,
-        value: val
+        value: val,
+        fee: fee,
     };
     let sig = sign(&t, &key_pair_sender).as_ref().to_vec();
     SignedTransaction{
@@ -0,0 +1,109 @@
+use super::address::Address;
+use super::hash::{Hashable, H256};
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+
+/// An output is identified by the hash of the transaction that created it and its index there
+pub type OutPoint = (H256, u32);
+
+/// Spends an output of an earlier transaction, signed by the key its recipient address belongs to
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Input {
+    pub prev_tx: H256,
+    pub index: u32,
+    pub pub_key: Vec<u8>,
+    pub sig: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Output {
+    pub value: u64,
+    pub recipient: Address,
+}
+
+/// A transaction of the UTXO ledger model. The difference between the value of the spent outputs
+/// and the value of the new outputs is the fee.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct UtxoTransaction {
+    pub inputs: Vec<Input>,
+    pub outputs: Vec<Output>,
+}
+
+impl Input {
+    pub fn new(prev_tx: H256, index: u32) -> Self {
+        Input {
+            prev_tx,
+            index,
+            pub_key: vec![],
+            sig: vec![],
+        }
+    }
+
+    pub fn out_point(&self) -> OutPoint {
+        (self.prev_tx, self.index)
+    }
+}
+
+impl UtxoTransaction {
+    /// The hash every input signs: the transaction with the signatures and public keys left out,
+    /// so that each signature covers every input and every output
+    pub fn signing_hash(&self) -> H256 {
+        let mut unsigned = self.clone();
+        for input in unsigned.inputs.iter_mut() {
+            input.pub_key = vec![];
+            input.sig = vec![];
+        }
+        unsigned.hash()
+    }
+
+    /// Sign the input at `index` with the key of the recipient of the output it spends
+    pub fn sign_input(&mut self, index: usize, key: &Ed25519KeyPair) {
+        let hash: [u8; 32] = self.signing_hash().into();
+        self.inputs[index].pub_key = key.public_key().as_ref().to_vec();
+        self.inputs[index].sig = key.sign(&hash).as_ref().to_vec();
+    }
+
+    /// Check the signature of the input at `index` against the recipient of the output it spends
+    pub fn verify_input(&self, index: usize, owner: &Address) -> bool {
+        let input = &self.inputs[index];
+        if Address::from_public_key_bytes(&input.pub_key) != *owner {
+            return false;
+        }
+        let hash: [u8; 32] = self.signing_hash().into();
+        UnparsedPublicKey::new(&ED25519, &input.pub_key)
+            .verify(&hash, &input.sig)
+            .is_ok()
+    }
+}
+
+impl Hashable for UtxoTransaction {
+    fn hash(&self) -> H256 {
+        let bytes: Vec<u8> = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+
+    #[test]
+    fn sign_verify_inputs() {
+        let key = key_pair::from_seed([0; 32]);
+        let other_key = key_pair::from_seed([1; 32]);
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut transaction = UtxoTransaction {
+            inputs: vec![Input::new([1; 32].into(), 0), Input::new([2; 32].into(), 1)],
+            outputs: vec![Output { value: 5, recipient: Address::random() }],
+        };
+        transaction.sign_input(0, &key);
+        transaction.sign_input(1, &other_key);
+        assert!(transaction.verify_input(0, &owner));
+        // signed by a key that does not own the output
+        assert!(!transaction.verify_input(1, &owner));
+        // the signatures cover the outputs
+        transaction.outputs[0].value = 6;
+        assert!(!transaction.verify_input(0, &owner));
+    }
+}